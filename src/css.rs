@@ -3,6 +3,9 @@
 //! To support more CSS syntax, it would probably be easiest to replace
 //! this hand-rolled parser with one based on a library or parser generator.
 
+use crate::error::{self, describe_next, ParseError, Parsed, PendingError};
+use crate::loader::{self, ResourceLoader};
+
 /*
     A CSS stylesheet is a series of rules.
  */
@@ -76,6 +79,8 @@ pub struct Declaration {
         Value::ColorValue(Color { r: 0, g: 0, b: 0, a: 1 })
  */
 #[derive(Clone, PartialEq)]
+// `ColorValue` keeps the tutorial's name, and `Value::Color` would read like the `Color` struct.
+#[allow(clippy::enum_variant_names)]
 pub enum Value {
     Keyword(String),
     Length(f32, Unit),
@@ -168,40 +173,72 @@ impl Value {
 struct Parser {
     input: String,
     position: usize,
+    // Declarations that were dropped because they couldn't be parsed.
+    warnings: Vec<PendingError>,
+    // Rules that were dropped because they couldn't be parsed.
+    errors: Vec<PendingError>,
+    // URLs of `@import` rules, as written in the stylesheet.
+    imports: Vec<String>,
 }
 
+/*
+    Every parsing method returns a `Result`. An `Err` means the method found something it
+    didn't expect, and `?` passes the error up to the caller. Errors stop at one of two
+    places, where the parser recovers like a browser would:
+        1. A bad declaration is dropped, and parsing continues after the next ';'.
+        2. A bad rule (e.g. an unsupported selector) is dropped, and parsing continues
+           after its closing '}'.
+    Dropped declarations are reported as warnings, and dropped rules as errors.
+ */
+type ParseResult<T> = Result<T, PendingError>;
+
 // Implemented Parser based on Default CSS Parser
 impl Parser {
+    fn new(input: String) -> Parser {
+        Parser { input, position: 0, warnings: Vec::new(), errors: Vec::new(), imports: Vec::new() }
+    }
+
     /// Return true if all input is consumed.
     fn eof(&self) -> bool {
         self.position >= self.input.len()
     }
 
-    /// Read the current character without consuming it.
-    fn next_char(&self) -> char {
-        self.input[self.position..].chars().next().unwrap()
+    /// Read the current character without consuming it, or `None` at the end of input.
+    fn next_char(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
     }
 
     /// Return the current character, and advance self.position to the next character.
-    fn consume_char(&mut self) -> char {
-        let c = self.next_char();
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
         self.position += c.len_utf8();
-        c
+        Some(c)
+    }
+
+    /// An error saying that `expected` was expected at the current position.
+    fn error(&self, expected: &str) -> PendingError {
+        PendingError::new(self.position, expected, describe_next(&self.input[self.position..]))
     }
 
-    /// If the exact string `s` is found at the current position, consume it.
-    /// Otherwise, panic.
-    fn expect_char(&mut self, c: char) {
-        if self.consume_char() != c {
-            panic!("Expected {:?} at byte {} but it was not found", c, self.position);
+    /// If the next character is `c`, consume it. Otherwise, return an error.
+    fn expect_char(&mut self, c: char) -> ParseResult<()> {
+        if self.next_char() == Some(c) {
+            self.consume_char();
+            Ok(())
+        } else {
+            Err(self.error(&format!("{:?}", c)))
         }
     }
 
     /// Consume characters until `test` returns false.
     fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
         let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
+        while let Some(c) = self.next_char() {
+            if !test(c) {
+                break;
+            }
+            result.push(c);
+            self.position += c.len_utf8();
         }
         result
     }
@@ -217,85 +254,145 @@ impl Parser {
     }
 
     /// Parse two hexadecimal digits.
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = &self.input[self.position..self.position + 2];
-        self.position += 2;
-        u8::from_str_radix(s, 16).unwrap()
-    }
-
-    fn parse_color(&mut self) -> Value {
-        self.expect_char('#');
-        Value::ColorValue(Color {
-            r: self.parse_hex_pair(),
-            g: self.parse_hex_pair(),
-            b: self.parse_hex_pair(),
+    fn parse_hex_pair(&mut self) -> ParseResult<u8> {
+        let start = self.position;
+        let mut value = 0;
+        for _ in 0..2 {
+            match self.next_char().and_then(|c| c.to_digit(16)) {
+                Some(digit) => {
+                    self.consume_char();
+                    value = value * 16 + digit as u8;
+                }
+                None => {
+                    self.position = start;
+                    return Err(self.error("hexadecimal digit"));
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_color(&mut self) -> ParseResult<Value> {
+        self.expect_char('#')?;
+        Ok(Value::ColorValue(Color {
+            r: self.parse_hex_pair()?,
+            g: self.parse_hex_pair()?,
+            b: self.parse_hex_pair()?,
             a: 255,
-        })
+        }))
     }
 
-    fn parse_unit(&mut self) -> Unit {
+    fn parse_unit(&mut self) -> ParseResult<Unit> {
+        let start = self.position;
         match &*self.parse_identifier().to_ascii_lowercase() {
-            "px" => Unit::Px,
-            _ => panic!("unrecognized unit"),
+            "px" => Ok(Unit::Px),
+            _ => {
+                self.position = start;
+                Err(self.error("unit"))
+            }
         }
     }
 
-    fn parse_float(&mut self) -> f32 {
-        self.consume_while(|c| matches!(c, '0'..='9' | '.')).parse().unwrap()
+    fn parse_float(&mut self) -> ParseResult<f32> {
+        let start = self.position;
+        let number = self.consume_while(|c| matches!(c, '0'..='9' | '.'));
+        number.parse().map_err(|_| PendingError::new(start, "number", format!("{:?}", number)))
     }
 
     // Methods for parsing values
 
-    fn parse_length(&mut self) -> Value {
-        Value::Length(self.parse_float(), self.parse_unit())
+    fn parse_length(&mut self) -> ParseResult<Value> {
+        Ok(Value::Length(self.parse_float()?, self.parse_unit()?))
     }
 
-    fn parse_value(&mut self) -> Value {
+    fn parse_value(&mut self) -> ParseResult<Value> {
         match self.next_char() {
-            '0'..='9' => self.parse_length(),
-            '#' => self.parse_color(),
-            _ => Value::Keyword(self.parse_identifier()),
+            Some('0'..='9') | Some('.') => self.parse_length(),
+            Some('#') => self.parse_color(),
+            Some(c) if valid_identifier_char(c) => Ok(Value::Keyword(self.parse_identifier())),
+            _ => Err(self.error("value")),
         }
     }
 
     /// Parse one `<property>: <value>;` declaration.
-    fn parse_declaration(&mut self) -> Declaration {
+    /// The ';' may be left out after the last declaration in a block.
+    fn parse_declaration(&mut self) -> ParseResult<Declaration> {
         let name = self.parse_identifier();
+        if name.is_empty() {
+            return Err(self.error("property name"));
+        }
         self.consume_whitespace();
-        self.expect_char(':');
+        self.expect_char(':')?;
         self.consume_whitespace();
 
-        let value = self.parse_value();
+        let value = self.parse_value()?;
         self.consume_whitespace();
-        self.expect_char(';');
+        match self.next_char() {
+            Some(';') => {
+                self.consume_char();
+            }
+            Some('}') | None => {}
+            _ => return Err(self.error("';' or '}'")),
+        }
 
-        Declaration { name, value }
+        Ok(Declaration { name, value })
     }
 
     /// Parse a list of declarations enclosed in `{ ... }`.
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        self.expect_char('{');
+    fn parse_declarations(&mut self) -> ParseResult<Vec<Declaration>> {
+        self.expect_char('{')?;
+        let declarations = self.parse_declaration_list();
+        if self.eof() {
+            // The block is closed by the end of the stylesheet, which is allowed but suspicious.
+            self.warnings.push(self.error("'}'"));
+        } else {
+            self.expect_char('}')?;
+        }
+        Ok(declarations)
+    }
+
+    /// Parse declarations up to a '}' or the end of input, dropping any that are invalid.
+    fn parse_declaration_list(&mut self) -> Vec<Declaration> {
         let mut declarations = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.next_char() == '}' {
-                self.consume_char();
-                break;
+            match self.next_char() {
+                Some('}') | None => break,
+                Some(';') => {
+                    // An empty declaration.
+                    self.consume_char();
+                }
+                Some(_) => match self.parse_declaration() {
+                    Ok(declaration) => declarations.push(declaration),
+                    Err(error) => {
+                        self.warnings.push(error);
+                        self.skip_declaration();
+                    }
+                },
             }
-            declarations.push(self.parse_declaration());
         }
         declarations
     }
 
+    /// Skip the rest of a bad declaration, up to and including the next ';'.
+    /// Stops before a '}' that closes the declaration block.
+    fn skip_declaration(&mut self) {
+        self.consume_while(|c| c != ';' && c != '}');
+        if self.next_char() == Some(';') {
+            self.consume_char();
+        }
+    }
+
     /// Parse one simple selector, e.g: `type#id.class1.class2.class3`
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
+    fn parse_simple_selector(&mut self) -> ParseResult<SimpleSelector> {
+        let start = self.position;
         let mut selector = SimpleSelector {
             tag_name: None,
             id: None,
             class: Vec::new(),
         };
-        while !self.eof() {
-            match self.next_char() {
+        while let Some(c) = self.next_char() {
+            match c {
                 '#' => {
                     self.consume_char();
                     selector.id = Some(self.parse_identifier());
@@ -314,34 +411,57 @@ impl Parser {
                 _ => break
             }
         }
-        selector
+        if self.position == start {
+            return Err(self.error("selector"));
+        }
+        Ok(selector)
     }
 
-    /// Parse a comma-separated list of selectors.
-    fn parse_selectors(&mut self) -> Vec<Selector> {
+    /// Parse a comma-separated list of selectors, ending at `end`: the '{' of a rule,
+    /// or `None` for the end of the input.
+    fn parse_selectors(&mut self, end: Option<char>) -> ParseResult<Vec<Selector>> {
         let mut selectors = Vec::new();
         loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+            selectors.push(Selector::Simple(self.parse_simple_selector()?));
             self.consume_whitespace();
             match self.next_char() {
-                ',' => {
+                Some(',') => {
                     self.consume_char();
                     self.consume_whitespace();
                 }
-                '{' => break,
-                c => panic!("Unexpected character {} in selector list", c),
+                c if c == end => break,
+                _ => {
+                    let expected = match end {
+                        Some(c) => format!("',' or '{}'", c),
+                        None => "',' or end of selector".to_string(),
+                    };
+                    return Err(self.error(&expected));
+                }
             }
         }
         // Return selectors with highest specificity first, for use in matching.
         selectors.sort_by_key(|s| s.specificity());
-        selectors
+        Ok(selectors)
     }
 
     /// Parse a rule set: `<selectors> { <declarations> }`.
-    fn parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_selectors(),
-            declarations: self.parse_declarations(),
+    fn parse_rule(&mut self) -> ParseResult<Rule> {
+        Ok(Rule {
+            selectors: self.parse_selectors(Some('{'))?,
+            declarations: self.parse_declarations()?,
+        })
+    }
+
+    /// Skip the rest of a bad rule, up to and including the '}' that closes its block.
+    fn skip_rule(&mut self) {
+        let mut depth = 0;
+        while let Some(c) = self.consume_char() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth <= 1 => break,
+                '}' => depth -= 1,
+                _ => {}
+            }
         }
     }
 
@@ -353,18 +473,204 @@ impl Parser {
             if self.eof() {
                 break;
             }
-            rules.push(self.parse_rule());
+            if self.next_char() == Some('@') {
+                if let Err(error) = self.parse_at_rule(!rules.is_empty()) {
+                    self.errors.push(error);
+                    self.skip_at_rule();
+                }
+                continue;
+            }
+            match self.parse_rule() {
+                Ok(rule) => rules.push(rule),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.skip_rule();
+                }
+            }
         }
         rules
     }
+
+    /*
+        The only at-rule supported is `@import`, which must come before any rule sets.
+        The URL is only recorded here. Loading it is up to `parse_with_imports`.
+
+        e.g.
+            @import "base.css";
+            @import url(theme/dark.css);
+     */
+
+    /// Parse an at-rule, which must be `@import`. `after_rules` says if any rule set came before it.
+    fn parse_at_rule(&mut self, after_rules: bool) -> ParseResult<()> {
+        let start = self.position;
+        self.expect_char('@')?;
+        let name = self.parse_identifier();
+        if !name.eq_ignore_ascii_case("import") {
+            return Err(PendingError::new(start, "@import", format!("@{}", name)));
+        }
+        if after_rules {
+            return Err(PendingError::new(start, "@import before any rule sets", "@import"));
+        }
+        self.consume_whitespace();
+        let url = self.parse_import_url()?;
+        self.consume_whitespace();
+        // Media queries aren't supported, so anything after the URL is an error.
+        match self.next_char() {
+            Some(';') => {
+                self.consume_char();
+            }
+            None => {}
+            _ => return Err(self.error("';'")),
+        }
+        self.imports.push(url);
+        Ok(())
+    }
+
+    /// Parse the URL of an `@import`, either a string or `url(...)`.
+    fn parse_import_url(&mut self) -> ParseResult<String> {
+        match self.next_char() {
+            Some(quote @ ('"' | '\'')) => self.parse_string(quote),
+            _ if self.input[self.position..].starts_with("url(") => {
+                self.position += "url(".len();
+                self.consume_whitespace();
+                let url = match self.next_char() {
+                    Some(quote @ ('"' | '\'')) => self.parse_string(quote)?,
+                    _ => self.consume_while(|c| c != ')' && !c.is_whitespace()),
+                };
+                self.consume_whitespace();
+                self.expect_char(')')?;
+                Ok(url)
+            }
+            _ => Err(self.error("string or url()")),
+        }
+    }
+
+    /// Parse a string enclosed in `quote` characters. Escapes aren't supported.
+    fn parse_string(&mut self, quote: char) -> ParseResult<String> {
+        self.expect_char(quote)?;
+        let value = self.consume_while(|c| c != quote && c != '\n');
+        self.expect_char(quote)?;
+        Ok(value)
+    }
+
+    /// Skip the rest of a bad at-rule: up to and including the next ';', or its whole block.
+    fn skip_at_rule(&mut self) {
+        let mut depth = 0;
+        while let Some(c) = self.consume_char() {
+            match c {
+                ';' if depth == 0 => break,
+                '{' => depth += 1,
+                '}' if depth <= 1 => break,
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
 }
 
-/// Parse a whole CSS stylesheet.
+
+/*
+    There are two entry points. `parse` never fails: it returns whatever could be
+    parsed, just like a browser would use it. `try_parse` is for callers that want to
+    know about problems. It fails if any rule had to be dropped, and otherwise returns
+    the stylesheet along with warnings about any declarations that were dropped.
+ */
+
+/// Parse a whole CSS stylesheet, skipping anything that can't be parsed.
 pub fn parse(source: String) -> Stylesheet {
-    let mut parser = Parser { position: 0, input: source };
+    let mut parser = Parser::new(source);
     Stylesheet { rules: parser.parse_rules() }
 }
 
+/// Parse a whole CSS stylesheet, failing with the first error if any rule is invalid.
+pub fn try_parse(source: String) -> Result<Parsed<Stylesheet>, ParseError> {
+    let mut parser = Parser::new(source);
+    let stylesheet = Stylesheet { rules: parser.parse_rules() };
+    if !parser.errors.is_empty() {
+        let errors = error::locate(&parser.input, parser.errors);
+        return Err(errors.into_iter().next().unwrap());
+    }
+    Ok(Parsed {
+        value: stylesheet,
+        warnings: error::locate(&parser.input, parser.warnings),
+    })
+}
+
+/*
+    Imported stylesheets are loaded through `loader`, with their URLs resolved against
+    the URL of the stylesheet that imports them. Their rules come before the rules of
+    the importing stylesheet, as if they had been pasted in place of the `@import`.
+    A stylesheet that can't be loaded is skipped, and so is one that imports itself,
+    directly or through other stylesheets.
+ */
+
+/// Parse a stylesheet found at `url`, along with every stylesheet it imports.
+pub fn parse_with_imports(source: String, url: &str, loader: &dyn ResourceLoader) -> Stylesheet {
+    parse_imported(source, url, loader, &mut vec![url.to_string()])
+}
+
+/// Parse a stylesheet and its imports. `loading` holds the URLs of the stylesheets
+/// being imported into this one, to catch import cycles.
+fn parse_imported(source: String, url: &str, loader: &dyn ResourceLoader, loading: &mut Vec<String>) -> Stylesheet {
+    let mut parser = Parser::new(source);
+    let own_rules = parser.parse_rules();
+
+    let mut rules = Vec::new();
+    for import in &parser.imports {
+        let import_url = loader::resolve_url(url, import);
+        if loading.contains(&import_url) {
+            continue;
+        }
+        if let Ok(source) = loader.load_text(&import_url) {
+            loading.push(import_url.clone());
+            rules.extend(parse_imported(source, &import_url, loader, loading).rules);
+            loading.pop();
+        }
+    }
+    rules.extend(own_rules);
+    Stylesheet { rules }
+}
+
+
+/*
+    A `style` attribute holds the inside of a declaration block, without the braces.
+    There is nothing to report it against, so invalid declarations are just dropped.
+
+    e.g.
+        style="color: #ff0000; margin: 10px"
+ */
+
+/// Parse the declarations in an HTML `style` attribute.
+pub fn parse_inline_declarations(source: String) -> Vec<Declaration> {
+    let mut parser = Parser::new(source);
+    let mut declarations = Vec::new();
+    loop {
+        declarations.extend(parser.parse_declaration_list());
+        // A stray '}' doesn't end the attribute.
+        if parser.consume_char().is_none() {
+            break;
+        }
+    }
+    declarations
+}
+
+/*
+    A selector list on its own, outside of a stylesheet, is what DOM methods like
+    `query_selector` take. Unlike in a stylesheet there is nothing to skip to, so
+    anything that isn't a valid selector is an error.
+
+    e.g.
+        "ul li.item, #main" => Err (only simple selectors are supported)
+        "li.item, #main"    => Ok([Selector::Simple(..), Selector::Simple(..)])
+ */
+
+/// Parse a comma-separated list of selectors.
+pub fn parse_selector_list(source: &str) -> Result<Vec<Selector>, ParseError> {
+    let mut parser = Parser::new(source.to_string());
+    parser.consume_whitespace();
+    parser.parse_selectors(None).map_err(|error| error::locate(&parser.input, vec![error]).remove(0))
+}
+
 /// check validation of char input
 /*
     char input must be a-z or A-Z or 0-9 or - or _
@@ -373,3 +679,95 @@ fn valid_identifier_char(c: char) -> bool {
     // TODO: Include U+00A0 and higher.
     matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_')
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The messages of the warnings from `try_parse`, or the error if it failed.
+    fn messages(source: &str) -> Result<Vec<String>, String> {
+        match try_parse(source.to_string()) {
+            Ok(parsed) => Ok(parsed.warnings.iter().map(|warning| warning.to_string()).collect()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_a_valid_stylesheet_without_warnings() {
+        let parsed = try_parse("div.a, #b { margin: 0px; color: #ff0000 }".to_string()).unwrap();
+        assert!(parsed.warnings.is_empty());
+        assert_eq!(parsed.value.rules.len(), 1);
+        assert_eq!(parsed.value.rules[0].declarations.len(), 2);
+    }
+
+    #[test]
+    fn skips_a_bad_declaration_and_keeps_going() {
+        let source = "p { margin: 0; padding: 2px }";
+        let parsed = try_parse(source.to_string()).unwrap();
+        let names: Vec<&str> = parsed.value.rules[0].declarations.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["padding"]);
+        assert_eq!(messages(source), Ok(vec!["line 1, column 14: expected unit but found ';'".to_string()]));
+        assert_eq!(messages("p { margin 0px; padding: 1px }"), Ok(vec!["line 1, column 12: expected ':' but found '0'".to_string()]));
+        assert_eq!(messages("p { color: #ff00zz }"), Ok(vec!["line 1, column 17: expected hexadecimal digit but found 'z'".to_string()]));
+        assert_eq!(messages("p { width: auto"), Ok(vec!["line 1, column 16: expected '}' but found end of input".to_string()]));
+    }
+
+    #[test]
+    fn fails_with_the_first_bad_rule() {
+        let source = "p { margin: 0px }\n\n  h1 { margin: 0px }\n  !h2 { margin: 0px }\n  ?h3 {}";
+        assert_eq!(messages(source), Err("line 4, column 3: expected selector but found '!'".to_string()));
+        // `parse` drops the bad rules and keeps the rest.
+        assert_eq!(parse(source.to_string()).rules.len(), 2);
+    }
+
+    /// The tag name of the first selector of each rule, which is how these tests tell rules apart.
+    fn tag_names(stylesheet: &Stylesheet) -> Vec<String> {
+        stylesheet.rules.iter().map(|rule| match rule.selectors[0] {
+            Selector::Simple(ref selector) => selector.tag_name.clone().unwrap_or_default(),
+        }).collect()
+    }
+
+    #[test]
+    fn puts_imported_rules_first() {
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("file:///site/css/base.css", "@import url(../reset.css); b {}");
+        loader.insert("file:///site/reset.css", "a {}");
+        let source = "@import 'css/base.css';\n@import \"missing.css\";\nc {}";
+        let stylesheet = parse_with_imports(source.to_string(), "file:///site/index.css", &loader);
+        assert_eq!(tag_names(&stylesheet), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn skips_import_cycles() {
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("file:///a.css", "@import 'b.css'; a {}");
+        loader.insert("file:///b.css", "@import 'a.css'; @import 'c.css'; b {}");
+        loader.insert("file:///c.css", "@import 'c.css'; @import 'b.css'; c {}");
+        let source = loader.load_text("file:///a.css").unwrap();
+        let stylesheet = parse_with_imports(source, "file:///a.css", &loader);
+        assert_eq!(tag_names(&stylesheet), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn rejects_imports_after_rules() {
+        assert_eq!(try_parse("a {} @import 'b.css';".to_string()).err().unwrap().to_string(),
+                   "line 1, column 6: expected @import before any rule sets but found @import");
+    }
+
+    #[test]
+    fn parses_a_selector_list_on_its_own() {
+        let selectors = parse_selector_list(" li.item, #main ").unwrap();
+        assert_eq!(selectors.len(), 2);
+        assert_eq!(parse_selector_list("ul li").err().unwrap().to_string(),
+                   "line 1, column 4: expected ',' or end of selector but found 'l'");
+        assert!(parse_selector_list("").is_err());
+    }
+
+    #[test]
+    fn parses_the_selectors_of_a_rule() {
+        let stylesheet = parse("h1, .a#b { margin: 0px }".to_string());
+        assert_eq!(stylesheet.rules[0].selectors.len(), 2);
+        assert_eq!(try_parse("h1 h2 { margin: 0px }".to_string()).err().unwrap().to_string(),
+                   "line 1, column 4: expected ',' or '{' but found 'h'");
+    }
+}
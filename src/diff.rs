@@ -0,0 +1,448 @@
+//! Finding the differences between two DOM trees, and applying them to a tree.
+//!
+//! `diff` returns a list of edits that turn one tree into the other, and `patch`
+//! applies them. A tree that is re-rendered often can then be updated with just
+//! the parts that changed, instead of being replaced.
+
+use crate::dom;
+use std::error::Error;
+use std::fmt;
+
+
+/*
+    An edit finds the node it changes by a path of child indices from the root.
+    Edits are applied one after another, so each path is relative to the tree as
+    the edits before it left it.
+
+    e.g.
+        <ul><li id="a">a</li><li id="b">b</li></ul> => <ul><li id="b">b</li><li id="a" class="x">a</li></ul>
+            Move { parent: [], from: 0, to: 1 }
+            SetAttribute { path: [1], name: "class", value: "x" }
+ */
+/// The position of a node: the index of each child to go through from the root.
+pub type Path = Vec<usize>;
+
+/// One change to a tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Edit {
+    /// Insert `node` as child number `index` of `parent`.
+    Insert { parent: Path, index: usize, node: dom::Node },
+    /// Remove child number `index` of `parent`.
+    Remove { parent: Path, index: usize },
+    /// Take child number `from` out of `parent`, and put it back as child number `to`.
+    Move { parent: Path, from: usize, to: usize },
+    /// Put `node` in the place of the node at `path`.
+    Replace { path: Path, node: dom::Node },
+    /// Set attribute `name` of the element at `path` to `value`, adding it if it's missing.
+    SetAttribute { path: Path, name: String, value: String },
+    /// Remove attribute `name` from the element at `path`, if it has one.
+    RemoveAttribute { path: Path, name: String },
+    /// Change the text of the text or comment node at `path`.
+    SetText { path: Path, text: String },
+}
+
+
+/*
+    Two nodes are considered the same node, changed in place, if they have the same
+    key. For elements that is the namespace, the tag name and the id, so elements
+    with an id are only ever matched with the element with the same id. Changing
+    the id of an element is never a `SetAttribute`: the element is removed, and one
+    with the new id is inserted.
+
+    e.g.
+        <ul><li>a</li><li>b</li></ul> => <ul><li>b</li><li id="x">a</li></ul>
+            Remove { parent: [], index: 1 }
+            Insert { parent: [], index: 1, node: <li id="x">a</li> }
+            SetText { path: [0, 0], text: "b" }
+
+    Among the children of two matched nodes, the longest run of children whose keys
+    appear in the same order in both stays where it is. Other children with a match
+    are moved, children of the old node without one are removed, and children of
+    the new node without one are inserted. Then the matched children are compared
+    in the same way.
+ */
+#[derive(PartialEq)]
+enum Key<'a> {
+    Element(&'a dom::Namespace, &'a str, Option<&'a String>),
+    Text,
+    Comment,
+    Doctype,
+}
+
+fn key(node: &dom::Node) -> Key<'_> {
+    match node.node_type {
+        dom::NodeType::Element(ref element) => Key::Element(&element.namespace, &element.tag_name, element.id()),
+        dom::NodeType::Text(_) => Key::Text,
+        dom::NodeType::Comment(_) => Key::Comment,
+        dom::NodeType::Doctype(_) => Key::Doctype,
+    }
+}
+
+/// Return the edits that turn `old` into `new`.
+pub fn diff(old: &dom::Node, new: &dom::Node) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    if key(old) == key(new) {
+        diff_node(old, new, &mut Vec::new(), &mut edits);
+    } else {
+        edits.push(Edit::Replace { path: Vec::new(), node: new.clone() });
+    }
+    edits
+}
+
+/// Add the edits that turn `old` into `new`, two nodes with the same key at `path`.
+fn diff_node(old: &dom::Node, new: &dom::Node, path: &mut Path, edits: &mut Vec<Edit>) {
+    match (&old.node_type, &new.node_type) {
+        (dom::NodeType::Text(old_text), dom::NodeType::Text(new_text))
+        | (dom::NodeType::Comment(old_text), dom::NodeType::Comment(new_text)) => {
+            if old_text != new_text {
+                edits.push(Edit::SetText { path: path.clone(), text: new_text.clone() });
+            }
+        }
+        (dom::NodeType::Doctype(old_doctype), dom::NodeType::Doctype(new_doctype)) => {
+            if old_doctype != new_doctype {
+                edits.push(Edit::Replace { path: path.clone(), node: new.clone() });
+            }
+        }
+        (dom::NodeType::Element(old_element), dom::NodeType::Element(new_element)) => {
+            diff_attributes(old_element, new_element, path, edits);
+            diff_children(old, new, path, edits);
+        }
+        _ => unreachable!("nodes with the same key have the same type"),
+    }
+}
+
+/// Add the edits that give `old` the attributes of `new`, in order of name.
+fn diff_attributes(old: &dom::Element, new: &dom::Element, path: &Path, edits: &mut Vec<Edit>) {
+    let mut removed: Vec<&String> = old.attributes.keys().filter(|name| !new.attributes.contains_key(*name)).collect();
+    removed.sort();
+    for name in removed {
+        edits.push(Edit::RemoveAttribute { path: path.clone(), name: name.clone() });
+    }
+    let mut names: Vec<&String> = new.attributes.keys().collect();
+    names.sort();
+    for name in names {
+        let value = &new.attributes[name];
+        if old.attributes.get(name) != Some(value) {
+            edits.push(Edit::SetAttribute { path: path.clone(), name: name.clone(), value: value.clone() });
+        }
+    }
+}
+
+/*
+    The children are edited in three passes, each keeping track of which old child
+    is where:
+        1. Remove the old children without a match.
+        2. Move each matched child that isn't part of the longest run to just after
+           the child that comes before it in the new node. That child is either part
+           of the run or was already moved, so it is in the right place.
+        3. Insert the new children without a match, from first to last.
+    Afterwards every matched child is at its index in the new node.
+ */
+fn diff_children(old: &dom::Node, new: &dom::Node, path: &mut Path, edits: &mut Vec<Edit>) {
+    let old_keys: Vec<Key> = old.children.iter().map(key).collect();
+    let new_keys: Vec<Key> = new.children.iter().map(key).collect();
+
+    // `matches[j]` is the old child matched with new child `j`.
+    let mut matches: Vec<Option<usize>> = vec![None; new_keys.len()];
+    let mut in_place = vec![false; new_keys.len()];
+    for (i, j) in longest_common_subsequence(&old_keys, &new_keys) {
+        matches[j] = Some(i);
+        in_place[j] = true;
+    }
+    let mut used = vec![false; old_keys.len()];
+    for &i in matches.iter().flatten() {
+        used[i] = true;
+    }
+    for (j, new_key) in new_keys.iter().enumerate() {
+        if matches[j].is_none() {
+            if let Some(i) = (0..old_keys.len()).find(|&i| !used[i] && old_keys[i] == *new_key) {
+                matches[j] = Some(i);
+                used[i] = true;
+            }
+        }
+    }
+
+    // The old children, in their current order. `None` is an inserted child.
+    let mut current: Vec<Option<usize>> = (0..old_keys.len()).map(Some).collect();
+    for i in (0..old_keys.len()).rev() {
+        if !used[i] {
+            current.remove(i);
+            edits.push(Edit::Remove { parent: path.clone(), index: i });
+        }
+    }
+
+    for j in 0..new_keys.len() {
+        let Some(i) = matches[j] else { continue };
+        if in_place[j] {
+            continue;
+        }
+        let from = current.iter().position(|&child| child == Some(i)).unwrap();
+        current.remove(from);
+        let to = match matches[..j].iter().rev().flatten().next() {
+            Some(&previous) => current.iter().position(|&child| child == Some(previous)).unwrap() + 1,
+            None => 0,
+        };
+        current.insert(to, Some(i));
+        if from != to {
+            edits.push(Edit::Move { parent: path.clone(), from, to });
+        }
+    }
+
+    for (j, child) in new.children.iter().enumerate() {
+        if matches[j].is_none() {
+            current.insert(j, None);
+            edits.push(Edit::Insert { parent: path.clone(), index: j, node: child.clone() });
+        }
+    }
+
+    for (j, child) in new.children.iter().enumerate() {
+        if let Some(i) = matches[j] {
+            path.push(j);
+            diff_node(&old.children[i], child, path, edits);
+            path.pop();
+        }
+    }
+}
+
+/// Return the index pairs of a longest common subsequence of `a` and `b`.
+fn longest_common_subsequence<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    // `lengths[i][j]` is the length of the longest common subsequence of `a[i..]` and `b[j..]`.
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+
+/// The reason a patch couldn't be applied: edit number `edit` doesn't fit the tree.
+#[derive(Debug, PartialEq)]
+pub struct PatchError {
+    pub edit: usize,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "edit {} doesn't fit the tree", self.edit)
+    }
+}
+
+impl Error for PatchError {}
+
+/// Apply `edits` to `root`, in order. Stops at the first edit that doesn't fit the tree,
+/// leaving the edits before it applied.
+pub fn patch(root: &mut dom::Node, edits: &[Edit]) -> Result<(), PatchError> {
+    for (index, edit) in edits.iter().enumerate() {
+        apply(root, edit).ok_or(PatchError { edit: index })?;
+    }
+    Ok(())
+}
+
+/// Apply one edit, returning `None` if it doesn't fit the tree.
+fn apply(root: &mut dom::Node, edit: &Edit) -> Option<()> {
+    match edit {
+        Edit::Insert { parent, index, node } => {
+            let children = &mut element_at(root, parent)?.children;
+            if *index > children.len() {
+                return None;
+            }
+            children.insert(*index, node.clone());
+        }
+        Edit::Remove { parent, index } => {
+            let children = &mut element_at(root, parent)?.children;
+            if *index >= children.len() {
+                return None;
+            }
+            children.remove(*index);
+        }
+        Edit::Move { parent, from, to } => {
+            let children = &mut element_at(root, parent)?.children;
+            if *from >= children.len() || *to >= children.len() {
+                return None;
+            }
+            let child = children.remove(*from);
+            children.insert(*to, child);
+        }
+        Edit::Replace { path, node } => *node_at(root, path)? = node.clone(),
+        Edit::SetAttribute { path, name, value } => match element_at(root, path)?.node_type {
+            dom::NodeType::Element(ref mut element) => {
+                element.attributes.insert(name.clone(), value.clone());
+            }
+            _ => return None,
+        },
+        Edit::RemoveAttribute { path, name } => match element_at(root, path)?.node_type {
+            dom::NodeType::Element(ref mut element) => {
+                element.attributes.remove(name);
+            }
+            _ => return None,
+        },
+        Edit::SetText { path, text: new_text } => match node_at(root, path)?.node_type {
+            dom::NodeType::Text(ref mut text) | dom::NodeType::Comment(ref mut text) => *text = new_text.clone(),
+            _ => return None,
+        },
+    }
+    Some(())
+}
+
+fn node_at<'a>(root: &'a mut dom::Node, path: &Path) -> Option<&'a mut dom::Node> {
+    let mut node = root;
+    for &index in path {
+        node = node.children.get_mut(index)?;
+    }
+    Some(node)
+}
+
+/// The node at `path`, if it is an element.
+fn element_at<'a>(root: &'a mut dom::Node, path: &Path) -> Option<&'a mut dom::Node> {
+    node_at(root, path).filter(|node| matches!(node.node_type, dom::NodeType::Element(_)))
+}
+
+
+/*
+    Edits can be written out one per line, for showing what changed. Paths are
+    written like file paths, with "/" for the root.
+
+    e.g.
+        insert <li> at /0/2
+        set attribute class="active" on /0/1
+        set text "Hello" on /1/0
+ */
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edit::Insert { parent, index, node } => {
+                write!(f, "insert {} at {}", describe(node), child_path(parent, *index))
+            }
+            Edit::Remove { parent, index } => write!(f, "remove {}", child_path(parent, *index)),
+            Edit::Move { parent, from, to } => {
+                write!(f, "move {} to {}", child_path(parent, *from), child_path(parent, *to))
+            }
+            Edit::Replace { path, node } => write!(f, "replace {} with {}", path_string(path), describe(node)),
+            Edit::SetAttribute { path, name, value } => {
+                write!(f, "set attribute {}={:?} on {}", name, value, path_string(path))
+            }
+            Edit::RemoveAttribute { path, name } => write!(f, "remove attribute {} from {}", name, path_string(path)),
+            Edit::SetText { path, text } => write!(f, "set text {:?} on {}", text, path_string(path)),
+        }
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.iter().map(|index| format!("/{}", index)).collect()
+}
+
+fn child_path(parent: &Path, index: usize) -> String {
+    let mut path = parent.clone();
+    path.push(index);
+    path_string(&path)
+}
+
+/// A short description of a node, e.g. `<li>` or `text "Hello"`.
+fn describe(node: &dom::Node) -> String {
+    match node.node_type {
+        dom::NodeType::Element(ref element) => format!("<{}>", element.tag_name),
+        dom::NodeType::Text(ref text) => format!("text {:?}", text),
+        dom::NodeType::Comment(_) => "comment".to_string(),
+        dom::NodeType::Doctype(_) => "doctype".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    /// The edits from `old` to `new`, written out.
+    fn edits(old: &str, new: &str) -> Vec<String> {
+        diff(&html::parse(old.to_string()), &html::parse(new.to_string())).iter().map(|edit| edit.to_string()).collect()
+    }
+
+    /// Parse `source`, leaving out the spans, which the edits don't keep up to date.
+    fn parse(source: &str) -> dom::Node {
+        fn clear_spans(node: &mut dom::Node) {
+            node.span = None;
+            node.children.iter_mut().for_each(clear_spans);
+        }
+        let mut node = html::parse(source.to_string());
+        clear_spans(&mut node);
+        node
+    }
+
+    /// Check that patching `old` with the edits from `old` to `new` gives `new`.
+    fn assert_round_trip(old: &str, new: &str) {
+        let mut tree = parse(old);
+        let edits = diff(&tree, &parse(new));
+        patch(&mut tree, &edits).unwrap();
+        assert_eq!(tree, parse(new), "{:?} => {:?}", old, new);
+    }
+
+    #[test]
+    fn finds_the_edits_in_the_examples() {
+        assert_eq!(edits("<ul><li id=a>a</li><li id=b>b</li></ul>", "<ul><li id=b>b</li><li id=a class=x>a</li></ul>"),
+                   ["move /0 to /1", "set attribute class=\"x\" on /1"]);
+        assert_eq!(edits("<ul><li>a</li><li>b</li></ul>", "<ul><li>b</li><li id=x>a</li></ul>"),
+                   ["remove /1", "insert <li> at /1", "set text \"b\" on /0/0"]);
+        assert_eq!(edits("<p>a</p>", "<div>a</div>"), ["replace / with <div>"]);
+        assert!(edits("<p class=x>a<!--b--></p>", "<p class=x>a<!--b--></p>").is_empty());
+    }
+
+    #[test]
+    fn patches_old_into_new() {
+        let lists = [
+            "<ul></ul>",
+            "<ul><li id=a>a</li></ul>",
+            "<ul><li id=a>a</li><li id=b>b</li><li id=c>c</li></ul>",
+            "<ul><li id=c>c</li><li id=b>b</li><li id=a>a</li></ul>",
+            "<ul><li id=b>b</li><li id=d>d</li><li id=a>a</li></ul>",
+            "<ul><li id=d>d</li><li>x</li><li id=a>a</li><li id=c class=y>c</li></ul>",
+            "<ul><li>x</li>text<!--note--><li id=a><b>a</b></li></ul>",
+        ];
+        for old in lists {
+            for new in lists {
+                assert_round_trip(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn patches_attributes_and_text() {
+        assert_round_trip("<div class=a title=t>one<p>two</p></div>", "<div class=b data-x=1>uno<p>dos</p></div>");
+        assert_round_trip("<div>a<!--b--></div>", "<div>c<!--d--></div>");
+        assert_round_trip("<div id=a>a</div>", "<div id=b>a</div>");
+        assert_round_trip("<div><svg><rect/></svg></div>", "<div><svg><circle/></svg></div>");
+    }
+
+    #[test]
+    fn refuses_edits_that_dont_fit() {
+        let mut tree = parse("<p>a</p>");
+        let edits = [
+            Edit::SetText { path: vec![0], text: "b".to_string() },
+            Edit::Remove { parent: vec![], index: 1 },
+        ];
+        assert_eq!(patch(&mut tree, &edits), Err(PatchError { edit: 1 }));
+        assert_eq!(tree, parse("<p>b</p>"));
+    }
+}
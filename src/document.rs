@@ -0,0 +1,586 @@
+//! A DOM document that can be navigated in every direction.
+//!
+//! A `dom::Node` owns its children, so there is no way to get from a node to its
+//! parent or its siblings. A `Document` keeps all of its nodes in one list instead,
+//! and the nodes refer to each other by `NodeId`.
+//!
+//! The tree can be changed in place with the mutation methods, like `append_child`
+//! and `set_attribute`, which keep all the links up to date and report each change
+//! to any mutation observers.
+
+use crate::dom::{self, DomError};
+use crate::event::Listeners;
+use crate::mutation::{MutationRecord, Observers};
+
+
+/*
+    A `NodeId` is an index into the document's list of nodes. It is only meaningful
+    for the document it came from.
+
+    e.g.
+        Document {
+            nodes: [
+                NodeData { node_type: Element("ul"), first_child: Some(NodeId(1)), .. },
+                NodeData { node_type: Element("li"), parent: Some(NodeId(0)), next_sibling: Some(NodeId(2)), .. },
+                NodeData { node_type: Element("li"), parent: Some(NodeId(0)), previous_sibling: Some(NodeId(1)), .. },
+            ],
+            root: NodeId(0),
+        }
+ */
+/// Identifies a node in a `Document`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// A node in a `Document`, with links to the nodes around it.
+struct NodeData {
+    node_type: dom::NodeType,
+    span: Option<dom::Span>,
+    parent: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    previous_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
+}
+
+/// A tree of nodes stored in a flat list.
+pub struct Document {
+    nodes: Vec<NodeData>,
+    root: NodeId,
+    pub(crate) listeners: Listeners,
+    pub(crate) observers: Observers,
+}
+
+
+/*
+    A document is built from a `dom::Node` tree, so `html::parse` and the constructor
+    functions in `dom` still work for making one. `to_node` goes the other way, for
+    the parts of the engine that work on `dom::Node`s, like styling and layout.
+
+    e.g.
+        let document = Document::new(dom::element("p".to_string(), HashMap::new(), vec![
+            dom::text("hello".to_string()),
+        ]));
+ */
+impl Document {
+    /// Build a document from a tree of `dom::Node`s.
+    pub fn new(root: dom::Node) -> Document {
+        let mut document = Document {
+            nodes: Vec::new(),
+            root: NodeId(0),
+            listeners: Listeners::default(),
+            observers: Observers::default(),
+        };
+        document.root = document.add_tree(root, None);
+        document
+    }
+
+    /// Add `node` and its descendants as the last child of `parent`, returning the new id.
+    fn add_tree(&mut self, node: dom::Node, parent: Option<NodeId>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(NodeData {
+            node_type: node.node_type,
+            span: node.span,
+            parent,
+            first_child: None,
+            last_child: None,
+            previous_sibling: None,
+            next_sibling: None,
+        });
+
+        if let Some(parent) = parent {
+            let previous = self.data(parent).last_child;
+            match previous {
+                Some(previous) => self.data_mut(previous).next_sibling = Some(id),
+                None => self.data_mut(parent).first_child = Some(id),
+            }
+            self.data_mut(id).previous_sibling = previous;
+            self.data_mut(parent).last_child = Some(id);
+        }
+
+        for child in node.children {
+            self.add_tree(child, Some(id));
+        }
+        id
+    }
+
+    fn data(&self, id: NodeId) -> &NodeData {
+        &self.nodes[id.0]
+    }
+
+    fn data_mut(&mut self, id: NodeId) -> &mut NodeData {
+        &mut self.nodes[id.0]
+    }
+
+    /// The root node of the document.
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    /// What kind of node `id` is, with its data.
+    pub fn node_type(&self, id: NodeId) -> &dom::NodeType {
+        &self.data(id).node_type
+    }
+
+    /// The data of node `id`, for changing it directly. Changes made this way aren't
+    /// seen by mutation observers; use the mutation methods for that.
+    pub fn node_type_mut(&mut self, id: NodeId) -> &mut dom::NodeType {
+        &mut self.data_mut(id).node_type
+    }
+
+    /// Return the element data of node `id`, or `None` if it isn't an element.
+    pub fn element(&self, id: NodeId) -> Option<&dom::Element> {
+        match self.data(id).node_type {
+            dom::NodeType::Element(ref element) => Some(element),
+            _ => None,
+        }
+    }
+
+    /// Where node `id` came from in the source, if it was parsed.
+    pub fn span(&self, id: NodeId) -> Option<dom::Span> {
+        self.data(id).span
+    }
+
+    /// The parent of node `id`, or `None` for the root and for removed nodes.
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.data(id).parent
+    }
+
+    /// The first child of node `id`, if it has any children.
+    pub fn first_child(&self, id: NodeId) -> Option<NodeId> {
+        self.data(id).first_child
+    }
+
+    /// The last child of node `id`, if it has any children.
+    pub fn last_child(&self, id: NodeId) -> Option<NodeId> {
+        self.data(id).last_child
+    }
+
+    /// The child of the same parent just before node `id`.
+    pub fn previous_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.data(id).previous_sibling
+    }
+
+    /// The child of the same parent just after node `id`.
+    pub fn next_sibling(&self, id: NodeId) -> Option<NodeId> {
+        self.data(id).next_sibling
+    }
+
+    /// Is `ancestor` the same node as `id`, or one of its ancestors?
+    fn is_inclusive_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut node = Some(id);
+        while let Some(current) = node {
+            if current == ancestor {
+                return true;
+            }
+            node = self.parent(current);
+        }
+        false
+    }
+
+    /// Copy node `id` and its descendants into a tree of `dom::Node`s.
+    pub fn to_node(&self, id: NodeId) -> dom::Node {
+        let mut children = Vec::new();
+        let mut child = self.first_child(id);
+        while let Some(child_id) = child {
+            children.push(self.to_node(child_id));
+            child = self.next_sibling(child_id);
+        }
+        dom::Node {
+            node_type: self.data(id).node_type.clone(),
+            children,
+            span: self.data(id).span,
+        }
+    }
+}
+
+
+/*
+    The mutation methods work like their DOM counterparts. A node that is inserted
+    somewhere is first removed from where it was. Removed nodes stay in the document's
+    list, without a parent, so they can be inserted again later.
+
+    Mistakes that would break the tree, like inserting a node into itself, leave the
+    tree as it was and return an error, named after the DOM exception for it.
+
+    e.g.
+        let item = document.create_node(dom::element("li".to_string(), HashMap::new(), vec![]));
+        document.append_child(list, item)?;
+        document.set_text_content(item, "three");
+        document.set_attribute(item, "class", "last");
+ */
+impl Document {
+    /// Add a tree of `dom::Node`s to the document without a parent, returning the id of its root.
+    pub fn create_node(&mut self, node: dom::Node) -> NodeId {
+        self.add_tree(node, None)
+    }
+
+    /// Insert `child` as the last child of `parent`.
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Insert `child` into `parent` just before `reference`, or at the end if `reference` is `None`.
+    pub fn insert_before(&mut self, parent: NodeId, child: NodeId, reference: Option<NodeId>) -> Result<(), DomError> {
+        if let Some(reference) = reference {
+            if self.parent(reference) != Some(parent) {
+                return Err(DomError::NotFound);
+            }
+        }
+        self.check_insertion(parent, child)?;
+        if reference == Some(child) {
+            return Ok(());
+        }
+
+        self.remove_from_parent(child);
+        let previous = match reference {
+            Some(reference) => self.previous_sibling(reference),
+            None => self.last_child(parent),
+        };
+        self.link(parent, child, reference);
+        self.queue_mutation_record(MutationRecord::child_list(parent, vec![child], Vec::new(), previous, reference));
+        Ok(())
+    }
+
+    /// Remove `child` from `parent`, and return it.
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<NodeId, DomError> {
+        if self.parent(child) != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        self.remove_from_parent(child);
+        Ok(child)
+    }
+
+    /// Put `new_child` in the place of `old_child`, and return `old_child`.
+    pub fn replace_child(&mut self, parent: NodeId, new_child: NodeId, old_child: NodeId) -> Result<NodeId, DomError> {
+        if self.parent(old_child) != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        self.check_insertion(parent, new_child)?;
+        if new_child == old_child {
+            return Ok(old_child);
+        }
+
+        let reference = match self.next_sibling(old_child) {
+            // `new_child` is about to be moved, so it can't be the reference.
+            Some(next) if next == new_child => self.next_sibling(new_child),
+            next => next,
+        };
+        self.remove_from_parent(new_child);
+        let previous = self.previous_sibling(old_child);
+        self.detach(old_child);
+        self.link(parent, new_child, reference);
+        self.queue_mutation_record(MutationRecord::child_list(parent, vec![new_child], vec![old_child], previous, reference));
+        Ok(old_child)
+    }
+
+    /// Check that inserting `child` into `parent` wouldn't break the tree.
+    fn check_insertion(&self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        // A node can't be inserted into itself, and only elements can have children.
+        if self.is_inclusive_ancestor(child, parent) || self.element(parent).is_none() {
+            return Err(DomError::HierarchyRequest);
+        }
+        Ok(())
+    }
+
+    /// Detach node `id` from its parent, if it has one, and record the change.
+    fn remove_from_parent(&mut self, id: NodeId) {
+        if let Some(parent) = self.parent(id) {
+            let previous = self.previous_sibling(id);
+            let next = self.next_sibling(id);
+            self.detach(id);
+            self.queue_mutation_record(MutationRecord::child_list(parent, Vec::new(), vec![id], previous, next));
+        }
+    }
+
+    /// Link the detached node `child` into `parent` just before `reference`, or at the end.
+    fn link(&mut self, parent: NodeId, child: NodeId, reference: Option<NodeId>) {
+        let previous = match reference {
+            Some(reference) => self.previous_sibling(reference),
+            None => self.last_child(parent),
+        };
+        match previous {
+            Some(previous) => self.data_mut(previous).next_sibling = Some(child),
+            None => self.data_mut(parent).first_child = Some(child),
+        }
+        match reference {
+            Some(reference) => self.data_mut(reference).previous_sibling = Some(child),
+            None => self.data_mut(parent).last_child = Some(child),
+        }
+        let data = self.data_mut(child);
+        data.parent = Some(parent);
+        data.previous_sibling = previous;
+        data.next_sibling = reference;
+    }
+
+
+    /// Unlink node `id` from its parent and siblings, if it has any.
+    fn detach(&mut self, id: NodeId) {
+        let Some(parent) = self.parent(id) else {
+            return;
+        };
+        let previous = self.previous_sibling(id);
+        let next = self.next_sibling(id);
+        match previous {
+            Some(previous) => self.data_mut(previous).next_sibling = next,
+            None => self.data_mut(parent).first_child = next,
+        }
+        match next {
+            Some(next) => self.data_mut(next).previous_sibling = previous,
+            None => self.data_mut(parent).last_child = previous,
+        }
+        let data = self.data_mut(id);
+        data.parent = None;
+        data.previous_sibling = None;
+        data.next_sibling = None;
+    }
+
+    /*
+        `Element::id` and `Element::classes` read the "id" and "class" attributes
+        each time they are called, so changing those attributes here is enough to
+        keep them up to date. Attribute names on HTML elements are lower case, like
+        the ones the parser makes, so `set_attribute(id, "ID", ..)` sets "id".
+     */
+    /// Set attribute `name` of element `id` to `value`. Does nothing if `id` isn't an element.
+    pub fn set_attribute(&mut self, id: NodeId, name: &str, value: &str) {
+        if let dom::NodeType::Element(ref mut element) = self.data_mut(id).node_type {
+            let name = attribute_name(element, name);
+            let old_value = element.attributes.insert(name.clone(), value.to_string());
+            self.queue_mutation_record(MutationRecord::attributes(id, &name, old_value));
+        }
+    }
+
+    /// Remove attribute `name` from element `id`, and return its old value.
+    pub fn remove_attribute(&mut self, id: NodeId, name: &str) -> Option<String> {
+        let (name, old_value) = match self.data_mut(id).node_type {
+            dom::NodeType::Element(ref mut element) => {
+                let name = attribute_name(element, name);
+                let old_value = element.attributes.remove(&name);
+                (name, old_value)
+            }
+            _ => return None,
+        };
+        if old_value.is_some() {
+            self.queue_mutation_record(MutationRecord::attributes(id, &name, old_value.clone()));
+        }
+        old_value
+    }
+
+    /// Return the text of node `id` and its descendants, like `dom::Node::text_content`.
+    pub fn text_content(&self, id: NodeId) -> String {
+        match self.data(id).node_type {
+            dom::NodeType::Text(ref data) | dom::NodeType::Comment(ref data) => data.clone(),
+            dom::NodeType::Doctype(_) => String::new(),
+            dom::NodeType::Element(_) => self.descendants(id).filter_map(|node| match self.data(node).node_type {
+                dom::NodeType::Text(ref data) => Some(data.as_str()),
+                _ => None,
+            }).collect(),
+        }
+    }
+
+    /*
+        Setting the text content of an element replaces all of its children with a
+        single text node, or with nothing if the text is empty. For text and comments
+        it replaces their data. Doctypes have no text content, so they are left alone.
+
+        Removed children stay in the document's list, so a text child that is already
+        there is reused. Otherwise setting the text over and over, like a ticking
+        clock, would add a new node to the list every time. Mutation observers see
+        the reused node's text change, and any other children being removed.
+
+        e.g.
+            <p>Hello <b>world</b></p>, "Bye" => <p>Bye</p>
+     */
+    /// Replace the contents of node `id` with `text`.
+    pub fn set_text_content(&mut self, id: NodeId, text: &str) {
+        match self.data_mut(id).node_type {
+            dom::NodeType::Text(ref mut data) | dom::NodeType::Comment(ref mut data) => {
+                let old_value = std::mem::replace(data, text.to_string());
+                self.queue_mutation_record(MutationRecord::character_data(id, old_value));
+            }
+            dom::NodeType::Doctype(_) => {}
+            dom::NodeType::Element(_) => {
+                let reused = self.first_child(id)
+                    .filter(|&child| !text.is_empty() && matches!(self.data(child).node_type, dom::NodeType::Text(_)));
+                let removed: Vec<NodeId> = self.children(id).filter(|&child| Some(child) != reused).collect();
+                for &child in &removed {
+                    self.detach(child);
+                }
+                if let Some(child) = reused {
+                    if !removed.is_empty() {
+                        self.queue_mutation_record(MutationRecord::child_list(id, Vec::new(), removed, Some(child), None));
+                    }
+                    self.set_text_content(child, text);
+                    return;
+                }
+                let mut added = Vec::new();
+                if !text.is_empty() {
+                    let text_node = self.create_node(dom::text(text.to_string()));
+                    self.link(id, text_node, None);
+                    added.push(text_node);
+                }
+                if !added.is_empty() || !removed.is_empty() {
+                    self.queue_mutation_record(MutationRecord::child_list(id, added, removed, None, None));
+                }
+            }
+        }
+    }
+}
+
+/// The name attribute `name` is stored under on `element`: lower case for HTML elements.
+fn attribute_name(element: &dom::Element, name: &str) -> String {
+    match element.namespace {
+        dom::Namespace::Html => name.to_ascii_lowercase(),
+        _ => name.to_string(),
+    }
+}
+
+impl From<dom::Node> for Document {
+    fn from(root: dom::Node) -> Document {
+        Document::new(root)
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::html;
+
+    #[test]
+    fn links_parents_children_and_siblings() {
+        let document = Document::new(html::parse("<ul><li>a</li><li>b</li><li>c</li></ul>".to_string()));
+        let list = document.root();
+        let first = document.first_child(list).unwrap();
+        let last = document.last_child(list).unwrap();
+        let middle = document.next_sibling(first).unwrap();
+
+        assert_eq!(document.element(list).unwrap().tag_name, "ul");
+        assert_eq!(document.parent(list), None);
+        assert_eq!(document.parent(middle), Some(list));
+        assert_eq!(document.next_sibling(middle), Some(last));
+        assert_eq!(document.previous_sibling(middle), Some(first));
+        assert_eq!(document.previous_sibling(first), None);
+        assert_eq!(document.next_sibling(last), None);
+
+        let text = document.first_child(middle).unwrap();
+        assert!(matches!(document.node_type(text), dom::NodeType::Text(data) if data == "b"));
+        assert!(document.element(text).is_none());
+        assert_eq!(document.span(text), Some(dom::Span { start: 18, end: 19 }));
+    }
+
+    #[test]
+    fn converts_back_to_the_same_tree() {
+        let node = html::parse("<!-- a --><div id=x><p>Hello <b>world</b></p>text</div>".to_string());
+        let document = Document::from(node.clone());
+        assert_eq!(document.to_node(document.root()).outer_html(), node.outer_html());
+
+        let paragraph = document.first_child(document.root()).unwrap();
+        assert_eq!(document.to_node(paragraph).outer_html(), node.children[0].outer_html());
+        assert_eq!(document.span(paragraph), node.children[0].span);
+    }
+
+    /// A document for `<ul><li>a</li><li>b</li></ul>`, with the ids of the list and its items.
+    pub(crate) fn list() -> (Document, NodeId, NodeId, NodeId) {
+        let document = Document::new(html::parse("<ul><li>a</li><li>b</li></ul>".to_string()));
+        let list = document.root();
+        let first = document.first_child(list).unwrap();
+        let second = document.last_child(list).unwrap();
+        (document, list, first, second)
+    }
+
+    fn html_of(document: &Document) -> String {
+        document.to_node(document.root()).outer_html()
+    }
+
+    #[test]
+    fn inserts_moves_and_removes_children() {
+        let (mut document, list, first, second) = list();
+        let item = document.create_node(dom::element("li".to_string(), Default::default(), vec![dom::text("c".to_string())]));
+        document.append_child(list, item).unwrap();
+        assert_eq!(html_of(&document), "<ul><li>a</li><li>b</li><li>c</li></ul>");
+
+        // Inserting a node that is already in the tree moves it.
+        document.insert_before(list, item, Some(first)).unwrap();
+        assert_eq!(html_of(&document), "<ul><li>c</li><li>a</li><li>b</li></ul>");
+        assert_eq!(document.previous_sibling(first), Some(item));
+
+        assert_eq!(document.remove_child(list, second), Ok(second));
+        assert_eq!(document.parent(second), None);
+        assert_eq!(html_of(&document), "<ul><li>c</li><li>a</li></ul>");
+
+        assert_eq!(document.replace_child(list, second, item), Ok(item));
+        assert_eq!(html_of(&document), "<ul><li>b</li><li>a</li></ul>");
+        assert_eq!(document.last_child(list), Some(first));
+    }
+
+    #[test]
+    fn refuses_changes_that_would_break_the_tree() {
+        let (mut document, list, first, second) = list();
+        let text = document.first_child(first).unwrap();
+
+        assert_eq!(document.append_child(first, list), Err(DomError::HierarchyRequest));
+        assert_eq!(document.append_child(list, list), Err(DomError::HierarchyRequest));
+        assert_eq!(document.append_child(text, second), Err(DomError::HierarchyRequest));
+        assert_eq!(document.replace_child(first, list, text), Err(DomError::HierarchyRequest));
+        assert_eq!(document.insert_before(list, second, Some(text)), Err(DomError::NotFound));
+        assert_eq!(document.remove_child(list, text), Err(DomError::NotFound));
+        assert_eq!(document.replace_child(list, second, text), Err(DomError::NotFound));
+        assert_eq!(html_of(&document), "<ul><li>a</li><li>b</li></ul>");
+    }
+
+    #[test]
+    fn lower_cases_html_attribute_names() {
+        let (mut document, list, first, _) = list();
+        document.set_attribute(first, "ID", "top");
+        assert_eq!(document.element(first).unwrap().id().map(String::as_str), Some("top"));
+        assert_eq!(document.remove_attribute(first, "Id"), Some("top".to_string()));
+        assert_eq!(document.element(first).unwrap().id(), None);
+
+        let svg = document.create_node(html::parse("<svg></svg>".to_string()));
+        document.append_child(list, svg).unwrap();
+        document.set_attribute(svg, "viewBox", "0 0 1 1");
+        assert!(document.element(svg).unwrap().attributes.contains_key("viewBox"));
+    }
+
+    #[test]
+    fn sets_text_content() {
+        let (mut document, list, first, _) = list();
+        document.set_text_content(list, "gone");
+        assert_eq!(html_of(&document), "<ul>gone</ul>");
+        assert_eq!(document.parent(first), None);
+        assert_eq!(document.text_content(list), "gone");
+
+        let text = document.first_child(list).unwrap();
+        document.set_text_content(text, "changed");
+        assert_eq!(html_of(&document), "<ul>changed</ul>");
+        document.set_text_content(list, "");
+        assert_eq!(document.first_child(list), None);
+    }
+
+    #[test]
+    fn setting_text_content_again_reuses_the_text_node() {
+        let (mut document, _, first, _) = list();
+        let text = document.first_child(first).unwrap();
+        let nodes = document.nodes.len();
+        for tick in 0..3 {
+            document.set_text_content(first, &tick.to_string());
+        }
+        assert_eq!(document.first_child(first), Some(text));
+        assert_eq!(document.last_child(first), Some(text));
+        assert_eq!(html_of(&document), "<ul><li>2</li><li>b</li></ul>");
+        assert_eq!(document.nodes.len(), nodes);
+    }
+
+    #[test]
+    fn text_content_joins_the_text_of_descendants() {
+        let node = html::parse("<p>Hello <b>world</b><!-- note --><br>!</p>".to_string());
+        assert_eq!(node.text_content(), "Hello world!");
+        assert_eq!(node.children[2].text_content(), " note ");
+
+        let document = Document::new(node);
+        let paragraph = document.root();
+        assert_eq!(document.text_content(paragraph), "Hello world!");
+        let bold = document.next_sibling(document.first_child(paragraph).unwrap()).unwrap();
+        assert_eq!(document.text_content(bold), "world");
+    }
+}
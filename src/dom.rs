@@ -1,6 +1,9 @@
 //! Basic DOM data structures.
 
-use std::collections::{HashMap, HashSet};
+use crate::loader;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 
 
 /*
@@ -8,20 +11,45 @@ use std::collections::{HashMap, HashSet};
     various other attributes and methods, but we can ignore most of those for now.)
  */
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Node {
     // data specific to each node type
     pub node_type: NodeType,
 
     // data common to all nodes
     pub children: Vec<Node>,
+    pub span: Option<Span>,
+}
+
+
+/*
+    A node made by the parser remembers where it came from in the source, as a range
+    of byte offsets. An element's span runs from its start tag to its end tag, or to
+    the tag that closed it implicitly. Nodes made any other way don't have a span.
+
+    e.g.
+        "<p>hi <b>there</b></p>" =>
+            p:      Span { start: 0, end: 22 }
+            "hi ":  Span { start: 3, end: 6 }
+            b:      Span { start: 6, end: 18 }
+        "<p>a<p>b" =>
+            first p:    Span { start: 0, end: 7 }, up to the end of the second <p>
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 
 /*
     There are several node types, but for now we will ignore most of them and say
-    that a node is either an Element or a Text node. In a language with inheritance
-    these would be subtypes of Node. In Rust, they can be enum (Rust's keyword for
-    a "tagged union" or "sum type").
+    that a node is an Element, a Text node, a Comment or a Doctype. In a language with
+    inheritance these would be subtypes of Node. In Rust, they can be enum (Rust's keyword
+    for a "tagged union" or "sum type").
+
+    Comments and doctypes are kept in the tree so nothing from the source is lost,
+    but they are never styled or rendered.
  */
 
 /*
@@ -29,34 +57,101 @@ pub struct Node {
         NodeType {
             Text("..."),
             Element(ElementData),
+            Comment("..."),
+            Doctype(Doctype),
         }
  */
+#[derive(Clone, Debug, PartialEq)]
 pub enum NodeType {
     Element(Element),
     Text(String),
+    Comment(String),
+    Doctype(Doctype),
 }
 
 
 /*
-    An element includes a tag name and any number of attributes, which can be stored
-    as a map from names to values. This engine doesn't support namespaces, so it just
-    stores tag and attribute names as simple strings.
+    An element includes a tag name, a namespace and any number of attributes, which
+    can be stored as a map from names to values. Most elements are HTML elements, but
+    inline SVG and MathML elements live in their own namespaces. Their tag names keep
+    their case, like "foreignObject", while HTML tag names are always lower case.
  */
 
 /*
     e.g.
         Element {
             tag_name: "div",
+            namespace: Namespace::Html,
             attributes: AttributeMap,
         }
  */
+#[derive(Clone, Debug, PartialEq)]
 pub struct Element {
     pub tag_name: String,
+    pub namespace: Namespace,
     pub attributes: AttributeMap,
 }
 
 
 /*
+    Namespaces are identified by URLs. Besides the element namespaces, a few
+    attributes on SVG and MathML elements have their own namespaces, which show up
+    as a prefix in the attribute name.
+
+    e.g.
+        <svg><use xlink:href="#icon"/></svg> => "xlink:href" is in Namespace::XLink
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Namespace {
+    Html,
+    Svg,
+    MathMl,
+    XLink,
+    Xml,
+    Xmlns,
+}
+
+impl Namespace {
+    /// The URL that identifies the namespace.
+    pub fn url(&self) -> &'static str {
+        match self {
+            Namespace::Html => "http://www.w3.org/1999/xhtml",
+            Namespace::Svg => "http://www.w3.org/2000/svg",
+            Namespace::MathMl => "http://www.w3.org/1998/Math/MathML",
+            Namespace::XLink => "http://www.w3.org/1999/xlink",
+            Namespace::Xml => "http://www.w3.org/XML/1998/namespace",
+            Namespace::Xmlns => "http://www.w3.org/2000/xmlns/",
+        }
+    }
+}
+
+
+/*
+    A doctype has a name and optional public and system identifiers. An identifier
+    that wasn't given in the source is stored as an empty string.
+ */
+
+/*
+    e.g.
+        <!DOCTYPE html> =>
+            Doctype {
+                name: "html",
+                public_id: "",
+                system_id: "",
+            }
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: String,
+    pub system_id: String,
+}
+
+
+/*
+    Attribute names are always lower case. If a tag lists the same attribute twice,
+    only the first one is kept, as in browsers.
+
     e.g.
         "id": "...",
         "class": "...",
@@ -74,14 +169,75 @@ pub fn text(data: String) -> Node {
     Node {
         node_type: NodeType::Text(data),
         children: Vec::new(),
+        span: None,
+    }
+}
+
+/// Return a new `Node` with `Comment`
+pub fn comment(data: String) -> Node {
+    Node {
+        node_type: NodeType::Comment(data),
+        children: Vec::new(),
+        span: None,
+    }
+}
+
+/// Return a new `Node` with `Doctype`
+pub fn doctype(name: String, public_id: String, system_id: String) -> Node {
+    Node {
+        node_type: NodeType::Doctype(Doctype { name, public_id, system_id }),
+        children: Vec::new(),
+        span: None,
     }
 }
 
-/// Return a new `Node` with `Element`
+/// Return a new `Node` with an HTML `Element`
 pub fn element(tag_name: String, attributes: AttributeMap, children: Vec<Node>) -> Node {
+    element_ns(Namespace::Html, tag_name, attributes, children)
+}
+
+/// Return a new `Node` with an `Element` in the given namespace
+pub fn element_ns(namespace: Namespace, tag_name: String, attributes: AttributeMap, children: Vec<Node>) -> Node {
     Node {
-        node_type: NodeType::Element(Element { tag_name, attributes }),
+        node_type: NodeType::Element(Element { tag_name, namespace, attributes }),
         children,
+        span: None,
+    }
+}
+
+
+/*
+    The text content of a node is the text it holds, without any markup. For an
+    element that's the text of all its descendants joined together, in document
+    order. Comments inside an element are left out.
+
+    e.g.
+        <p>Hello <b>world</b><!-- note --></p> => "Hello world"
+ */
+
+impl Node {
+    /// Return the text of this node and its descendants.
+    pub fn text_content(&self) -> String {
+        match self.node_type {
+            NodeType::Text(ref data) | NodeType::Comment(ref data) => data.clone(),
+            NodeType::Doctype(_) => String::new(),
+            NodeType::Element(_) => {
+                let mut text = String::new();
+                collect_text(self, &mut text);
+                text
+            }
+        }
+    }
+}
+
+/// Add the text of every text node under `node` to `text`.
+fn collect_text(node: &Node, text: &mut String) {
+    for child in &node.children {
+        match child.node_type {
+            NodeType::Text(ref data) => text.push_str(data),
+            NodeType::Element(_) => collect_text(child, text),
+            NodeType::Comment(_) | NodeType::Doctype(_) => {}
+        }
     }
 }
 
@@ -103,9 +259,327 @@ impl Element {
 
     /// Return "Class" Set or empty Set
     pub fn classes(&self) -> HashSet<&str> {
-        match self.attributes.get("class") {
-            Some(class_list) => class_list.split(' ').collect(),
-            Node => HashSet::new(),
+        self.class_list().into_iter().collect()
+    }
+
+    /// Return the namespace of attribute `name`, or `None` if it isn't in one.
+    /// Only attributes of SVG and MathML elements can have a namespace.
+    pub fn attribute_namespace(&self, name: &str) -> Option<Namespace> {
+        if self.namespace == Namespace::Html {
+            return None;
+        }
+        if name.starts_with("xlink:") {
+            Some(Namespace::XLink)
+        } else if name.starts_with("xml:") {
+            Some(Namespace::Xml)
+        } else if name == "xmlns" || name.starts_with("xmlns:") {
+            Some(Namespace::Xmlns)
+        } else {
+            None
+        }
+    }
+}
+
+
+/*
+    DOM methods refuse requests that make no sense, like inserting a node into itself
+    or adding an empty class name, with a `DomError`, named after the DOM exception
+    for it.
+ */
+
+/// The reason a DOM method refused to do what it was asked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DomError {
+    /// The node would end up inside itself, or inside a node that can't have children.
+    HierarchyRequest,
+    /// The node the change was relative to isn't a child of the parent, or the
+    /// mutation observer doesn't belong to the document.
+    NotFound,
+    /// The arguments don't make sense, like observer options that don't ask for anything.
+    Type,
+    /// A name can't be used, like an empty class name.
+    Syntax,
+    /// A name has a character it can't have, like a class name with a space in it.
+    InvalidCharacter,
+}
+
+impl fmt::Display for DomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DomError::HierarchyRequest => write!(f, "the node can't be inserted there"),
+            DomError::NotFound => write!(f, "the node or observer was not found"),
+            DomError::Type => write!(f, "the arguments are not valid"),
+            DomError::Syntax => write!(f, "the name is not valid"),
+            DomError::InvalidCharacter => write!(f, "the name contains a character it can't have"),
         }
     }
 }
+
+impl Error for DomError {}
+
+
+/*
+    The class attribute is a list of class names separated by any ASCII whitespace,
+    like `classList` in the DOM. Changing the list writes the class attribute back
+    with the names separated by single spaces, and without duplicates. A name that
+    is empty or has whitespace in it could never be in the list, so the methods
+    that change it return an error for one.
+
+    e.g.
+        class="  card\tcard  active" => class_list() == ["card", "active"]
+        toggle_class("active")       => class="card"
+ */
+impl Element {
+    /// Return the class names in the class attribute, in order, without duplicates.
+    pub fn class_list(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        if let Some(class_list) = self.attributes.get("class") {
+            for name in class_list.split_ascii_whitespace() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Is `name` one of the class names in the class attribute?
+    pub fn has_class(&self, name: &str) -> bool {
+        self.class_list().contains(&name)
+    }
+
+    /// Add class `name` to the end of the class list, if it isn't there already.
+    pub fn add_class(&mut self, name: &str) -> Result<(), DomError> {
+        check_class_name(name)?;
+        let mut names = self.class_list();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+        let value = names.join(" ");
+        self.attributes.insert("class".to_string(), value);
+        Ok(())
+    }
+
+    /// Remove class `name` from the class list.
+    pub fn remove_class(&mut self, name: &str) -> Result<(), DomError> {
+        check_class_name(name)?;
+        if !self.attributes.contains_key("class") {
+            return Ok(());
+        }
+        let mut names = self.class_list();
+        names.retain(|existing| *existing != name);
+        let value = names.join(" ");
+        self.attributes.insert("class".to_string(), value);
+        Ok(())
+    }
+
+    /// Remove class `name` if it is in the class list, or add it if it isn't.
+    /// Returns true if the class is in the list afterwards.
+    pub fn toggle_class(&mut self, name: &str) -> Result<bool, DomError> {
+        if self.has_class(name) {
+            self.remove_class(name)?;
+            Ok(false)
+        } else {
+            self.add_class(name)?;
+            Ok(true)
+        }
+    }
+}
+
+/// Returns `DomError::Syntax` for an empty class name, and `DomError::InvalidCharacter`
+/// for one with whitespace in it.
+fn check_class_name(name: &str) -> Result<(), DomError> {
+    if name.is_empty() {
+        Err(DomError::Syntax)
+    } else if name.contains(|c: char| c.is_ascii_whitespace()) {
+        Err(DomError::InvalidCharacter)
+    } else {
+        Ok(())
+    }
+}
+
+
+/*
+    The dataset holds the `data-*` attributes of an element, with the "data-" taken
+    off and the rest turned into camel case, like `dataset` in the DOM.
+
+    e.g.
+        data-user-id="42" => dataset() == {"userId": "42"}
+        set_data("sortOrder", "asc") => data-sort-order="asc"
+ */
+impl Element {
+    /// Return the `data-*` attributes, keyed by their camel case names.
+    pub fn dataset(&self) -> BTreeMap<String, &str> {
+        self.attributes.iter()
+            .filter_map(|(name, value)| Some((data_key(name)?, value.as_str())))
+            .collect()
+    }
+
+    /// Return the value of the `data-*` attribute for the camel case `key`.
+    pub fn data(&self, key: &str) -> Option<&str> {
+        self.attributes.get(&data_attribute_name(key)?).map(String::as_str)
+    }
+
+    /// Set the `data-*` attribute for the camel case `key`. Returns `DomError::Syntax` if `key`
+    /// has a '-' followed by a lowercase letter, which no attribute name turns into.
+    pub fn set_data(&mut self, key: &str, value: &str) -> Result<(), DomError> {
+        let name = data_attribute_name(key).ok_or(DomError::Syntax)?;
+        self.attributes.insert(name, value.to_string());
+        Ok(())
+    }
+
+    /// Remove the `data-*` attribute for the camel case `key`, and return its old value.
+    pub fn remove_data(&mut self, key: &str) -> Option<String> {
+        self.attributes.remove(&data_attribute_name(key)?)
+    }
+}
+
+/// Turn an attribute name like "data-user-id" into a dataset key like "userId".
+fn data_key(attribute_name: &str) -> Option<String> {
+    let rest = attribute_name.strip_prefix("data-")?;
+    let mut key = String::with_capacity(rest.len());
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(&next) if c == '-' && next.is_ascii_lowercase() => {
+                key.push(next.to_ascii_uppercase());
+                chars.next();
+            }
+            _ => key.push(c),
+        }
+    }
+    Some(key)
+}
+
+/// Turn a dataset key like "userId" into an attribute name like "data-user-id".
+/// Returns `None` for a key that no attribute name turns into, like "user-id".
+fn data_attribute_name(key: &str) -> Option<String> {
+    let mut name = String::from("data-");
+    let mut chars = key.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '-' && chars.peek().is_some_and(char::is_ascii_lowercase) {
+            return None;
+        }
+        if c.is_ascii_uppercase() {
+            name.push('-');
+            name.push(c.to_ascii_lowercase());
+        } else {
+            name.push(c);
+        }
+    }
+    Some(name)
+}
+
+
+/*
+    Attribute values are always strings. These getters read them as other types,
+    following the HTML rules for each:
+        - An integer may have whitespace before it, and anything after its digits
+          is ignored.
+        - A boolean attribute is true if it is there at all, whatever its value,
+          so `disabled="false"` still means disabled.
+        - A URL is resolved against the URL of the document.
+
+    e.g.
+        tabindex=" 3rd"      => attribute_int("tabindex") == Some(3)
+        href="../style.css"  => attribute_url("href", "file:///site/blog/") == Some("file:///site/style.css")
+ */
+impl Element {
+    /// Return attribute `name` read as an integer, or `None` if it is missing or doesn't start with one.
+    pub fn attribute_int(&self, name: &str) -> Option<i64> {
+        let value = self.attributes.get(name)?.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        let end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+        let number: i64 = digits[..end].parse().ok()?;
+        Some(if negative { -number } else { number })
+    }
+
+    /// Return true if boolean attribute `name` is present.
+    pub fn attribute_bool(&self, name: &str) -> bool {
+        self.attributes.contains_key(name)
+    }
+
+    /// Return attribute `name` as an absolute URL, resolved against `base`.
+    pub fn attribute_url(&self, name: &str, base: &str) -> Option<String> {
+        let value = self.attributes.get(name)?.trim_matches(|c: char| c.is_ascii_whitespace());
+        Some(loader::resolve_url(base, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An HTML element with the attributes in `attributes`.
+    fn element_with(attributes: &[(&str, &str)]) -> Element {
+        let attributes = attributes.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Element { tag_name: "div".to_string(), namespace: Namespace::Html, attributes }
+    }
+
+    #[test]
+    fn edits_the_class_list() {
+        let mut element = element_with(&[("class", "  card\tcard  active")]);
+        assert_eq!(element.class_list(), ["card", "active"]);
+        assert!(element.has_class("card") && !element.has_class("car"));
+
+        assert_eq!(element.toggle_class("active"), Ok(false));
+        assert_eq!(element.attributes["class"], "card");
+        assert_eq!(element.toggle_class("new"), Ok(true));
+        element.add_class("card").unwrap();
+        assert_eq!(element.attributes["class"], "card new");
+        element.remove_class("card").unwrap();
+        assert_eq!(element.attributes["class"], "new");
+
+        let mut element = element_with(&[]);
+        element.remove_class("card").unwrap();
+        assert!(!element.attributes.contains_key("class"));
+    }
+
+    #[test]
+    fn refuses_bad_class_names() {
+        let mut element = element_with(&[("class", "a")]);
+        assert_eq!(element.add_class(""), Err(DomError::Syntax));
+        assert_eq!(element.remove_class("a b"), Err(DomError::InvalidCharacter));
+        assert_eq!(element.toggle_class("a\n"), Err(DomError::InvalidCharacter));
+        assert_eq!(element.attributes["class"], "a");
+    }
+
+    #[test]
+    fn edits_the_dataset() {
+        let mut element = element_with(&[("data-user-id", "42"), ("data-", "empty"), ("title", "t")]);
+        let dataset: Vec<(String, &str)> = element.dataset().into_iter().collect();
+        assert_eq!(dataset, [("".to_string(), "empty"), ("userId".to_string(), "42")]);
+        assert_eq!(element.data("userId"), Some("42"));
+
+        element.set_data("sortOrder", "asc").unwrap();
+        assert_eq!(element.attributes["data-sort-order"], "asc");
+        assert_eq!(element.remove_data("userId"), Some("42".to_string()));
+        assert_eq!(element.data("userId"), None);
+    }
+
+    #[test]
+    fn refuses_keys_no_attribute_turns_into() {
+        let mut element = element_with(&[("data-x", "1")]);
+        assert_eq!(element.set_data("-x", "2"), Err(DomError::Syntax));
+        assert_eq!(element.set_data("user-id", "2"), Err(DomError::Syntax));
+        assert_eq!(element.data("-x"), None);
+        assert_eq!(element.remove_data("-x"), None);
+        assert_eq!(element.attributes.len(), 1);
+        // A '-' that isn't followed by a lowercase letter is kept.
+        element.set_data("a-1", "3").unwrap();
+        assert_eq!(element.attributes["data-a-1"], "3");
+    }
+
+    #[test]
+    fn reads_attributes_as_other_types() {
+        let element = element_with(&[("tabindex", " 3rd"), ("width", "-12"), ("disabled", "false"), ("href", " ../style.css ")]);
+        assert_eq!(element.attribute_int("tabindex"), Some(3));
+        assert_eq!(element.attribute_int("width"), Some(-12));
+        assert_eq!(element.attribute_int("href"), None);
+        assert!(element.attribute_bool("disabled") && !element.attribute_bool("hidden"));
+        assert_eq!(element.attribute_url("href", "file:///site/blog/"), Some("file:///site/style.css".to_string()));
+    }
+}
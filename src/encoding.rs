@@ -0,0 +1,473 @@
+//! Character encodings: finding out how an HTML document is encoded, and decoding it.
+//!
+//! Only a few encodings are supported: UTF-8, UTF-16, windows-1252 and Shift_JIS.
+//! Decoding follows the Encoding Standard: https://encoding.spec.whatwg.org/
+
+use crate::jis0208::JIS0208;
+
+
+/// A character encoding supported by the decoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    ShiftJis,
+}
+
+impl Encoding {
+    /*
+        Documents name their encoding with a label, and most encodings have several.
+        Labels are matched ignoring case and surrounding whitespace.
+
+        e.g.
+            "latin1", "ISO-8859-1", "us-ascii" => Encoding::Windows1252
+     */
+    /// Find the encoding for a label, like "utf-8" or "Shift_JIS".
+    pub fn for_label(label: &str) -> Option<Encoding> {
+        let label = label.trim_matches(|c: char| c.is_ascii_whitespace()).to_ascii_lowercase();
+        match &*label {
+            "unicode-1-1-utf-8" | "unicode11utf8" | "unicode20utf8" | "utf-8" | "utf8"
+            | "x-unicode20utf8" => Some(Encoding::Utf8),
+            "csunicode" | "iso-10646-ucs-2" | "ucs-2" | "unicode" | "unicodefeff" | "utf-16"
+            | "utf-16le" => Some(Encoding::Utf16Le),
+            "unicodefffe" | "utf-16be" => Some(Encoding::Utf16Be),
+            "ansi_x3.4-1968" | "ascii" | "cp1252" | "cp819" | "csisolatin1" | "ibm819"
+            | "iso-8859-1" | "iso-ir-100" | "iso8859-1" | "iso88591" | "iso_8859-1"
+            | "iso_8859-1:1987" | "l1" | "latin1" | "us-ascii" | "windows-1252"
+            | "x-cp1252" => Some(Encoding::Windows1252),
+            "csshiftjis" | "ms932" | "ms_kanji" | "shift-jis" | "shift_jis" | "sjis"
+            | "windows-31j" | "x-sjis" => Some(Encoding::ShiftJis),
+            _ => None,
+        }
+    }
+
+    /// The standard name of the encoding.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Windows1252 => "windows-1252",
+            Encoding::ShiftJis => "Shift_JIS",
+        }
+    }
+}
+
+
+/*
+    Finding the encoding of a document is called "sniffing". The first match wins:
+        1. A byte order mark at the start of the input.
+        2. A `<meta charset>`, or `<meta http-equiv="Content-Type">`, in the first
+           1024 bytes.
+        3. The default chosen by the caller.
+ */
+/// Work out the encoding of an HTML document, using `default` if nothing says otherwise.
+pub fn sniff(bytes: &[u8], default: Encoding) -> Encoding {
+    if let Some((encoding, _)) = byte_order_mark(bytes) {
+        return encoding;
+    }
+    let start = &bytes[..bytes.len().min(1024)];
+    Prescanner { bytes: start, position: 0 }.find_meta_charset().unwrap_or(default)
+}
+
+/// Detect the encoding of an HTML document and decode it.
+/// Returns the text along with the encoding that was used.
+pub fn decode_document(bytes: &[u8], default: Encoding) -> (String, Encoding) {
+    let encoding = sniff(bytes, default);
+    (decode(bytes, encoding), encoding)
+}
+
+/// Return the encoding named by a byte order mark at the start of `bytes`, and its length.
+fn byte_order_mark(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some((Encoding::Utf8, 3))
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        Some((Encoding::Utf16Le, 2))
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some((Encoding::Utf16Be, 2))
+    } else {
+        None
+    }
+}
+
+
+/*
+    The prescan looks for `<meta>` tags without building a DOM. It skips comments
+    and the attributes of other tags, so that something like `<a title="<meta>">`
+    isn't mistaken for a meta tag.
+
+    e.g.
+        <meta charset="shift_jis">                                           => Shift_JIS
+        <meta http-equiv="Content-Type" content="text/html; charset=latin1"> => windows-1252
+ */
+struct Prescanner<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Prescanner<'a> {
+    /// Return the encoding named by the first `<meta>` tag that names a supported one.
+    fn find_meta_charset(&mut self) -> Option<Encoding> {
+        while self.position < self.bytes.len() {
+            if self.starts_with(b"<!--") {
+                self.skip_past(b"-->");
+            } else if self.starts_with(b"<meta") && self.byte_at(5).is_some_and(|b| b.is_ascii_whitespace() || b == b'/') {
+                self.position += 5;
+                if let Some(encoding) = self.meta_charset() {
+                    // A document that can be read as ASCII can't really be UTF-16.
+                    return Some(match encoding {
+                        Encoding::Utf16Le | Encoding::Utf16Be => Encoding::Utf8,
+                        encoding => encoding,
+                    });
+                }
+            } else if (self.starts_with(b"<") && self.byte_at(1).is_some_and(|b| b.is_ascii_alphabetic()))
+                || (self.starts_with(b"</") && self.byte_at(2).is_some_and(|b| b.is_ascii_alphabetic()))
+            {
+                // Skip the tag name, then any attributes.
+                while self.byte_at(0).is_some_and(|b| !b.is_ascii_whitespace() && b != b'>') {
+                    self.position += 1;
+                }
+                while self.attribute().is_some() {}
+            } else if self.starts_with(b"<!") || self.starts_with(b"</") || self.starts_with(b"<?") {
+                self.skip_past(b">");
+            } else {
+                self.position += 1;
+            }
+        }
+        None
+    }
+
+    /// Read the attributes of a `<meta>` tag, and return the encoding it names, if any.
+    fn meta_charset(&mut self) -> Option<Encoding> {
+        let mut charset = None;
+        let mut content = None;
+        let mut is_content_type = false;
+        while let Some((name, value)) = self.attribute() {
+            match &*name {
+                "charset" if charset.is_none() => charset = Some(value),
+                "content" if content.is_none() => content = Some(value),
+                "http-equiv" => is_content_type |= value.eq_ignore_ascii_case("content-type"),
+                _ => {}
+            }
+        }
+        match (charset, content) {
+            (Some(charset), _) => Encoding::for_label(&charset),
+            (None, Some(content)) if is_content_type => {
+                charset_from_content(&content).and_then(|label| Encoding::for_label(&label))
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the next attribute of a tag, returning its lower-case name and its value.
+    /// Returns `None` at the end of the tag.
+    fn attribute(&mut self) -> Option<(String, String)> {
+        while self.byte_at(0).is_some_and(|b| b.is_ascii_whitespace() || b == b'/') {
+            self.position += 1;
+        }
+        if self.byte_at(0).is_none_or(|b| b == b'>') {
+            return None;
+        }
+
+        let name = self.take_while(|b| b != b'=' && b != b'/' && b != b'>' && !b.is_ascii_whitespace());
+        self.take_while(|b| b.is_ascii_whitespace());
+        if self.byte_at(0) != Some(b'=') {
+            return Some((name, String::new()));
+        }
+        self.position += 1;
+        self.take_while(|b| b.is_ascii_whitespace());
+
+        let value = match self.byte_at(0) {
+            Some(quote @ (b'"' | b'\'')) => {
+                self.position += 1;
+                let value = self.take_while(|b| b != quote);
+                self.position = (self.position + 1).min(self.bytes.len());
+                value
+            }
+            _ => self.take_while(|b| b != b'>' && !b.is_ascii_whitespace()),
+        };
+        Some((name, value))
+    }
+
+    fn byte_at(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(self.position + offset).copied()
+    }
+
+    /// Does the input continue with `prefix`? Letters are matched ignoring case.
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.bytes[self.position..].len() >= prefix.len()
+            && self.bytes[self.position..][..prefix.len()].eq_ignore_ascii_case(prefix)
+    }
+
+    /// Move to just after the next `end`, or to the end of the input.
+    fn skip_past(&mut self, end: &[u8]) {
+        while self.position < self.bytes.len() && !self.starts_with(end) {
+            self.position += 1;
+        }
+        self.position = (self.position + end.len()).min(self.bytes.len());
+    }
+
+    /// Consume bytes while `test` is true, and return them as lower-case text.
+    fn take_while(&mut self, test: impl Fn(u8) -> bool) -> String {
+        let start = self.position;
+        while self.byte_at(0).is_some_and(&test) {
+            self.position += 1;
+        }
+        String::from_utf8_lossy(&self.bytes[start..self.position]).to_ascii_lowercase()
+    }
+}
+
+/// Find the label after "charset=" in the `content` of a `<meta http-equiv>`.
+/// e.g. "text/html; charset=utf-8" => "utf-8"
+fn charset_from_content(content: &str) -> Option<String> {
+    let mut rest = content;
+    loop {
+        let start = rest.to_ascii_lowercase().find("charset")?;
+        rest = rest[start + "charset".len()..].trim_start();
+        if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+            return match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let value = &value[1..];
+                    value.find(quote).map(|end| value[..end].to_string())
+                }
+                _ => {
+                    let end = value.find(|c: char| c.is_ascii_whitespace() || c == ';').unwrap_or(value.len());
+                    Some(value[..end].to_string()).filter(|value| !value.is_empty())
+                }
+            };
+        }
+    }
+}
+
+
+/*
+    Decoding never fails. Bytes that don't form a valid character in the encoding
+    are replaced with U+FFFD, the replacement character, like a browser would.
+ */
+/// Decode `bytes` as text in `encoding`. A byte order mark for that encoding is dropped.
+pub fn decode(bytes: &[u8], encoding: Encoding) -> String {
+    let bytes = match byte_order_mark(bytes) {
+        Some((bom_encoding, length)) if bom_encoding == encoding => &bytes[length..],
+        _ => bytes,
+    };
+    match encoding {
+        Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        Encoding::Utf16Le => decode_utf16(bytes, u16::from_le_bytes),
+        Encoding::Utf16Be => decode_utf16(bytes, u16::from_be_bytes),
+        Encoding::Windows1252 => bytes.iter().map(|&b| windows_1252_char(b)).collect(),
+        Encoding::ShiftJis => decode_shift_jis(bytes),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks_exact(2).map(|pair| to_unit([pair[0], pair[1]]));
+    let mut text: String = char::decode_utf16(units).map(|c| c.unwrap_or('\u{FFFD}')).collect();
+    if bytes.len() % 2 == 1 {
+        // Half a code unit at the end.
+        text.push('\u{FFFD}');
+    }
+    text
+}
+
+
+/// windows-1252 differs from Latin-1 only for bytes 0x80 to 0x9F.
+/// Bytes that windows-1252 leaves undefined map to the C1 control with the same number.
+pub(crate) const WINDOWS_1252_C1: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+fn windows_1252_char(byte: u8) -> char {
+    match byte {
+        0x80..=0x9F => WINDOWS_1252_C1[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+
+/*
+    Shift_JIS mixes one- and two-byte characters:
+        0x00 to 0x80    ASCII (and U+0080)
+        0xA1 to 0xDF    half-width katakana
+        0x81 to 0x9F,   the first byte of a two-byte character, which is looked
+        0xE0 to 0xFC    up in the JIS X 0208 index
+    The two bytes are turned into a "pointer" into the index:
+        pointer = (lead - lead offset) * 188 + (trail - trail offset)
+    where the offsets skip over the byte values that can't be used.
+
+    e.g.
+        [0x82, 0xA0] => pointer 283 => 'あ'
+ */
+fn decode_shift_jis(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let lead = bytes[index];
+        index += 1;
+        match lead {
+            0x00..=0x80 => text.push(lead as char),
+            0xA1..=0xDF => text.push(char::from_u32(0xFF61 + (lead - 0xA1) as u32).unwrap()),
+            0x81..=0x9F | 0xE0..=0xFC => {
+                let trail = match bytes.get(index) {
+                    Some(&trail) => trail,
+                    None => {
+                        text.push('\u{FFFD}');
+                        break;
+                    }
+                };
+                match shift_jis_pointer(lead, trail).and_then(jis0208_char) {
+                    Some(c) => {
+                        text.push(c);
+                        index += 1;
+                    }
+                    None => {
+                        text.push('\u{FFFD}');
+                        // An ASCII byte after a bad lead byte is read again on its own.
+                        if !trail.is_ascii() {
+                            index += 1;
+                        }
+                    }
+                }
+            }
+            _ => text.push('\u{FFFD}'),
+        }
+    }
+    text
+}
+
+/// The index pointer for a two-byte Shift_JIS character, or `None` if `trail` can't follow a lead byte.
+fn shift_jis_pointer(lead: u8, trail: u8) -> Option<usize> {
+    let lead_offset = if lead < 0xA0 { 0x81 } else { 0xC1 };
+    let trail_offset = match trail {
+        0x40..=0x7E => 0x40,
+        0x80..=0xFC => 0x41,
+        _ => return None,
+    };
+    Some((lead - lead_offset) as usize * 188 + (trail - trail_offset) as usize)
+}
+
+fn jis0208_char(pointer: usize) -> Option<char> {
+    match pointer {
+        // The user-defined area.
+        8836..=10715 => char::from_u32(0xE000 + (pointer - 8836) as u32),
+        _ => match JIS0208.get(pointer) {
+            Some(&0) | None => None,
+            Some(&code) => char::from_u32(code as u32),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta_charset(source: &str) -> Option<Encoding> {
+        Prescanner { bytes: source.as_bytes(), position: 0 }.find_meta_charset()
+    }
+
+    #[test]
+    fn finds_encodings_by_label() {
+        assert_eq!(Encoding::for_label(" UTF-8\n"), Some(Encoding::Utf8));
+        assert_eq!(Encoding::for_label("Latin1"), Some(Encoding::Windows1252));
+        assert_eq!(Encoding::for_label("sjis"), Some(Encoding::ShiftJis));
+        assert_eq!(Encoding::for_label("utf-16"), Some(Encoding::Utf16Le));
+        assert_eq!(Encoding::for_label("koi8-r"), None);
+        assert_eq!(Encoding::ShiftJis.name(), "Shift_JIS");
+    }
+
+    #[test]
+    fn byte_order_mark_beats_meta_charset() {
+        let meta = b"<meta charset=shift_jis>";
+        assert_eq!(sniff(&[b"\xEF\xBB\xBF".as_slice(), meta].concat(), Encoding::Windows1252), Encoding::Utf8);
+        assert_eq!(sniff(b"\xFF\xFEa\0", Encoding::Windows1252), Encoding::Utf16Le);
+        assert_eq!(sniff(b"\xFE\xFF\0a", Encoding::Windows1252), Encoding::Utf16Be);
+        assert_eq!(sniff(meta, Encoding::Windows1252), Encoding::ShiftJis);
+        assert_eq!(sniff(b"<p>caf\xE9", Encoding::Windows1252), Encoding::Windows1252);
+
+        // The byte order mark isn't part of the text.
+        assert_eq!(decode_document(b"\xEF\xBB\xBFa", Encoding::Windows1252), ("a".to_string(), Encoding::Utf8));
+        assert_eq!(decode_document(b"\xFE\xFF\0a\0b", Encoding::Utf8), ("ab".to_string(), Encoding::Utf16Be));
+    }
+
+    #[test]
+    fn prescan_skips_comments_and_other_tags() {
+        assert_eq!(meta_charset("<!-- <meta charset=shift_jis> --><meta charset=\"utf-8\">"), Some(Encoding::Utf8));
+        assert_eq!(meta_charset("<a title=\"<meta charset=shift_jis>\"><meta charset=latin1>"), Some(Encoding::Windows1252));
+        assert_eq!(meta_charset("<metadata charset=shift_jis>"), None);
+        assert_eq!(meta_charset("<meta charset=bogus><meta charset=sjis>"), Some(Encoding::ShiftJis));
+        assert_eq!(meta_charset("<META CHARSET='SHIFT_JIS'>"), Some(Encoding::ShiftJis));
+        // A document that names UTF-16 in ASCII is really UTF-8.
+        assert_eq!(meta_charset("<meta charset=utf-16>"), Some(Encoding::Utf8));
+    }
+
+    #[test]
+    fn prescan_only_reads_the_first_1024_bytes() {
+        let source = format!("{}<meta charset=shift_jis>", " ".repeat(1024));
+        assert_eq!(sniff(source.as_bytes(), Encoding::Utf8), Encoding::Utf8);
+    }
+
+    #[test]
+    fn reads_the_charset_from_http_equiv_content() {
+        assert_eq!(meta_charset("<meta http-equiv=\"Content-Type\" content=\"text/html; charset=latin1\">"),
+                   Some(Encoding::Windows1252));
+        assert_eq!(meta_charset("<meta content='text/html;charset=\"sjis\"' http-equiv=content-type>"),
+                   Some(Encoding::ShiftJis));
+        // Without http-equiv, the content is ignored.
+        assert_eq!(meta_charset("<meta content=\"text/html; charset=sjis\">"), None);
+
+        assert_eq!(charset_from_content("text/html; charset = utf-8; x=y"), Some("utf-8".to_string()));
+        assert_eq!(charset_from_content("charsetcharset=sjis"), Some("sjis".to_string()));
+        assert_eq!(charset_from_content("charset='utf-8"), None);
+        assert_eq!(charset_from_content("charset="), None);
+        assert_eq!(charset_from_content("text/html"), None);
+    }
+
+    #[test]
+    fn replaces_broken_utf16_with_replacement_characters() {
+        // A high surrogate at the end, with no low surrogate after it.
+        assert_eq!(decode(b"a\0\x3D\xD8", Encoding::Utf16Le), "a\u{FFFD}");
+        // A low surrogate on its own.
+        assert_eq!(decode(b"\xDC\x00\0b", Encoding::Utf16Be), "\u{FFFD}b");
+        // Half a code unit.
+        assert_eq!(decode(b"a\0b", Encoding::Utf16Le), "a\u{FFFD}");
+        // A whole surrogate pair.
+        assert_eq!(decode(b"\x3D\xD8\x00\xDE", Encoding::Utf16Le), "\u{1F600}");
+    }
+
+    #[test]
+    fn decodes_the_windows_1252_c1_row() {
+        let bytes: Vec<u8> = (0x80..=0x9F).collect();
+        let text: Vec<char> = decode(&bytes, Encoding::Windows1252).chars().collect();
+        assert_eq!(text, WINDOWS_1252_C1);
+        assert_eq!(text[0], '\u{20AC}');
+        assert_eq!(text[1], '\u{81}');
+        assert_eq!(text[0x1F], '\u{178}');
+        assert_eq!(decode(b"caf\xE9 \xA0", Encoding::Windows1252), "caf\u{E9} \u{A0}");
+    }
+
+    #[test]
+    fn decodes_shift_jis() {
+        assert_eq!(decode(b"a\x82\xA0", Encoding::ShiftJis), "a\u{3042}");
+        // Half-width katakana are a single byte.
+        assert_eq!(decode(b"\xB1\xDF", Encoding::ShiftJis), "\u{FF71}\u{FF9F}");
+        // The user-defined area maps to the Private Use Area.
+        assert_eq!(decode(b"\xF0\x40", Encoding::ShiftJis), "\u{E000}");
+    }
+
+    #[test]
+    fn replaces_broken_shift_jis_with_replacement_characters() {
+        // A lead byte at the end of the input.
+        assert_eq!(decode(b"a\x82", Encoding::ShiftJis), "a\u{FFFD}");
+        // An ASCII byte after a lead byte is read again on its own.
+        assert_eq!(decode(b"\x82 b", Encoding::ShiftJis), "\u{FFFD} b");
+        // A pair that doesn't map to anything is replaced as a whole, unless the second byte is ASCII.
+        assert_eq!(decode(b"\x85\x80b", Encoding::ShiftJis), "\u{FFFD}b");
+        assert_eq!(decode(b"\x85\x40b", Encoding::ShiftJis), "\u{FFFD}@b");
+        assert_eq!(decode(b"\xA0\xFD", Encoding::ShiftJis), "\u{FFFD}\u{FFFD}");
+    }
+}
@@ -0,0 +1,94 @@
+//! Errors reported by the HTML, CSS and JSON parsers.
+
+use std::fmt;
+
+/*
+    Neither parser gives up on bad input. The HTML parser fixes up broken markup the
+    way browsers do, and the CSS parser skips anything it doesn't understand. Each
+    problem it ran into is still reported as a `ParseError`, so that callers can
+    warn about it or refuse the input.
+
+    e.g.
+        ParseError {
+            line: 3,
+            column: 14,
+            expected: "';' or '}'",
+            found: "'!'",
+        }
+ */
+
+/// A problem found while parsing, with its location in the source.
+/// Lines and columns are counted from 1, and columns count characters, not bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+/// The result of a successful parse, along with the errors that were recovered from.
+pub struct Parsed<T> {
+    pub value: T,
+    pub warnings: Vec<ParseError>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: expected {} but found {}", self.line, self.column, self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+
+/*
+    While parsing, errors only record a byte position. Working out line and column
+    numbers means scanning the source from the start, so that is done once for all
+    errors at the end of parsing.
+ */
+
+/// An error whose line and column haven't been worked out yet.
+pub(crate) struct PendingError {
+    pub position: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+impl PendingError {
+    pub fn new(position: usize, expected: impl Into<String>, found: impl Into<String>) -> PendingError {
+        PendingError { position, expected: expected.into(), found: found.into() }
+    }
+}
+
+/// Turn pending errors found in `source` into `ParseError`s, in source order.
+pub(crate) fn locate(source: &str, mut errors: Vec<PendingError>) -> Vec<ParseError> {
+    errors.sort_by_key(|error| error.position);
+
+    let mut line = 1;
+    let mut column = 1;
+    let mut chars = source.char_indices().peekable();
+    errors.into_iter().map(|error| {
+        while let Some(&(index, c)) = chars.peek() {
+            if index >= error.position {
+                break;
+            }
+            chars.next();
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        ParseError { line, column, expected: error.expected, found: error.found }
+    }).collect()
+}
+
+/// Describe the character at the start of `rest` for an error message.
+pub(crate) fn describe_next(rest: &str) -> String {
+    match rest.chars().next() {
+        Some(c) => format!("{:?}", c),
+        None => "end of input".to_string(),
+    }
+}
@@ -0,0 +1,364 @@
+//! DOM events: listeners on nodes, and dispatching events through the tree.
+//!
+//! Works like events in a browser, minus anything a user would do: events are only
+//! ever made and dispatched by the program, e.g. to simulate a click in a test.
+
+use crate::document::{Document, NodeId};
+use std::rc::Rc;
+
+
+/*
+    An event has a type, like "click", and flags that say how it travels through the
+    tree. The rest of its state is filled in while it is being dispatched.
+
+    e.g.
+        Event::new("click", true, true) is the same as Event::click()
+ */
+/// Something that happened to a node, passed to the listeners of the nodes on its path.
+pub struct Event {
+    event_type: String,
+    bubbles: bool,
+    cancelable: bool,
+    target: Option<NodeId>,
+    current_target: Option<NodeId>,
+    phase: EventPhase,
+    propagation_stopped: bool,
+    immediate_propagation_stopped: bool,
+    default_prevented: bool,
+}
+
+/// Which part of its path an event is going through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventPhase {
+    /// The event isn't being dispatched.
+    None,
+    /// Going down from the root towards the target.
+    Capturing,
+    AtTarget,
+    /// Going back up from the target towards the root.
+    Bubbling,
+}
+
+impl Event {
+    /// A new event of type `event_type`.
+    pub fn new(event_type: &str, bubbles: bool, cancelable: bool) -> Event {
+        Event {
+            event_type: event_type.to_string(),
+            bubbles,
+            cancelable,
+            target: None,
+            current_target: None,
+            phase: EventPhase::None,
+            propagation_stopped: false,
+            immediate_propagation_stopped: false,
+            default_prevented: false,
+        }
+    }
+
+    /// A "click" event, which bubbles and can be canceled.
+    pub fn click() -> Event {
+        Event::new("click", true, true)
+    }
+
+    /// An "input" event, which bubbles but can't be canceled.
+    pub fn input() -> Event {
+        Event::new("input", true, false)
+    }
+
+    /// A "load" event, which doesn't bubble and can't be canceled.
+    pub fn load() -> Event {
+        Event::new("load", false, false)
+    }
+
+    /// The type of event, like "click".
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// Whether the event goes back up to the root after reaching its target.
+    pub fn bubbles(&self) -> bool {
+        self.bubbles
+    }
+
+    /// Whether `prevent_default` does anything.
+    pub fn cancelable(&self) -> bool {
+        self.cancelable
+    }
+
+    /// The node the event was dispatched to.
+    pub fn target(&self) -> Option<NodeId> {
+        self.target
+    }
+
+    /// The node whose listeners are being called.
+    pub fn current_target(&self) -> Option<NodeId> {
+        self.current_target
+    }
+
+    /// Which part of its path the event is going through.
+    pub fn phase(&self) -> EventPhase {
+        self.phase
+    }
+
+    /// Don't pass the event on to any more nodes, after the listeners of this one.
+    pub fn stop_propagation(&mut self) {
+        self.propagation_stopped = true;
+    }
+
+    /// Don't call any more listeners, not even the rest of this node's.
+    pub fn stop_immediate_propagation(&mut self) {
+        self.propagation_stopped = true;
+        self.immediate_propagation_stopped = true;
+    }
+
+    /// Ask for the event's default action not to happen. Does nothing if the event can't be canceled.
+    pub fn prevent_default(&mut self) {
+        if self.cancelable {
+            self.default_prevented = true;
+        }
+    }
+
+    /// Whether a listener has called `prevent_default`.
+    pub fn default_prevented(&self) -> bool {
+        self.default_prevented
+    }
+}
+
+
+/*
+    A listener is a function that is called with the document and the event, so it
+    can change the document in response. Rust closures can't be compared, so instead
+    of passing the same function again to remove a listener, `add_event_listener`
+    returns an id to pass to `remove_event_listener`.
+
+    e.g.
+        let id = document.add_event_listener(button, "click", false, |document, event| {
+            document.set_attribute(event.target().unwrap(), "class", "pressed");
+        });
+ */
+/// Identifies a listener added with `Document::add_event_listener`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ListenerId(usize);
+
+type Callback = Rc<dyn Fn(&mut Document, &mut Event)>;
+
+struct Listener {
+    id: ListenerId,
+    node: NodeId,
+    event_type: String,
+    capture: bool,
+    callback: Callback,
+}
+
+/// The event listeners of every node in a document.
+#[derive(Default)]
+pub(crate) struct Listeners {
+    listeners: Vec<Listener>,
+    next_id: usize,
+}
+
+
+impl Document {
+    /// Call `callback` whenever an event of type `event_type` passes through node `id`.
+    /// A capturing listener is called on the way down to the target, and any other
+    /// listener on the way back up.
+    pub fn add_event_listener<F>(&mut self, id: NodeId, event_type: &str, capture: bool, callback: F) -> ListenerId
+    where
+        F: Fn(&mut Document, &mut Event) + 'static,
+    {
+        let listeners = &mut self.listeners;
+        let listener_id = ListenerId(listeners.next_id);
+        listeners.next_id += 1;
+        listeners.listeners.push(Listener {
+            id: listener_id,
+            node: id,
+            event_type: event_type.to_string(),
+            capture,
+            callback: Rc::new(callback),
+        });
+        listener_id
+    }
+
+    /// Remove a listener, so that it isn't called any more. Returns false if it was already removed.
+    pub fn remove_event_listener(&mut self, listener: ListenerId) -> bool {
+        let listeners = &mut self.listeners.listeners;
+        let count = listeners.len();
+        listeners.retain(|existing| existing.id != listener);
+        listeners.len() != count
+    }
+
+    /*
+        Dispatching an event calls listeners along the path from the root to the target,
+        which is fixed before any listener runs:
+            1. Capture: the capturing listeners of each ancestor, from the root down.
+            2. Target: the listeners of the target, capturing ones first.
+            3. Bubble: the other listeners of each ancestor, from the parent up, but
+               only if the event bubbles.
+        Listeners that are removed while the event is on its way aren't called, and
+        listeners that are added aren't called until the next event.
+
+        e.g.
+            <div><button>OK</button></div>, click on the button:
+                div (capture), button, div (bubble)
+     */
+    /// Send `event` to node `target`. Returns false if a listener called `prevent_default`.
+    /// An event can be dispatched again; each dispatch starts with its flags cleared.
+    pub fn dispatch_event(&mut self, target: NodeId, event: &mut Event) -> bool {
+        event.target = Some(target);
+        event.default_prevented = false;
+        event.propagation_stopped = false;
+        event.immediate_propagation_stopped = false;
+
+        let mut path: Vec<NodeId> = self.ancestors(target).collect();
+        path.reverse();
+
+        event.phase = EventPhase::Capturing;
+        for &node in &path {
+            self.invoke_listeners(node, event, true);
+        }
+        event.phase = EventPhase::AtTarget;
+        self.invoke_listeners(target, event, true);
+        self.invoke_listeners(target, event, false);
+        if event.bubbles {
+            event.phase = EventPhase::Bubbling;
+            for &node in path.iter().rev() {
+                self.invoke_listeners(node, event, false);
+            }
+        }
+
+        event.phase = EventPhase::None;
+        event.current_target = None;
+        !event.default_prevented
+    }
+
+    /// Call the listeners of `node` for `event`, either the capturing ones or the others.
+    fn invoke_listeners(&mut self, node: NodeId, event: &mut Event, capture: bool) {
+        if event.propagation_stopped {
+            return;
+        }
+        event.current_target = Some(node);
+        let matching: Vec<(ListenerId, Callback)> = self.listeners.listeners.iter()
+            .filter(|listener| {
+                listener.node == node
+                    && listener.event_type == event.event_type
+                    && listener.capture == capture
+            })
+            .map(|listener| (listener.id, listener.callback.clone()))
+            .collect();
+        for (id, callback) in matching {
+            if event.immediate_propagation_stopped {
+                return;
+            }
+            // An earlier listener may have removed this one.
+            if !self.listeners.listeners.iter().any(|listener| listener.id == id) {
+                continue;
+            }
+            callback(self, event);
+        }
+    }
+
+    /// Simulate a click on node `id`. Returns false if a listener called `prevent_default`.
+    pub fn click(&mut self, id: NodeId) -> bool {
+        self.dispatch_event(id, &mut Event::click())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::list;
+    use std::cell::RefCell;
+
+    /// Add a "click" listener that writes `name` and the event's phase to `log`.
+    fn log_clicks(document: &mut Document, id: NodeId, capture: bool, name: &'static str,
+                  log: &Rc<RefCell<Vec<String>>>) -> ListenerId {
+        let log = log.clone();
+        document.add_event_listener(id, "click", capture, move |_, event| {
+            log.borrow_mut().push(format!("{} {:?}", name, event.phase()));
+        })
+    }
+
+    #[test]
+    fn goes_down_to_the_target_and_back_up() {
+        let (mut document, list, item, _) = list();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        log_clicks(&mut document, list, false, "list", &log);
+        log_clicks(&mut document, item, false, "item", &log);
+        log_clicks(&mut document, item, true, "item capture", &log);
+        log_clicks(&mut document, list, true, "list capture", &log);
+
+        assert!(document.click(item));
+        assert_eq!(*log.borrow(), ["list capture Capturing", "item capture AtTarget",
+                                   "item AtTarget", "list Bubbling"]);
+
+        log.borrow_mut().clear();
+        let mut event = Event::new("click", false, false);
+        document.dispatch_event(item, &mut event);
+        assert_eq!(*log.borrow(), ["list capture Capturing", "item capture AtTarget", "item AtTarget"]);
+        assert_eq!(event.target(), Some(item));
+        assert_eq!(event.current_target(), None);
+        assert_eq!(event.phase(), EventPhase::None);
+    }
+
+    #[test]
+    fn stops_propagation() {
+        let (mut document, list, item, _) = list();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        document.add_event_listener(item, "click", false, |_, event| event.stop_propagation());
+        log_clicks(&mut document, item, false, "item", &log);
+        log_clicks(&mut document, list, false, "list", &log);
+        document.click(item);
+        assert_eq!(*log.borrow(), ["item AtTarget"]);
+    }
+
+    #[test]
+    fn stops_immediate_propagation() {
+        let (mut document, _, item, _) = list();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        document.add_event_listener(item, "click", false, |_, event| event.stop_immediate_propagation());
+        log_clicks(&mut document, item, false, "item", &log);
+        document.click(item);
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn prevents_the_default_only_if_cancelable() {
+        let (mut document, list, item, _) = list();
+        document.add_event_listener(list, "click", false, |_, event| event.prevent_default());
+        document.add_event_listener(list, "input", false, |_, event| event.prevent_default());
+        assert!(!document.click(item));
+
+        let mut event = Event::input();
+        assert!(document.dispatch_event(item, &mut event));
+        assert!(!event.default_prevented());
+        assert_eq!(event.event_type(), "input");
+        assert!(event.bubbles() && !event.cancelable());
+    }
+
+    #[test]
+    fn clears_default_prevented_when_dispatched_again() {
+        let (mut document, list, item, _) = list();
+        let listener = document.add_event_listener(list, "click", false, |_, event| event.prevent_default());
+        let mut event = Event::click();
+        assert!(!document.dispatch_event(item, &mut event));
+        assert!(event.default_prevented());
+
+        document.remove_event_listener(listener);
+        assert!(document.dispatch_event(item, &mut event));
+        assert!(!event.default_prevented());
+    }
+
+    #[test]
+    fn removes_listeners() {
+        let (mut document, list, item, _) = list();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let listener = log_clicks(&mut document, list, false, "list", &log);
+        // A listener removed by an earlier one isn't called for the same event.
+        document.add_event_listener(item, "click", false, move |document, _| {
+            document.remove_event_listener(listener);
+        });
+        document.click(item);
+        assert!(log.borrow().is_empty());
+        assert!(!document.remove_event_listener(listener));
+    }
+}
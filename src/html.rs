@@ -1,15 +1,17 @@
-//! A simple parser for a tiny subset of HTML.
+//! A parser for a subset of HTML.
 //!
-//! Can parse basic opening and closing tags, and text nodes.
+//! Input is split into tokens by a tokenizer state machine, and the tokens are
+//! assembled into a DOM tree by a tree builder that recovers from bad markup.
+//! Each recovery is recorded as a parse error, returned by `try_parse`.
 //!
-//! Not yes supported:
-//!     - Comments
-//!     - Doctypes and processing instructions
-//!     - Self-closing tags
-//!     - Non-well-formed markup
-//!     - Character entities
+//! Inline `<svg>` and `<math>` elements are parsed as foreign content, in their own
+//! namespaces, with XML-like rules for tag names, self-closing tags and CDATA.
 
 use crate::dom;
+use crate::encoding::{self, Encoding};
+use crate::entities;
+use crate::error::{self, describe_next, ParseError, Parsed, PendingError};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
 /*
@@ -32,243 +34,2656 @@ use std::collections::HashMap;
 
     The following syntax is allowed:
         1. Balanced tags: <p>...</p>
-        2. Attributes with quoted values: id="main"
+        2. Attributes with quoted, unquoted or no values: id="main" tabindex=3 disabled
         3. Text nodes: <em>world</em>
+        4. Unbalanced or improperly nested tags, which are fixed up the way browsers do
+        5. Comments: <!-- ... -->
+        6. Doctype declarations: <!DOCTYPE html>
+        7. Processing instructions, which are read as comments: <?xml version="1.0"?>
+        8. Void elements, with or without a self-closing slash: <br> or <br />
+        9. Character references in text and attribute values: &amp; &#x27; &nbsp;
+       10. Raw text in <script>, <style>, <textarea> and <title>, where "<" is not markup
+       11. Documents in other encodings, detected from a byte order mark or <meta charset>
+           by `parse_bytes`
+
+       12. Inline SVG and MathML, with their elements in their own namespaces: <svg><rect/></svg>
+       13. CDATA sections inside SVG and MathML: <![CDATA[x < y]]>
 
     Everything else is unsupported, include
-        1. Comments
-        2. Doctype declarations
-        3. Escaped characters (like &amp;) and CDATA sections
-        4. Self-closing tags: <br /> or <br> with no closing tag
-        5. Error handling (e.g. unbalanced or improperly nested tags)
-        6. Namespaces and other XHTML syntax: <html:body>
-        7. Character encoding detection
-
-    The HTML parser structure is based loosely on the tokenizer module from
-    Servo's CSS-Parser(https://github.com/servo/rust-cssparser) library.
-    It has no real error handling; in most cases, it just aborts when faced
-    with unexpected syntax.
+        1. XHTML syntax, like namespace prefixes on tag names: <html:body>
+
+    Parsing happens in two stages, just like in the [HTML standard](https://html.spec.whatwg.org/multipage/parsing.html):
+        1. The tokenizer reads characters and produces tokens: start tags, end tags, text,
+           comments and doctypes.
+        2. The tree builder consumes those tokens and inserts nodes into the DOM tree.
+           It keeps a stack of open elements, so it can tell which end tags are implied
+           (a new <p> closes the previous one) and which ones are stray and can be ignored.
  */
 
 
 /*
-    The parser stores its input string and a current position within the string.
+    The tokenizer stores its input string and a current position within the string.
     The position is the index of the next character we haven't processed yet.
  */
 
-/// HTML Parser struct
+/// HTML Tokenizer struct
 /*
     e.g.
-        Parser {
+        Tokenizer {
             input: Input String,
-            position: Position of Input String
+            position: Position of Input String,
+            state: State::Data,
+            last_start_tag: "div",
+            errors: [],
+            cdata_allowed: false,
+            ran_out: false,
         }
  */
-struct Parser {
+struct Tokenizer {
     input: String,
     position: usize, // "usize" is an unsigned integer, similar to "size_t" in C
+    state: State,
+    last_start_tag: String,
+    errors: Vec<PendingError>,
+    // Set by the tree builder inside SVG and MathML, the only places CDATA sections are allowed.
+    cdata_allowed: bool,
+    // Set when the input ends in the middle of a token, which more input might complete.
+    ran_out: bool,
 }
 
 
 /*
-    We can use this to implement some simple methods for peeking at the next
-    characters in the input.
+    The tokenizer is a state machine. Each state knows how to handle the next character,
+    and most characters either add to the token being built or move to another state.
+    The names follow the states in the [tokenization](https://html.spec.whatwg.org/multipage/parsing.html#tokenization)
+    section of the HTML standard.
  */
 
-impl Parser {
-    /// Return true if all input is consumed.
-    fn eof(&self) -> bool {
-        self.position >= self.input.len()
-    }
+/// Tokenizer states
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    RawText,
+    RcData,
+    PlainText,
+}
+
+
+/// A token produced by the tokenizer.
+/*
+    e.g.
+        Token::StartTag { name: "div", attributes: { "id": "main" }, self_closing: false }
+        Token::EndTag { name: "div" }
+        Token::Text("Hello ")
+        Token::Comment(" note ")
+        Token::Doctype(Doctype { name: "html", public_id: "", system_id: "" })
+ */
+enum Token {
+    StartTag { name: String, attributes: dom::AttributeMap, self_closing: bool },
+    EndTag { name: String },
+    Text(String),
+    Comment(String),
+    Doctype(dom::Doctype),
+    Eof,
+}
 
-    /// Read the current character without consuming it.
-    fn next_char(&self) -> char {
-        self.input[self.position..].chars().next().unwrap()
+
+/// A start or end tag that the tokenizer is in the middle of reading.
+struct Tag {
+    is_end: bool,
+    self_closing: bool,
+    name: String,
+    attributes: dom::AttributeMap,
+    duplicate_attributes: Vec<String>,
+    attribute_name: String,
+    attribute_value: String,
+}
+
+impl Tag {
+    fn new(is_end: bool) -> Tag {
+        Tag {
+            is_end,
+            self_closing: false,
+            name: String::new(),
+            attributes: HashMap::new(),
+            duplicate_attributes: Vec::new(),
+            attribute_name: String::new(),
+            attribute_value: String::new(),
+        }
     }
 
-    /// Do the next characters start with the given string?
-    fn starts_with(&self, s: &str) -> bool {
-        self.input[self.position..].starts_with(s)
+    /// Add the attribute that has just been read to the attribute map.
+    /// If the tag already has an attribute with this name, the first one wins.
+    fn finish_attribute(&mut self) {
+        if !self.attribute_name.is_empty() {
+            let name = std::mem::take(&mut self.attribute_name);
+            let value = std::mem::take(&mut self.attribute_value);
+            match self.attributes.entry(name) {
+                Entry::Occupied(entry) => self.duplicate_attributes.push(entry.key().clone()),
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+            }
+        }
     }
 
-    /// If the exact string `s` is found at the current position, consume it.
-    /// Otherwise, panic.
-    fn expect(&mut self, s: &str) {
-        if self.starts_with(s) {
-            self.position += s.len();
+    /// Turn the finished tag into a token.
+    fn into_token(mut self) -> Token {
+        self.finish_attribute();
+        if self.is_end {
+            Token::EndTag { name: self.name }
         } else {
-            panic!("Expected {:?} at byte {} but it was not found", s, self.position);
+            Token::StartTag { name: self.name, attributes: self.attributes, self_closing: self.self_closing }
+        }
+    }
+}
+
+
+/*
+    We can use this to implement some simple methods for peeking at the next
+    characters in the input.
+ */
+
+impl Tokenizer {
+    fn new(input: String) -> Tokenizer {
+        Tokenizer {
+            input,
+            position: 0,
+            state: State::Data,
+            last_start_tag: String::new(),
+            errors: Vec::new(),
+            cdata_allowed: false,
+            ran_out: false,
         }
     }
 
+    /// Read the current character without consuming it, or `None` at the end of input.
+    fn next_char(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
 
     /*
         Rust strings are stored as UTF-8 byte arrays. To go to the next character,
         we can't just advance by one byte.
      */
 
+    /// Do the next characters start with the given string, ignoring ASCII case?
+    fn starts_with_ignore_case(&self, s: &str) -> bool {
+        self.input[self.position..]
+            .get(..s.len())
+            .is_some_and(|next| next.eq_ignore_ascii_case(s))
+    }
+
     /// Return the current character, and advance self.position to the next character.
-    fn consume_char(&mut self) -> char {
-        let c = self.next_char();
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
         self.position += c.len_utf8();
-        c
+        Some(c)
     }
 
+    /// Is the input at an end tag for the element whose raw text is being read?
+    fn at_appropriate_end_tag(&self) -> bool {
+        let rest = &self.input[self.position..];
+        let name_end = "</".len() + self.last_start_tag.len();
+        !self.last_start_tag.is_empty()
+            && rest.starts_with("</")
+            && rest.get(2..name_end).is_some_and(|name| name.eq_ignore_ascii_case(&self.last_start_tag))
+            && rest[name_end..].chars().next().is_some_and(|c| c.is_whitespace() || c == '/' || c == '>')
+    }
 
-    /*
-        Often we will want to consume a string of consecutive characters. The "consume_while"
-        method consumes characters that meet a given condition, and returns them as a string.
-        This method's argument is a function that takes a char and returns a bool.
-     */
+    /// Is the rest of the input the beginning of an end tag for the raw text element,
+    /// which more input might complete?
+    fn may_be_end_tag_start(&self) -> bool {
+        let rest = &self.input.as_bytes()[self.position..];
+        let end_tag_len = "</".len() + self.last_start_tag.len();
+        !rest.is_empty() && !self.last_start_tag.is_empty() && rest.len() <= end_tag_len
+            && format!("</{}", self.last_start_tag).as_bytes()[..rest.len()].eq_ignore_ascii_case(rest)
+    }
+
+    /// Is the input at a "&" whose character reference runs to the end of the input,
+    /// so that more input might make it longer?
+    fn reference_may_continue(&self) -> bool {
+        self.input[self.position..].strip_prefix('&').is_some_and(reference_runs_to_end)
+    }
 
     /// Consume characters until `test` returns false.
     fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
         let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
+        while let Some(c) = self.next_char() {
+            if !test(c) {
+                break;
+            }
+            result.push(c);
+            self.position += c.len_utf8();
         }
         result
     }
 
+    /// Record a parse error at the current position.
+    fn error(&mut self, expected: &str) {
+        let found = describe_next(&self.input[self.position..]);
+        self.errors.push(PendingError::new(self.position, expected, found));
+    }
+
+    /// Record that the input ended in the middle of a token.
+    fn unexpected_eof(&mut self, expected: &str) {
+        self.error(expected);
+        self.ran_out = true;
+    }
+
+    /// Record that the input ended in the middle of a tag, and return `Token::Eof`.
+    fn eof_error(&mut self, expected: &str) -> Token {
+        self.unexpected_eof(expected);
+        Token::Eof
+    }
+
+    /// Turn a finished tag into a token, reporting any problems with it.
+    fn emit_tag(&mut self, mut tag: Tag) -> Token {
+        tag.finish_attribute();
+        for name in &tag.duplicate_attributes {
+            self.errors.push(PendingError::new(self.position, "unique attribute names", format!("duplicate attribute {:?}", name)));
+        }
+        if tag.is_end && (!tag.attributes.is_empty() || tag.self_closing) {
+            self.errors.push(PendingError::new(self.position, "'>'", format!("attributes on end tag </{}>", tag.name)));
+        }
+        tag.into_token()
+    }
+
+
+    /*
+        Each call to "next_token" runs the state machine until it has a whole token.
+        Text is collected until the next "<", and a tag is collected until its closing ">".
+        Markup that doesn't make sense is never fatal: a "<" that doesn't start a tag
+        becomes text, and a tag cut off by the end of the input is dropped.
+     */
+
+    /// Return the next token from the input.
+    fn next_token(&mut self) -> Token {
+        self.ran_out = false;
+        let token = self.run_state_machine();
+        if let Token::StartTag { ref name, .. } = token {
+            self.last_start_tag = name.clone();
+        }
+        token
+    }
+
+    /// Run the state machine until the next token is complete.
+    fn run_state_machine(&mut self) -> Token {
+        let mut text = String::new();
+        let mut tag = Tag::new(false);
+        let mut comment = String::new();
+        loop {
+            match self.state {
+                State::Data => match self.next_char() {
+                    Some('<') if !text.is_empty() => return Token::Text(text),
+                    Some('<') => {
+                        self.consume_char();
+                        self.state = State::TagOpen;
+                    }
+                    Some('&') if !text.is_empty() && self.reference_may_continue() => return Token::Text(text),
+                    Some('&') => {
+                        self.consume_char();
+                        text.push_str(&self.consume_character_reference(false));
+                    }
+                    Some(c) => {
+                        self.consume_char();
+                        text.push(c);
+                    }
+                    None if !text.is_empty() => return Token::Text(text),
+                    None => return Token::Eof,
+                },
+
+                State::TagOpen => match self.next_char() {
+                    Some('!') => {
+                        self.consume_char();
+                        self.state = State::MarkupDeclarationOpen;
+                    }
+                    Some('?') => {
+                        // Processing instructions aren't part of HTML, so they become comments.
+                        self.error("tag name");
+                        self.state = State::BogusComment;
+                    }
+                    Some('/') => {
+                        self.consume_char();
+                        self.state = State::EndTagOpen;
+                    }
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        tag = Tag::new(false);
+                        self.state = State::TagName;
+                    }
+                    None => {
+                        self.unexpected_eof("tag name");
+                        text.push('<');
+                        self.state = State::Data;
+                    }
+                    Some(_) => {
+                        // Not a tag after all, so the "<" is just text.
+                        self.error("tag name");
+                        text.push('<');
+                        self.state = State::Data;
+                    }
+                },
+
+                State::EndTagOpen => match self.next_char() {
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        tag = Tag::new(true);
+                        self.state = State::TagName;
+                    }
+                    Some('>') => {
+                        // "</>" is ignored.
+                        self.error("tag name");
+                        self.consume_char();
+                        self.state = State::Data;
+                    }
+                    Some(_) => {
+                        self.error("tag name");
+                        self.state = State::BogusComment;
+                    }
+                    None => {
+                        self.unexpected_eof("tag name");
+                        text.push_str("</");
+                        self.state = State::Data;
+                    }
+                },
+
+                State::TagName => match self.consume_char() {
+                    Some(c) if c.is_whitespace() => self.state = State::BeforeAttributeName,
+                    Some('/') => self.state = State::SelfClosingStartTag,
+                    Some('>') => {
+                        self.state = State::Data;
+                        return self.emit_tag(tag);
+                    }
+                    Some(c) => tag.name.push(c.to_ascii_lowercase()),
+                    None => return self.eof_error("'>'"),
+                },
+
+                /*
+                    An attribute name is anything up to whitespace, "/", ">" or "=", folded
+                    to lower case like tag names: `data-id`, `aria-label`, `xml:lang`. The value
+                    can be double-quoted, single-quoted or unquoted, or left out entirely for
+                    boolean attributes like `disabled`, which then have an empty value.
+                 */
+                State::BeforeAttributeName => match self.next_char() {
+                    Some(c) if c.is_whitespace() => {
+                        self.consume_char();
+                    }
+                    Some('/') | Some('>') | None => self.state = State::AfterAttributeName,
+                    Some('=') => {
+                        // An attribute name can't start with "=", so it becomes part of the name.
+                        self.consume_char();
+                        tag.attribute_name.push('=');
+                        self.state = State::AttributeName;
+                    }
+                    Some(_) => self.state = State::AttributeName,
+                },
+
+                State::AttributeName => match self.next_char() {
+                    Some(c) if c.is_whitespace() => self.state = State::AfterAttributeName,
+                    Some('/') | Some('>') | None => self.state = State::AfterAttributeName,
+                    Some('=') => {
+                        self.consume_char();
+                        self.state = State::BeforeAttributeValue;
+                    }
+                    Some(c) => {
+                        self.consume_char();
+                        tag.attribute_name.push(c.to_ascii_lowercase());
+                    }
+                },
+
+                State::AfterAttributeName => match self.consume_char() {
+                    Some(c) if c.is_whitespace() => {}
+                    Some('/') => {
+                        tag.finish_attribute();
+                        self.state = State::SelfClosingStartTag;
+                    }
+                    Some('=') => self.state = State::BeforeAttributeValue,
+                    Some('>') => {
+                        self.state = State::Data;
+                        return self.emit_tag(tag);
+                    }
+                    Some(c) => {
+                        tag.finish_attribute();
+                        tag.attribute_name.push(c.to_ascii_lowercase());
+                        self.state = State::AttributeName;
+                    }
+                    None => return self.eof_error("'>'"),
+                },
+
+                State::BeforeAttributeValue => match self.next_char() {
+                    Some(c) if c.is_whitespace() => {
+                        self.consume_char();
+                    }
+                    Some('"') => {
+                        self.consume_char();
+                        self.state = State::AttributeValueDoubleQuoted;
+                    }
+                    Some('\'') => {
+                        self.consume_char();
+                        self.state = State::AttributeValueSingleQuoted;
+                    }
+                    Some('>') => {
+                        // "name=" with no value at all: the value is empty.
+                        self.consume_char();
+                        self.state = State::Data;
+                        return self.emit_tag(tag);
+                    }
+                    _ => self.state = State::AttributeValueUnquoted,
+                },
+
+                State::AttributeValueDoubleQuoted | State::AttributeValueSingleQuoted => {
+                    let quote = if self.state == State::AttributeValueDoubleQuoted { '"' } else { '\'' };
+                    match self.consume_char() {
+                        Some(c) if c == quote => {
+                            tag.finish_attribute();
+                            self.state = State::AfterAttributeValueQuoted;
+                        }
+                        Some('&') => {
+                            let value = self.consume_character_reference(true);
+                            tag.attribute_value.push_str(&value);
+                        }
+                        Some(c) => tag.attribute_value.push(c),
+                        None => return self.eof_error("'>'"),
+                    }
+                }
+
+                State::AttributeValueUnquoted => match self.consume_char() {
+                    Some(c) if c.is_whitespace() => {
+                        tag.finish_attribute();
+                        self.state = State::BeforeAttributeName;
+                    }
+                    Some('>') => {
+                        self.state = State::Data;
+                        return self.emit_tag(tag);
+                    }
+                    Some('&') => {
+                        let value = self.consume_character_reference(true);
+                        tag.attribute_value.push_str(&value);
+                    }
+                    Some(c) => tag.attribute_value.push(c),
+                    None => return self.eof_error("'>'"),
+                },
+
+                State::AfterAttributeValueQuoted => match self.next_char() {
+                    Some(c) if c.is_whitespace() => {
+                        self.consume_char();
+                        self.state = State::BeforeAttributeName;
+                    }
+                    Some('/') => {
+                        self.consume_char();
+                        self.state = State::SelfClosingStartTag;
+                    }
+                    Some('>') => {
+                        self.consume_char();
+                        self.state = State::Data;
+                        return self.emit_tag(tag);
+                    }
+                    Some(_) => {
+                        self.error("whitespace between attributes");
+                        self.state = State::BeforeAttributeName;
+                    }
+                    None => return self.eof_error("'>'"),
+                },
+
+                State::SelfClosingStartTag => match self.next_char() {
+                    Some('>') => {
+                        self.consume_char();
+                        self.state = State::Data;
+                        tag.self_closing = true;
+                        return self.emit_tag(tag);
+                    }
+                    Some(_) => {
+                        self.error("'>' after '/'");
+                        self.state = State::BeforeAttributeName;
+                    }
+                    None => return self.eof_error("'>'"),
+                },
+
+                /*
+                    The contents of elements like <style> and <script> are raw text: a "<"
+                    doesn't start a tag, and only the matching end tag (`</style>`) ends
+                    the text. RCDATA, used for <textarea> and <title>, is the same except
+                    that character references are decoded. PLAINTEXT never ends at all.
+
+                    Real browsers have extra states for `<!--` inside <script>, which are
+                    not implemented here.
+                 */
+                State::RawText | State::RcData => {
+                    if self.at_appropriate_end_tag() {
+                        if !text.is_empty() {
+                            return Token::Text(text);
+                        }
+                        self.position += "</".len();
+                        self.state = State::EndTagOpen;
+                        continue;
+                    }
+                    if self.may_be_end_tag_start() {
+                        // The end tag might be cut off by the end of the input.
+                        if !text.is_empty() {
+                            return Token::Text(text);
+                        }
+                        self.ran_out = true;
+                    }
+                    if self.state == State::RcData && !text.is_empty() && self.reference_may_continue() {
+                        return Token::Text(text);
+                    }
+                    match self.consume_char() {
+                        Some('&') if self.state == State::RcData => {
+                            text.push_str(&self.consume_character_reference(false));
+                        }
+                        Some(c) => text.push(c),
+                        None if !text.is_empty() => return Token::Text(text),
+                        None => return Token::Eof,
+                    }
+                }
+
+                State::PlainText => match self.consume_char() {
+                    Some(c) => text.push(c),
+                    None if !text.is_empty() => return Token::Text(text),
+                    None => return Token::Eof,
+                },
+
+                State::BogusComment => match self.consume_char() {
+                    Some('>') => {
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    None => {
+                        self.ran_out = true;
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some(c) => comment.push(c),
+                },
+
+                State::MarkupDeclarationOpen => {
+                    if self.starts_with_ignore_case("--") {
+                        self.position += 2;
+                        self.state = State::CommentStart;
+                    } else if self.starts_with_ignore_case("doctype") {
+                        self.position += "doctype".len();
+                        self.state = State::Data;
+                        return self.consume_doctype();
+                    } else if self.cdata_allowed && self.input[self.position..].starts_with("[CDATA[") {
+                        self.position += "[CDATA[".len();
+                        self.state = State::Data;
+                        return self.consume_cdata();
+                    } else {
+                        self.error("'--' or DOCTYPE");
+                        self.state = State::BogusComment;
+                    }
+                }
+
+
+                /*
+                    A comment ends at the first "-->". The extra states deal with dashes
+                    that turn out not to be the end, and with broken endings like "--!>"
+                    or "<!-->" that browsers accept anyway.
+                 */
+                State::CommentStart => match self.next_char() {
+                    Some('-') => {
+                        self.consume_char();
+                        self.state = State::CommentStartDash;
+                    }
+                    Some('>') => {
+                        self.error("'-->'");
+                        self.consume_char();
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    _ => self.state = State::Comment,
+                },
+
+                State::CommentStartDash => match self.next_char() {
+                    Some('-') => {
+                        self.consume_char();
+                        self.state = State::CommentEnd;
+                    }
+                    Some('>') => {
+                        self.error("'-->'");
+                        self.consume_char();
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    _ => {
+                        comment.push('-');
+                        self.state = State::Comment;
+                    }
+                },
+
+                State::Comment => match self.consume_char() {
+                    Some('-') => self.state = State::CommentEndDash,
+                    Some(c) => comment.push(c),
+                    None => {
+                        self.unexpected_eof("'-->'");
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                },
+
+                State::CommentEndDash => match self.next_char() {
+                    Some('-') => {
+                        self.consume_char();
+                        self.state = State::CommentEnd;
+                    }
+                    None => {
+                        self.unexpected_eof("'-->'");
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some(_) => {
+                        comment.push('-');
+                        self.state = State::Comment;
+                    }
+                },
+
+                State::CommentEnd => match self.next_char() {
+                    Some('>') => {
+                        self.consume_char();
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some('!') => {
+                        self.consume_char();
+                        self.state = State::CommentEndBang;
+                    }
+                    Some('-') => {
+                        self.consume_char();
+                        comment.push('-');
+                    }
+                    None => {
+                        self.unexpected_eof("'-->'");
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some(_) => {
+                        comment.push_str("--");
+                        self.state = State::Comment;
+                    }
+                },
+
+                State::CommentEndBang => match self.next_char() {
+                    Some('>') => {
+                        self.errors.push(PendingError::new(self.position - "--!".len(), "'-->'", "'--!>'"));
+                        self.consume_char();
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some('-') => {
+                        self.consume_char();
+                        comment.push_str("--!");
+                        self.state = State::CommentEndDash;
+                    }
+                    None => {
+                        self.unexpected_eof("'-->'");
+                        self.state = State::Data;
+                        return Token::Comment(comment);
+                    }
+                    Some(_) => {
+                        comment.push_str("--!");
+                        self.state = State::Comment;
+                    }
+                },
+            }
+        }
+    }
+}
+
 
+impl Tokenizer {
     /*
-        We can use this to ignore a sequence of space characters, or to consume a string of
-        alphanumeric characters.
+        Doctypes are read in one go, since the only thing that matters about them
+        is the name and the two optional identifiers:
+            <!DOCTYPE html PUBLIC "-//W3C//DTD HTML 4.01//EN" "http://www.w3.org/TR/html4/strict.dtd">
      */
 
-    /// Consume and discard zero or more whitespace characters.
-    fn consume_whitespace(&mut self) {
+    /// Read the rest of a doctype, after the `<!DOCTYPE` keyword.
+    fn consume_doctype(&mut self) -> Token {
+        self.consume_while(char::is_whitespace);
+        let name = self.consume_while(|c| !c.is_whitespace() && c != '>').to_ascii_lowercase();
+        self.consume_while(char::is_whitespace);
+
+        let mut public_id = String::new();
+        let mut system_id = String::new();
+        if self.starts_with_ignore_case("public") {
+            self.position += "public".len();
+            public_id = self.consume_doctype_identifier();
+            system_id = self.consume_doctype_identifier();
+        } else if self.starts_with_ignore_case("system") {
+            self.position += "system".len();
+            system_id = self.consume_doctype_identifier();
+        }
+
+        // Skip anything else up to the closing ">".
+        self.consume_while(|c| c != '>');
+        if self.consume_char().is_none() {
+            self.ran_out = true;
+        }
+        Token::Doctype(dom::Doctype { name, public_id, system_id })
+    }
+
+    /// Read the text of a CDATA section, after the `<![CDATA[`.
+    fn consume_cdata(&mut self) -> Token {
+        let rest = &self.input[self.position..];
+        match rest.find("]]>") {
+            Some(end) => {
+                let text = rest[..end].to_string();
+                self.position += end + "]]>".len();
+                Token::Text(text)
+            }
+            None => {
+                let text = rest.to_string();
+                self.position = self.input.len();
+                self.unexpected_eof("']]>'");
+                Token::Text(text)
+            }
+        }
+    }
+
+    /// Read a quoted doctype identifier, or return an empty string if there isn't one.
+    fn consume_doctype_identifier(&mut self) -> String {
         self.consume_while(char::is_whitespace);
+        let quote = match self.next_char() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => return String::new(),
+        };
+        self.consume_char();
+        let identifier = self.consume_while(|c| c != quote && c != '>');
+        if self.next_char() == Some(quote) {
+            self.consume_char();
+        }
+        identifier
+    }
+}
+
+
+/*
+    Character references let authors write characters that would otherwise be
+    markup (`&lt;`), or that are hard to type (`&nbsp;`, `&#x2014;`). There are
+    three forms:
+        1. Named: &amp; => "&" (see the `entities` module for the full list)
+        2. Decimal: &#39; => "'"
+        3. Hexadecimal: &#x27; => "'"
+
+    The ";" at the end is optional for numeric references and for some old named
+    ones. Anything that doesn't turn out to be a reference is left in the text as-is.
+ */
+
+impl Tokenizer {
+    /// Read a character reference after a "&", returning the text it stands for.
+    /// If there is no valid reference here, nothing more is consumed and "&" is returned.
+    fn consume_character_reference(&mut self, in_attribute: bool) -> String {
+        if reference_runs_to_end(&self.input[self.position..]) {
+            self.ran_out = true;
+        }
+        match self.next_char() {
+            Some('#') => self.consume_numeric_character_reference(),
+            Some(c) if c.is_ascii_alphanumeric() => self.consume_named_character_reference(in_attribute),
+            _ => "&".to_string(),
+        }
+    }
+
+    /// Read the longest name from the table of named character references.
+    fn consume_named_character_reference(&mut self, in_attribute: bool) -> String {
+        let rest = &self.input[self.position..];
+        let mut end = rest.bytes().take_while(u8::is_ascii_alphanumeric).count();
+        if rest[end..].starts_with(';') {
+            end += 1;
+        }
+
+        for len in (1..=end.min(entities::LONGEST_NAME)).rev() {
+            let name = &rest[..len];
+            if let Some(value) = entities::lookup(name) {
+                // In attribute values, "&copy=1" or "&copyright" are left alone, because
+                // they are probably part of a URL query rather than a reference.
+                let next = rest[len..].chars().next();
+                if in_attribute && !name.ends_with(';') && next.is_some_and(|c| c == '=' || c.is_ascii_alphanumeric()) {
+                    return "&".to_string();
+                }
+                self.position += len;
+                if !name.ends_with(';') {
+                    self.error("';'");
+                }
+                return value.to_string();
+            }
+        }
+        "&".to_string()
+    }
+
+    /// Read a decimal or hexadecimal reference like `&#39;` or `&#x27;`.
+    fn consume_numeric_character_reference(&mut self) -> String {
+        let start = self.position;
+        self.consume_char(); // "#"
+
+        let radix = match self.next_char() {
+            Some('x') | Some('X') => {
+                self.consume_char();
+                16
+            }
+            _ => 10,
+        };
+        let digits = self.consume_while(|c| c.is_digit(radix));
+        if digits.is_empty() {
+            // Not a reference after all: "&#" is just text.
+            self.error("digits");
+            self.position = start;
+            return "&".to_string();
+        }
+        if self.next_char() == Some(';') {
+            self.consume_char();
+        } else {
+            self.error("';'");
+        }
+
+        // Numbers too large to fit are out of range, just like those above U+10FFFF.
+        let code = u32::from_str_radix(&digits, radix).unwrap_or(u32::MAX);
+        let c = numeric_reference_char(code);
+        if code == 0 || code > 0x10FFFF || (0xD800..=0xDFFF).contains(&code) {
+            let found = format!("&#{}{};", if radix == 16 { "x" } else { "" }, digits);
+            self.errors.push(PendingError::new(start - 1, "reference to a valid character", found));
+        }
+        c.to_string()
+    }
+}
+
+/// Could `rest`, the input after a "&", be cut off in the middle of a character reference?
+fn reference_runs_to_end(rest: &str) -> bool {
+    let name = match rest.strip_prefix('#') {
+        Some(number) => number.strip_prefix(['x', 'X']).unwrap_or(number),
+        None => rest,
+    };
+    name.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/*
+    Code points 0x80 to 0x9F are control characters in Unicode, but old pages use
+    them to mean the windows-1252 characters with those byte values, so `&#150;`
+    is read as an en dash. Code points that are not valid characters are replaced
+    with U+FFFD REPLACEMENT CHARACTER.
+ */
+
+/// The character for a numeric character reference.
+fn numeric_reference_char(code: u32) -> char {
+    match code {
+        0 => '\u{FFFD}',
+        0x80..=0x9F => encoding::WINDOWS_1252_C1[(code - 0x80) as usize],
+        _ => char::from_u32(code).unwrap_or('\u{FFFD}'),
+    }
+}
+
+
+/*
+    The tree builder needs to move nodes around after they have been inserted
+    (see the adoption agency algorithm below), which is awkward with a tree of
+    owned `dom::Node`s. So while parsing, nodes live in a flat list and refer to
+    each other by index. Node 0 is the document itself. The finished tree is
+    converted to `dom::Node`s at the end.
+ */
+
+/// A node in the tree builder's working tree.
+struct TreeNode {
+    node_type: dom::NodeType,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    span: dom::Span,
+}
+
+/// An entry in the list of active formatting elements.
+#[derive(Clone, Copy, PartialEq)]
+enum Formatting {
+    Element(usize),
+    Marker,
+}
+
+/// HTML Tree Builder struct
+/*
+    e.g.
+        TreeBuilder {
+            nodes: [document, <div>, <p>, "Hello "],
+            open_elements: [0, 1, 2],
+            active_formatting: [],
+        }
+ */
+struct TreeBuilder {
+    nodes: Vec<TreeNode>,
+    open_elements: Vec<usize>,
+    active_formatting: Vec<Formatting>,
+    // Set when the tokenizer has to switch states, e.g. to read a <style> as raw text.
+    tokenizer_state: Option<State>,
+    // Set after <textarea>, <pre> and <listing>, whose first newline is ignored.
+    ignore_newline: bool,
+    // Where the token being processed starts and ends in the input.
+    position: usize,
+    token_end: usize,
+    errors: Vec<PendingError>,
+    // The tag name of the element a fragment is being parsed for, if any.
+    fragment_context: Option<String>,
+}
+
+
+/*
+    Elements that have special parsing rules. An end tag never implicitly closes
+    one of these (the "any other end tag" rule stops when it reaches one).
+ */
+const SPECIAL_ELEMENTS: &[&str] = &[
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound",
+    "blockquote", "body", "br", "button", "caption", "center", "col", "colgroup", "dd",
+    "details", "dir", "div", "dl", "dt", "embed", "fieldset", "figcaption", "figure",
+    "footer", "form", "frame", "frameset", "h1", "h2", "h3", "h4", "h5", "h6", "head",
+    "header", "hgroup", "hr", "html", "iframe", "img", "input", "keygen", "li", "link",
+    "listing", "main", "marquee", "menu", "meta", "nav", "noembed", "noframes",
+    "noscript", "object", "ol", "p", "param", "plaintext", "pre", "script", "search",
+    "section", "select", "source", "style", "summary", "table", "tbody", "td",
+    "template", "textarea", "tfoot", "th", "thead", "title", "tr", "track", "ul", "wbr",
+    "xmp",
+];
+
+/// Inline elements that are re-opened when they are closed out of order.
+const FORMATTING_ELEMENTS: &[&str] = &[
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong",
+    "tt", "u",
+];
+
+/// Block start tags that close an open <p>.
+const CLOSES_P: &[&str] = &[
+    "address", "article", "aside", "blockquote", "center", "details", "dialog", "dir",
+    "div", "dl", "fieldset", "figcaption", "figure", "footer", "form", "header", "hgroup",
+    "listing", "main", "menu", "nav", "ol", "p", "pre", "search", "section",
+    "summary", "table", "ul",
+];
+
+/// Block end tags that close everything up to the matching element.
+const BLOCK_END_TAGS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "button", "center", "details", "dialog",
+    "dir", "div", "dl", "fieldset", "figcaption", "figure", "footer", "form", "header",
+    "hgroup", "listing", "main", "menu", "nav", "ol", "pre", "search", "section",
+    "summary", "ul",
+];
+
+const HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// Elements that can't have any content, so they never have an end tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img",
+    "input", "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Elements whose end tags may be left out.
+const IMPLIED_END_TAGS: &[&str] = &[
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+/// Elements that stop a search for an element "in scope".
+const SCOPE_BOUNDARIES: &[&str] = &[
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+];
+
+/// Elements that stop a search for an element "in table scope".
+const TABLE_SCOPE_BOUNDARIES: &[&str] = &["html", "table", "template"];
+
+/// HTML start tags that end foreign content.
+const BREAKOUT_ELEMENTS: &[&str] = &[
+    "b", "big", "blockquote", "body", "br", "center", "code", "dd", "div", "dl", "dt", "em",
+    "embed", "h1", "h2", "h3", "h4", "h5", "h6", "head", "hr", "i", "img", "li", "listing",
+    "menu", "meta", "nobr", "ol", "p", "pre", "ruby", "s", "small", "span", "strike",
+    "strong", "sub", "sup", "table", "tt", "u", "ul", "var",
+];
+
+/// MathML elements whose contents are parsed as HTML.
+const MATHML_TEXT_INTEGRATION_POINTS: &[&str] = &["mi", "mn", "mo", "ms", "mtext"];
+
+/// SVG tag names with capital letters.
+const SVG_TAG_NAMES: &[&str] = &[
+    "altGlyph", "altGlyphDef", "altGlyphItem", "animateColor", "animateMotion",
+    "animateTransform", "clipPath", "feBlend", "feColorMatrix", "feComponentTransfer",
+    "feComposite", "feConvolveMatrix", "feDiffuseLighting", "feDisplacementMap",
+    "feDistantLight", "feDropShadow", "feFlood", "feFuncA", "feFuncB", "feFuncG", "feFuncR",
+    "feGaussianBlur", "feImage", "feMerge", "feMergeNode", "feMorphology", "feOffset",
+    "fePointLight", "feSpecularLighting", "feSpotLight", "feTile", "feTurbulence",
+    "foreignObject", "glyphRef", "linearGradient", "radialGradient", "textPath",
+];
+
+/// SVG attribute names with capital letters.
+const SVG_ATTRIBUTES: &[&str] = &[
+    "attributeName", "attributeType", "baseFrequency", "baseProfile", "calcMode",
+    "clipPathUnits", "diffuseConstant", "edgeMode", "filterUnits", "glyphRef",
+    "gradientTransform", "gradientUnits", "kernelMatrix", "kernelUnitLength", "keyPoints",
+    "keySplines", "keyTimes", "lengthAdjust", "limitingConeAngle", "markerHeight",
+    "markerUnits", "markerWidth", "maskContentUnits", "maskUnits", "numOctaves",
+    "pathLength", "patternContentUnits", "patternTransform", "patternUnits", "pointsAtX",
+    "pointsAtY", "pointsAtZ", "preserveAlpha", "preserveAspectRatio", "primitiveUnits",
+    "refX", "refY", "repeatCount", "repeatDur", "requiredExtensions", "requiredFeatures",
+    "specularConstant", "specularExponent", "spreadMethod", "startOffset", "stdDeviation",
+    "stitchTiles", "surfaceScale", "systemLanguage", "tableValues", "targetX", "targetY",
+    "textLength", "viewBox", "viewTarget", "xChannelSelector", "yChannelSelector",
+    "zoomAndPan",
+];
+
+/// Return the entry of `names` that matches the lower-case `name`, or `name` if there isn't one.
+fn adjust_case(names: &[&str], name: String) -> String {
+    match names.iter().find(|adjusted| adjusted.eq_ignore_ascii_case(&name)) {
+        Some(adjusted) => adjusted.to_string(),
+        None => name,
+    }
+}
+
+
+impl TreeBuilder {
+    fn new() -> TreeBuilder {
+        let document = TreeNode {
+            node_type: dom::NodeType::Element(dom::Element {
+                tag_name: String::new(),
+                namespace: dom::Namespace::Html,
+                attributes: HashMap::new(),
+            }),
+            parent: None,
+            children: Vec::new(),
+            span: dom::Span { start: 0, end: 0 },
+        };
+        TreeBuilder {
+            nodes: vec![document],
+            open_elements: vec![0],
+            active_formatting: Vec::new(),
+            tokenizer_state: None,
+            ignore_newline: false,
+            position: 0,
+            token_end: 0,
+            errors: Vec::new(),
+            fragment_context: None,
+        }
+    }
+
+    /// The tag name of node `id`, or "" if it is not an element.
+    fn tag_name(&self, id: usize) -> &str {
+        match self.nodes[id].node_type {
+            dom::NodeType::Element(ref element) => &element.tag_name,
+            _ => "",
+        }
+    }
+
+    /// The innermost open element.
+    fn current_node(&self) -> usize {
+        *self.open_elements.last().unwrap()
     }
 
-    /// Parse a tag or attribute name.
-    fn parse_name(&mut self) -> String {
-        self.consume_while(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9'))
+    /// Add a new node to the working tree without attaching it anywhere.
+    /// A new node's span covers the token being processed, even if the node is only implied by it.
+    fn create_node(&mut self, node_type: dom::NodeType) -> usize {
+        let span = dom::Span { start: self.position, end: self.token_end };
+        self.nodes.push(TreeNode { node_type, parent: None, children: Vec::new(), span });
+        self.nodes.len() - 1
+    }
+
+    /// Create a copy of element `id` with no children, used to re-open formatting elements.
+    fn clone_element(&mut self, id: usize) -> usize {
+        let node_type = self.nodes[id].node_type.clone();
+        self.create_node(node_type)
+    }
+
+    /// Remove node `id` from its parent, if it has one.
+    fn detach(&mut self, id: usize) {
+        if let Some(parent) = self.nodes[id].parent.take() {
+            self.nodes[parent].children.retain(|&child| child != id);
+        }
+    }
+
+    /// Make node `child` the last child of node `parent`.
+    fn append(&mut self, parent: usize, child: usize) {
+        self.detach(child);
+        self.nodes[child].parent = Some(parent);
+        self.nodes[parent].children.push(child);
+    }
+
+    /// Insert a new element as a child of the current node, and push it onto the stack.
+    fn insert_element(&mut self, tag_name: String, attributes: dom::AttributeMap) -> usize {
+        self.insert_element_ns(dom::Namespace::Html, tag_name, attributes)
+    }
+
+    /// Create an element in `namespace` and insert it as the last child of the current node.
+    fn insert_element_ns(&mut self, namespace: dom::Namespace, tag_name: String, attributes: dom::AttributeMap) -> usize {
+        let element = dom::Element { tag_name, namespace, attributes };
+        let id = self.create_node(dom::NodeType::Element(element));
+        let parent = self.current_node();
+        self.append(parent, id);
+        self.open_elements.push(id);
+        id
+    }
+
+
+    /// Insert an element that can't have children, and close it again immediately.
+    fn insert_void_element(&mut self, tag_name: String, attributes: dom::AttributeMap) {
+        self.insert_element(tag_name, attributes);
+        self.open_elements.pop();
     }
 
 
     /*
-        In our simplified version of HTML, a text node can contain any character except "<".
+        Text is added to the current node, merging with a text node that is already
+        there. Whitespace is kept as it is, since the space in `<b>a</b> <i>b</i>`
+        matters. The only exception is whitespace at the start of the document or
+        outside the root element, like the newline after a doctype, which isn't part
+        of the document's content.
      */
 
-    /// Parse a text node.
-    fn parse_text(&mut self) -> dom::Node {
-        dom::text(self.consume_while(|c| c != '<'))
+    /// Insert text into the current node.
+    fn insert_text(&mut self, text: &str) {
+        let parent = self.current_node();
+        if let Some(&last) = self.nodes[parent].children.last() {
+            if let dom::NodeType::Text(ref mut data) = self.nodes[last].node_type {
+                data.push_str(text);
+                self.nodes[last].span.end = self.token_end;
+                return;
+            }
+        }
+        let mut text = text;
+        if parent == 0 {
+            let content = text.trim_start_matches(|c: char| c.is_ascii_whitespace());
+            if content.is_empty() {
+                return;
+            }
+            self.position += text.len() - content.len();
+            text = content;
+        }
+        let id = self.create_node(dom::NodeType::Text(text.to_string()));
+        self.append(parent, id);
     }
 
 
     /*
-        Parsing attributes is pretty easy in our simplified syntax. Until we reach the
-        end of the opening tag (>) we repeatedly look for a name followed by = and then
-        a string enclosed in quotes.
+        Many rules depend on whether an element is "in scope": whether it's on the
+        stack of open elements without a scope boundary (like a table) in between.
+        This is how `</p>` inside a table cell avoids closing a paragraph outside the table.
      */
 
-    /// Parse a quoted value
-    fn parse_attribute_value(&mut self) -> String {
-        let open_quote = self.consume_char();
-        assert!(open_quote == '""' || open_quote == '\'');
-        let value = self.consume_while(|c| c != open_quote);
-        let close_quote = self.consume_char();
-        assert_eq!(open_quote, close_quote);
-        value
+    /// Is there an open element named `tag_name` that isn't hidden behind a scope boundary?
+    fn has_element_in_scope(&self, tag_name: &str, extra_boundaries: &[&str]) -> bool {
+        for &id in self.open_elements.iter().rev() {
+            let name = self.tag_name(id);
+            if name == tag_name {
+                return true;
+            }
+            if SCOPE_BOUNDARIES.contains(&name) || extra_boundaries.contains(&name) || self.is_integration_point(id) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Is there an open element named `tag_name` that isn't hidden behind a table?
+    fn has_element_in_table_scope(&self, tag_name: &str) -> bool {
+        for &id in self.open_elements.iter().rev() {
+            let name = self.tag_name(id);
+            if name == tag_name {
+                return true;
+            }
+            if TABLE_SCOPE_BOUNDARIES.contains(&name) {
+                return false;
+            }
+        }
+        false
     }
 
-    /// Parse a single name="value" pair.
-    fn parse_attribute(&mut self) -> (String, String) {
-        let name = self.parse_name();
-        self.expect("=");
-        let value = self.parse_attribute_value();
-        (name, value)
+    /// Is there an open element among `tag_names` that isn't hidden behind a scope boundary?
+    fn has_any_in_scope(&self, tag_names: &[&str]) -> bool {
+        tag_names.iter().any(|name| self.has_element_in_scope(name, &[]))
     }
 
-    /// Parse a list of name="value" pairs, seperated by whitespace.
-    fn parse_attributes(&mut self) -> dom::AttributeMap {
-        let mut attributes = HashMap::new();
+    /// Pop elements whose end tags may be left out, except for `except`.
+    fn generate_implied_end_tags(&mut self, except: &str) {
         loop {
-            self.consume_whitespace();
-            if self.next_char() == '>' {
+            let name = self.tag_name(self.current_node());
+            if name == except || !IMPLIED_END_TAGS.contains(&name) {
+                break;
+            }
+            self.open_elements.pop();
+        }
+    }
+
+    /// Pop elements off the stack until one named `tag_name` has been popped.
+    fn pop_until(&mut self, tag_names: &[&str]) {
+        while self.open_elements.len() > 1 {
+            let id = self.open_elements.pop().unwrap();
+            if tag_names.contains(&self.tag_name(id)) {
                 break;
             }
-            let (name, value) = self.parse_attribute();
-            attributes.insert(name, value);
         }
-        attributes
     }
 
+    /// Close the open <p> element, if there is one in button scope.
+    fn close_p_element(&mut self) {
+        if self.has_element_in_scope("p", &["button"]) {
+            self.generate_implied_end_tags("p");
+            self.pop_until(&["p"]);
+        }
+    }
+
+
     /*
-        An element is more complicated. It includes opening and closing tags, and between them
-        any number of child nodes.
+        Formatting elements like <b> and <i> are tracked in a second list, the
+        "active formatting elements". If one is closed early because a parent
+        ended, the tree builder opens a fresh copy before inserting more content,
+        so `<p><b>bold</p><p>still bold</p>` keeps the second paragraph bold.
      */
 
-    /// Parse a single element, including its open tag, contents, and closing tag.
-    fn parse_element(&mut self) -> dom::Node {
-        // Opening tag.
-        self.expect("<");
-        let tag_name = self.parse_name();
-        let attrs = self.parse_attributes();
-        self.expect(">");
+    /// Re-open formatting elements that were closed implicitly.
+    fn reconstruct_active_formatting_elements(&mut self) {
+        let entry_is_open = |builder: &TreeBuilder, entry: Formatting| match entry {
+            Formatting::Marker => true,
+            Formatting::Element(id) => builder.open_elements.contains(&id),
+        };
+
+        let last = match self.active_formatting.last() {
+            Some(&entry) => entry,
+            None => return,
+        };
+        if entry_is_open(self, last) {
+            return;
+        }
+
+        // Rewind to the earliest entry that isn't open, then re-open everything after it.
+        let mut index = self.active_formatting.len() - 1;
+        while index > 0 && !entry_is_open(self, self.active_formatting[index - 1]) {
+            index -= 1;
+        }
+        for i in index..self.active_formatting.len() {
+            if let Formatting::Element(id) = self.active_formatting[i] {
+                let new_id = self.clone_element(id);
+                let parent = self.current_node();
+                self.append(parent, new_id);
+                self.open_elements.push(new_id);
+                self.active_formatting[i] = Formatting::Element(new_id);
+            }
+        }
+    }
 
-        // Contents.
-        let children = self.parse_nodes();
+    /// Add element `id` to the list of active formatting elements.
+    fn push_active_formatting(&mut self, id: usize) {
+        // At most three identical formatting elements are kept after the last marker.
+        let mut identical = Vec::new();
+        for (i, &entry) in self.active_formatting.iter().enumerate().rev() {
+            match entry {
+                Formatting::Marker => break,
+                Formatting::Element(other) => {
+                    let same = match (&self.nodes[id].node_type, &self.nodes[other].node_type) {
+                        (dom::NodeType::Element(a), dom::NodeType::Element(b)) =>
+                            a.tag_name == b.tag_name && a.attributes == b.attributes,
+                        _ => false,
+                    };
+                    if same {
+                        identical.push(i);
+                    }
+                }
+            }
+        }
+        if identical.len() >= 3 {
+            self.active_formatting.remove(*identical.last().unwrap());
+        }
+        self.active_formatting.push(Formatting::Element(id));
+    }
 
-        // Closing tag.
-        self.expect("</");
-        self.expect(&tag_name);
-        self.expect(">");
+    /// Find the last active formatting element named `tag_name` after the last marker.
+    fn active_formatting_element(&self, tag_name: &str) -> Option<usize> {
+        for &entry in self.active_formatting.iter().rev() {
+            match entry {
+                Formatting::Marker => return None,
+                Formatting::Element(id) if self.tag_name(id) == tag_name => return Some(id),
+                Formatting::Element(_) => {}
+            }
+        }
+        None
+    }
 
-        dom::element(tag_name, attrs, children)
+    fn remove_active_formatting(&mut self, id: usize) {
+        self.active_formatting.retain(|&entry| entry != Formatting::Element(id));
     }
 
 
     /*
-        Now we're ready to start parsing HTML. To parse a single node, we look at its
-        first character to see if it is an element or a text node.
+        The adoption agency algorithm handles end tags for formatting elements that
+        are closed in the wrong order, like `<b>1<p>2</b>3</p>`. Browsers turn that into
+        `<b>1</b><p><b>2</b>3</p>`: the paragraph is moved out of the <b>, and the part
+        of the <b> that was inside the paragraph is re-created there.
+
+        See https://html.spec.whatwg.org/multipage/parsing.html#adoption-agency-algorithm
      */
 
-    /// Parse a single node.
-    fn parse_node(&mut self) -> dom::Node {
-        if self.starts_with("<") {
-            self.parse_element()
-        } else {
-            self.parse_text()
+    /// Handle the end tag of a formatting element. Returns false if the tag should be
+    /// treated like any other end tag instead.
+    fn adoption_agency(&mut self, subject: &str) -> bool {
+        let current = self.current_node();
+        if self.tag_name(current) == subject && !self.active_formatting.contains(&Formatting::Element(current)) {
+            self.open_elements.pop();
+            return true;
         }
-    }
 
+        for _ in 0..8 {
+            let formatting_element = match self.active_formatting_element(subject) {
+                Some(id) => id,
+                None => return false,
+            };
 
-    /*
-        To parse the child nodes, we recursively call "parse_node" in a loop until
-        we reach the closing tag. This function returns a Vec, which is Rust's name
-        for a growable array.
-     */
+            let formatting_index = match self.open_elements.iter().position(|&id| id == formatting_element) {
+                Some(index) => index,
+                None => {
+                    // Closed already: forget about it.
+                    self.stray_end_tag(subject);
+                    self.remove_active_formatting(formatting_element);
+                    return true;
+                }
+            };
+            if !self.has_element_in_scope(subject, &[]) {
+                // Stray end tag: ignore it.
+                self.stray_end_tag(subject);
+                return true;
+            }
+            if formatting_element != self.current_node() {
+                self.check_current_node(&[subject], subject);
+            }
 
-    /// Parse a sequence of sibling nodes.
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
-        let mut nodes = Vec::new();
-        loop {
-            self.consume_whitespace();
-            if self.eof() || self.starts_with("</") {
-                break;
+            // The furthest block is the first special element opened inside the formatting element.
+            let furthest_block = self.open_elements[formatting_index + 1..]
+                .iter()
+                .copied()
+                .find(|&id| SPECIAL_ELEMENTS.contains(&self.tag_name(id)));
+            let furthest_block = match furthest_block {
+                Some(id) => id,
+                None => {
+                    // Nothing to move: just close everything up to the formatting element.
+                    self.open_elements.truncate(formatting_index);
+                    self.remove_active_formatting(formatting_element);
+                    return true;
+                }
+            };
+
+            let common_ancestor = self.open_elements[formatting_index - 1];
+            let mut bookmark = self.active_formatting
+                .iter()
+                .position(|&entry| entry == Formatting::Element(formatting_element))
+                .unwrap();
+
+            // Walk up from the furthest block, re-creating formatting elements along the way.
+            let mut node_index = self.open_elements.iter().position(|&id| id == furthest_block).unwrap();
+            let mut last_node = furthest_block;
+            let mut inner_loop_counter = 0;
+            loop {
+                inner_loop_counter += 1;
+                node_index -= 1;
+                let node = self.open_elements[node_index];
+                if node == formatting_element {
+                    break;
+                }
+
+                let mut list_index = self.active_formatting.iter().position(|&entry| entry == Formatting::Element(node));
+                if inner_loop_counter > 3 {
+                    if let Some(index) = list_index {
+                        self.active_formatting.remove(index);
+                        if index < bookmark {
+                            bookmark -= 1;
+                        }
+                        list_index = None;
+                    }
+                }
+                let list_index = match list_index {
+                    Some(index) => index,
+                    None => {
+                        self.open_elements.remove(node_index);
+                        continue;
+                    }
+                };
+
+                let new_node = self.clone_element(node);
+                self.active_formatting[list_index] = Formatting::Element(new_node);
+                self.open_elements[node_index] = new_node;
+                if last_node == furthest_block {
+                    bookmark = list_index + 1;
+                }
+                self.append(new_node, last_node);
+                last_node = new_node;
+            }
+
+            self.append(common_ancestor, last_node);
+
+            // Move the furthest block's children into a new copy of the formatting element.
+            let new_element = self.clone_element(formatting_element);
+            let children = std::mem::take(&mut self.nodes[furthest_block].children);
+            for child in children {
+                self.nodes[child].parent = None;
+                self.append(new_element, child);
+            }
+            self.append(furthest_block, new_element);
+
+            let old_index = self.active_formatting
+                .iter()
+                .position(|&entry| entry == Formatting::Element(formatting_element))
+                .unwrap();
+            self.active_formatting.remove(old_index);
+            if old_index < bookmark {
+                bookmark -= 1;
             }
-            nodes.push(self.parse_node());
+            self.active_formatting.insert(bookmark, Formatting::Element(new_element));
+
+            self.open_elements.retain(|&id| id != formatting_element);
+            let block_index = self.open_elements.iter().position(|&id| id == furthest_block).unwrap();
+            self.open_elements.insert(block_index + 1, new_element);
         }
-        nodes
+        true
     }
-}
 
 
-/*
-    This function will create a root node for the document if it doesn't
-    include one explicitly; this is similar to what a real HTML parser does.
- */
+    /*
+        This is where each token is handled. The rules are a simplified version of
+        the "in body" insertion mode from the HTML standard.
+     */
 
-/// Parse an HTML document and return the root element.
-pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser { position: 0, input: source }.parse_nodes();
+    /// Add a token to the tree.
+    fn process_token(&mut self, token: Token) {
+        // A token is part of the elements that are open when it arrives, including any
+        // it closes, like `<p>` closing the previous <p>. Elements it opens start with it.
+        self.extend_open_elements();
+        self.dispatch_token(token);
+    }
 
-    // If the document contains a root element, just return it. Otherwise, create one.
-    if nodes.len() == 1 {
-        nodes.remove(0)
-    } else {
-        dom::element("html".to_string(), HashMap::new(), nodes)
+    /// Make the spans of the open elements reach the end of the current token.
+    fn extend_open_elements(&mut self) {
+        for &id in &self.open_elements[1..] {
+            self.nodes[id].span.end = self.token_end;
+        }
+    }
+
+    fn dispatch_token(&mut self, token: Token) {
+        let ignore_newline = std::mem::take(&mut self.ignore_newline);
+        match token {
+            Token::Text(text) => {
+                let text = match text.strip_prefix('\n') {
+                    Some(rest) if ignore_newline => {
+                        // The text node starts after the newline, wherever the text was split.
+                        self.position += 1;
+                        rest
+                    }
+                    _ => &text,
+                };
+                if text.is_empty() {
+                    return;
+                }
+                if self.namespace(self.current_node()) == dom::Namespace::Html {
+                    self.reconstruct_active_formatting_elements();
+                }
+                self.insert_text(text);
+            }
+            Token::StartTag { name, attributes, self_closing } => {
+                if self.in_foreign_content(&name) {
+                    self.process_foreign_start_tag(name, attributes, self_closing);
+                } else if name == "svg" || name == "math" {
+                    self.reconstruct_active_formatting_elements();
+                    let namespace = if name == "svg" { dom::Namespace::Svg } else { dom::Namespace::MathMl };
+                    self.insert_foreign_element(namespace, name, attributes, self_closing);
+                } else {
+                    if self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+                        // `<div/>` doesn't close the <div>.
+                        self.error("'>'", format!("<{}/>", name));
+                    }
+                    self.process_start_tag(name, attributes);
+                }
+            }
+            Token::EndTag { name } => {
+                if self.namespace(self.current_node()) == dom::Namespace::Html {
+                    self.process_end_tag(&name);
+                } else {
+                    self.process_foreign_end_tag(&name);
+                }
+            }
+            Token::Comment(data) => {
+                let id = self.create_node(dom::NodeType::Comment(data));
+                let parent = self.current_node();
+                self.append(parent, id);
+            }
+            Token::Doctype(doctype) => {
+                // A doctype is only allowed at the very start of the document.
+                if self.nodes[0].children.is_empty() {
+                    let id = self.create_node(dom::NodeType::Doctype(doctype));
+                    self.append(0, id);
+                } else {
+                    self.error("doctype at the start of the document", "<!DOCTYPE>");
+                }
+            }
+            Token::Eof => {
+                // Elements left open at the end are closed, but most of them are errors.
+                for index in 1..self.open_elements.len() {
+                    let name = self.tag_name(self.open_elements[index]);
+                    if !IMPLIED_END_TAGS.contains(&name) && !["html", "body"].contains(&name) {
+                        let expected = format!("</{}>", name);
+                        self.error(expected, "end of input");
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+        Void elements like <br> and <img> are popped off the stack as soon as they are
+        inserted, so they never get children and never wait for an end tag. The
+        self-closing slash in `<br />` is allowed but not needed. On other HTML elements
+        browsers ignore it, so `<div />` opens a <div> just like `<div>` does.
+     */
+
+    fn process_start_tag(&mut self, name: String, attributes: dom::AttributeMap) {
+        match name.as_str() {
+            "style" | "script" | "noembed" | "noframes" => {
+                self.insert_element(name, attributes);
+                self.tokenizer_state = Some(State::RawText);
+            }
+            "xmp" | "iframe" => {
+                if name == "xmp" {
+                    self.close_p_element();
+                }
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(name, attributes);
+                self.tokenizer_state = Some(State::RawText);
+            }
+            "textarea" | "title" => {
+                self.ignore_newline = name == "textarea";
+                self.insert_element(name, attributes);
+                self.tokenizer_state = Some(State::RcData);
+            }
+            "plaintext" => {
+                self.close_p_element();
+                self.insert_element(name, attributes);
+                self.tokenizer_state = Some(State::PlainText);
+            }
+            "html" if self.open_elements.len() > 1 => {
+                // A second <html> tag only contributes attributes the first one lacks.
+                let root = self.open_elements[1];
+                if self.tag_name(root) != "html" {
+                    return;
+                }
+                if let dom::NodeType::Element(ref mut element) = self.nodes[root].node_type {
+                    for (key, value) in attributes {
+                        element.attributes.entry(key).or_insert(value);
+                    }
+                }
+            }
+            "li" | "dd" | "dt" => {
+                // A new list item closes the previous one.
+                let closes: &[&str] = if name == "li" { &["li"] } else { &["dd", "dt"] };
+                let mut open_item = None;
+                for &id in self.open_elements.iter().rev() {
+                    let open_name = self.tag_name(id);
+                    if closes.contains(&open_name) {
+                        open_item = Some(open_name.to_string());
+                        break;
+                    }
+                    if SPECIAL_ELEMENTS.contains(&open_name) && !["address", "div", "p"].contains(&open_name) {
+                        break;
+                    }
+                }
+                if let Some(open_name) = open_item {
+                    self.generate_implied_end_tags(&open_name);
+                    self.pop_until(&[&open_name]);
+                }
+                self.close_p_element();
+                self.insert_element(name, attributes);
+            }
+            n if HEADINGS.contains(&n) => {
+                self.close_p_element();
+                let current = self.tag_name(self.current_node());
+                if HEADINGS.contains(&current) {
+                    // Headings don't nest.
+                    let expected = format!("</{}>", current);
+                    self.error(expected, format!("<{}>", name));
+                    self.open_elements.pop();
+                }
+                self.insert_element(name, attributes);
+            }
+            n if CLOSES_P.contains(&n) => {
+                self.close_p_element();
+                // Like <textarea>, these ignore a newline right after the start tag.
+                self.ignore_newline = name == "pre" || name == "listing";
+                self.insert_element(name, attributes);
+            }
+            "a" => {
+                // An <a> inside another <a> closes the outer one first.
+                if let Some(id) = self.active_formatting_element("a") {
+                    self.error("</a>", "<a>");
+                    self.adoption_agency("a");
+                    self.remove_active_formatting(id);
+                    self.open_elements.retain(|&open| open != id);
+                }
+                self.reconstruct_active_formatting_elements();
+                let id = self.insert_element(name, attributes);
+                self.push_active_formatting(id);
+            }
+            "nobr" => {
+                self.reconstruct_active_formatting_elements();
+                if self.has_element_in_scope("nobr", &[]) {
+                    self.adoption_agency("nobr");
+                    self.reconstruct_active_formatting_elements();
+                }
+                let id = self.insert_element(name, attributes);
+                self.push_active_formatting(id);
+            }
+            n if FORMATTING_ELEMENTS.contains(&n) => {
+                self.reconstruct_active_formatting_elements();
+                let id = self.insert_element(name, attributes);
+                self.push_active_formatting(id);
+            }
+            "button" => {
+                if self.has_element_in_scope("button", &[]) {
+                    self.generate_implied_end_tags("");
+                    self.pop_until(&["button"]);
+                }
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(name, attributes);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(name, attributes);
+                self.active_formatting.push(Formatting::Marker);
+            }
+            "caption" | "col" | "colgroup" | "tbody" | "td" | "tfoot" | "th" | "thead" | "tr" => {
+                self.process_table_start_tag(name, attributes);
+            }
+            "hr" => {
+                self.close_p_element();
+                self.insert_void_element(name, attributes);
+            }
+            "image" => {
+                // Not a real element: browsers treat it as a misspelled <img>.
+                self.reconstruct_active_formatting_elements();
+                self.insert_void_element("img".to_string(), attributes);
+            }
+            n if VOID_ELEMENTS.contains(&n) => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_void_element(name, attributes);
+            }
+            _ => {
+                self.reconstruct_active_formatting_elements();
+                self.insert_element(name, attributes);
+            }
+        }
+    }
+
+    fn process_end_tag(&mut self, name: &str) {
+        match name {
+            // Without separate <head> and <body> handling, these never close anything.
+            "html" | "body" => {}
+            "br" => {
+                // Browsers treat a stray </br> as <br>.
+                self.stray_end_tag(name);
+                self.reconstruct_active_formatting_elements();
+                self.insert_void_element("br".to_string(), HashMap::new());
+            }
+            "p" => {
+                if !self.has_element_in_scope("p", &["button"]) {
+                    // A stray </p> creates an empty paragraph, as in browsers.
+                    self.stray_end_tag(name);
+                    self.insert_element("p".to_string(), HashMap::new());
+                }
+                self.close_elements(&["p"], "p", name);
+            }
+            "li" => {
+                if self.has_element_in_scope("li", &["ol", "ul"]) {
+                    self.close_elements(&["li"], "li", name);
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            "dd" | "dt" => {
+                if self.has_element_in_scope(name, &[]) {
+                    self.close_elements(&[name], name, name);
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            n if HEADINGS.contains(&n) => {
+                if self.has_any_in_scope(HEADINGS) {
+                    self.close_elements(HEADINGS, "", name);
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            n if BLOCK_END_TAGS.contains(&n) => {
+                if self.has_element_in_scope(name, &[]) {
+                    self.close_elements(&[name], "", name);
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            "applet" | "marquee" | "object" => {
+                if self.has_element_in_scope(name, &[]) {
+                    self.close_elements(&[name], "", name);
+                    self.clear_active_formatting_to_last_marker();
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            "caption" | "td" | "th" => {
+                if self.has_element_in_table_scope(name) {
+                    self.close_elements(&[name], "", name);
+                    self.clear_active_formatting_to_last_marker();
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            "colgroup" | "table" | "tbody" | "tfoot" | "thead" | "tr" => {
+                if self.has_element_in_table_scope(name) {
+                    self.close_table_parts(&[name]);
+                    self.open_elements.pop();
+                } else {
+                    self.stray_end_tag(name);
+                }
+            }
+            n if FORMATTING_ELEMENTS.contains(&n) => {
+                if !self.adoption_agency(name) {
+                    self.process_any_other_end_tag(name);
+                }
+            }
+            _ => self.process_any_other_end_tag(name),
+        }
+    }
+
+    /// Close the innermost open element named `name`, unless a special element is in the way.
+    fn process_any_other_end_tag(&mut self, name: &str) {
+        for index in (1..self.open_elements.len()).rev() {
+            let id = self.open_elements[index];
+            let open_name = self.tag_name(id);
+            if open_name == name {
+                self.generate_implied_end_tags(name);
+                self.check_current_node(&[name], name);
+                self.open_elements.truncate(index);
+                return;
+            }
+            if SPECIAL_ELEMENTS.contains(&open_name) || self.is_integration_point(id) {
+                break;
+            }
+        }
+        self.stray_end_tag(name);
+    }
+
+    /// Close everything up to an element named in `tag_names`, because of the end tag `end_tag`.
+    fn close_elements(&mut self, tag_names: &[&str], except: &str, end_tag: &str) {
+        self.generate_implied_end_tags(except);
+        self.check_current_node(tag_names, end_tag);
+        self.pop_until(tag_names);
+    }
+
+    /// Report an error if the end tag `end_tag` is about to close other elements too.
+    fn check_current_node(&mut self, tag_names: &[&str], end_tag: &str) {
+        let current = self.tag_name(self.current_node());
+        if !tag_names.contains(&current) {
+            let expected = format!("</{}>", current);
+            self.error(expected, format!("</{}>", end_tag));
+        }
+    }
+
+    /// Report an end tag that doesn't match any open element, and is ignored.
+    fn stray_end_tag(&mut self, name: &str) {
+        self.error(format!("an open <{}> element", name), format!("</{}>", name));
+    }
+
+    /// Record a parse error for the current token.
+    fn error(&mut self, expected: impl Into<String>, found: impl Into<String>) {
+        self.errors.push(PendingError::new(self.position, expected, found));
+    }
+
+    /*
+        There is no separate mode for tables: sections, rows and cells are handled by
+        closing whatever table parts are open until there is a suitable parent, and
+        creating the parents that were left out. Text and other elements directly
+        inside a table, which browsers move out in front of it, are left where they are.
+
+        e.g.
+            <table><td>a<td>b<tr><td>c</table> =>
+                <table><tbody><tr><td>a</td><td>b</td></tr><tr><td>c</td></tr></tbody></table>
+     */
+
+    /// Insert a part of a table, closing the parts it ends and creating the parents it needs.
+    fn process_table_start_tag(&mut self, name: String, attributes: dom::AttributeMap) {
+        let parents: &[&str] = match name.as_str() {
+            "td" | "th" => &["table", "tbody", "tfoot", "thead", "tr"],
+            "tr" => &["table", "tbody", "tfoot", "thead"],
+            "col" => &["colgroup", "table"],
+            _ => &["table"],
+        };
+        if !self.close_table_parts(parents) {
+            // Not inside a table, so there is nothing this could be part of.
+            self.error("an open <table> element", format!("<{}>", name));
+            return;
+        }
+
+        if self.context_tag_name(self.current_node()) == "table" {
+            match name.as_str() {
+                "td" | "th" | "tr" => {
+                    self.insert_element("tbody".to_string(), HashMap::new());
+                }
+                "col" => {
+                    self.insert_element("colgroup".to_string(), HashMap::new());
+                }
+                _ => {}
+            }
+        }
+        if (name == "td" || name == "th") && self.context_tag_name(self.current_node()) != "tr" {
+            self.insert_element("tr".to_string(), HashMap::new());
+        }
+
+        match name.as_str() {
+            "col" => self.insert_void_element(name, attributes),
+            "caption" | "td" | "th" => {
+                self.insert_element(name, attributes);
+                self.active_formatting.push(Formatting::Marker);
+            }
+            _ => {
+                self.insert_element(name, attributes);
+            }
+        }
+    }
+
+    /// Close open elements until the current node is one of `parents`, without leaving the table.
+    /// Returns false, closing nothing, if there is no such element in the table.
+    fn close_table_parts(&mut self, parents: &[&str]) -> bool {
+        let mut index = self.open_elements.len() - 1;
+        loop {
+            let name = self.context_tag_name(self.open_elements[index]);
+            if parents.contains(&name) {
+                break;
+            }
+            if index <= 1 || TABLE_SCOPE_BOUNDARIES.contains(&name) {
+                return false;
+            }
+            index -= 1;
+        }
+        while self.open_elements.len() > index + 1 {
+            let id = self.open_elements.pop().unwrap();
+            if ["caption", "td", "th"].contains(&self.tag_name(id)) {
+                self.clear_active_formatting_to_last_marker();
+            }
+        }
+        true
+    }
+
+    /*
+        Inside `<svg>` and `<math>`, elements are "foreign content" and follow simpler,
+        XML-like rules: any element can be self-closing, and an end tag closes the
+        matching element. Tag names are lower-cased by the tokenizer, so SVG names
+        like "foreignObject" get their capital letters back here.
+
+        Some HTML elements can't appear in foreign content. They "break out": the
+        foreign elements are closed, and the tag is handled as HTML.
+
+        A few foreign elements, like SVG's `<foreignObject>`, are "integration points"
+        whose contents are HTML again.
+
+        e.g.
+            <svg viewbox="0 0 8 8"><circle r=4 /><p>x</svg>  =>
+                <svg viewBox="0 0 8 8"><circle r="4"></circle></svg><p>x</p>
+     */
+
+    /// The namespace of node `id`.
+    fn namespace(&self, id: usize) -> dom::Namespace {
+        match self.nodes[id].node_type {
+            dom::NodeType::Element(ref element) => element.namespace,
+            _ => dom::Namespace::Html,
+        }
+    }
+
+    /// Is node `id` a foreign element whose contents are parsed as HTML?
+    fn is_integration_point(&self, id: usize) -> bool {
+        let name = self.tag_name(id);
+        match self.namespace(id) {
+            dom::Namespace::Svg => ["desc", "foreignObject", "title"].contains(&name),
+            dom::Namespace::MathMl if name == "annotation-xml" => match self.nodes[id].node_type {
+                // <annotation-xml> only holds HTML if its encoding says so.
+                dom::NodeType::Element(ref element) => element.attributes.get("encoding").is_some_and(|encoding| {
+                    encoding.eq_ignore_ascii_case("text/html") || encoding.eq_ignore_ascii_case("application/xhtml+xml")
+                }),
+                _ => false,
+            },
+            dom::Namespace::MathMl => MATHML_TEXT_INTEGRATION_POINTS.contains(&name),
+            _ => false,
+        }
+    }
+
+    /// Should a start tag named `name` be handled with the foreign content rules?
+    fn in_foreign_content(&self, name: &str) -> bool {
+        let current = self.current_node();
+        match self.namespace(current) {
+            dom::Namespace::Html => false,
+            dom::Namespace::MathMl if self.tag_name(current) == "annotation-xml" => {
+                !self.is_integration_point(current) && name != "svg"
+            }
+            dom::Namespace::MathMl if self.is_integration_point(current) => {
+                name == "mglyph" || name == "malignmark"
+            }
+            _ => !self.is_integration_point(current),
+        }
+    }
+
+    fn process_foreign_start_tag(&mut self, name: String, attributes: dom::AttributeMap, self_closing: bool) {
+        let is_font_breakout = name == "font" && ["color", "face", "size"].iter().any(|a| attributes.contains_key(*a));
+        if BREAKOUT_ELEMENTS.contains(&name.as_str()) || is_font_breakout {
+            self.error(format!("</{}>", self.context_tag_name(self.current_node())), format!("<{}>", name));
+            while self.open_elements.len() > self.first_closable() {
+                let current = self.current_node();
+                if self.namespace(current) == dom::Namespace::Html || self.is_integration_point(current) {
+                    break;
+                }
+                self.open_elements.pop();
+            }
+            self.process_start_tag(name, attributes);
+            return;
+        }
+        let namespace = self.namespace(self.current_node());
+        self.insert_foreign_element(namespace, name, attributes, self_closing);
+    }
+
+    /// Insert an SVG or MathML element, fixing the case of its tag and attribute names.
+    fn insert_foreign_element(&mut self, namespace: dom::Namespace, name: String, attributes: dom::AttributeMap, self_closing: bool) {
+        let (name, attributes) = match namespace {
+            dom::Namespace::Svg => {
+                let attributes = attributes.into_iter().map(|(key, value)| (adjust_case(SVG_ATTRIBUTES, key), value)).collect();
+                (adjust_case(SVG_TAG_NAMES, name), attributes)
+            }
+            _ => {
+                let attributes = attributes.into_iter().map(|(key, value)| (adjust_case(&["definitionURL"], key), value)).collect();
+                (name, attributes)
+            }
+        };
+        self.insert_element_ns(namespace, name, attributes);
+        if self_closing {
+            self.open_elements.pop();
+        }
+    }
+
+    /// Close the innermost foreign element matching an end tag, or hand it to the HTML rules
+    /// if an HTML element comes first.
+    fn process_foreign_end_tag(&mut self, name: &str) {
+        if !self.tag_name(self.current_node()).eq_ignore_ascii_case(name) {
+            let expected = format!("</{}>", self.context_tag_name(self.current_node()));
+            self.error(expected, format!("</{}>", name));
+        }
+        for index in (self.first_closable()..self.open_elements.len()).rev() {
+            let id = self.open_elements[index];
+            if self.namespace(id) == dom::Namespace::Html {
+                self.process_end_tag(name);
+                return;
+            }
+            if self.tag_name(id).eq_ignore_ascii_case(name) {
+                self.open_elements.truncate(index);
+                return;
+            }
+        }
+    }
+
+    /// The index of the first open element that foreign content can close. When parsing
+    /// a fragment, the root element stands in for the context element, so it stays open.
+    fn first_closable(&self) -> usize {
+        if self.fragment_context.is_some() { 2 } else { 1 }
+    }
+
+    /// The tag name of node `id`. When parsing a fragment, the root element
+    /// takes the name of the context element, since it stands in for it.
+    fn context_tag_name(&self, id: usize) -> &str {
+        match self.fragment_context {
+            Some(ref context) if self.open_elements.get(1) == Some(&id) => context,
+            _ => self.tag_name(id),
+        }
+    }
+
+    fn clear_active_formatting_to_last_marker(&mut self) {
+        while let Some(entry) = self.active_formatting.pop() {
+            if entry == Formatting::Marker {
+                break;
+            }
+        }
+    }
+
+
+    /*
+        When all tokens have been processed, the working tree is turned back into
+        a tree of `dom::Node`s. Any elements that are still open are simply closed.
+     */
+
+    /// Copy the tree built so far, leaving the working tree as it is.
+    fn snapshot(&self) -> Vec<dom::Node> {
+        self.nodes[0].children.iter().map(|&id| self.copy_node(id)).collect()
+    }
+
+    /// Recursively copy node `id` and its descendants out of the working tree.
+    fn copy_node(&self, id: usize) -> dom::Node {
+        let node = &self.nodes[id];
+        dom::Node {
+            node_type: node.node_type.clone(),
+            children: node.children.iter().map(|&child| self.copy_node(child)).collect(),
+            span: Some(node.span),
+        }
+    }
+
+    /// Convert the working tree into DOM nodes, returning the top-level nodes.
+    fn finish(self) -> Vec<dom::Node> {
+        let mut nodes: Vec<Option<TreeNode>> = self.nodes.into_iter().map(Some).collect();
+        let top_level = nodes[0].take().unwrap().children;
+        top_level.into_iter().map(|id| build_node(&mut nodes, id)).collect()
+    }
+}
+
+/// Recursively move node `id` and its descendants out of the working tree.
+fn build_node(nodes: &mut [Option<TreeNode>], id: usize) -> dom::Node {
+    let node = nodes[id].take().unwrap();
+    dom::Node {
+        node_type: node.node_type,
+        children: node.children.into_iter().map(|child| build_node(nodes, child)).collect(),
+        span: Some(node.span),
+    }
+}
+
+
+/// Is `tag_name` a void element, which has no content and no end tag?
+pub fn is_void_element(tag_name: &str) -> bool {
+    VOID_ELEMENTS.contains(&tag_name)
+}
+
+
+/*
+    This function will create a root node for the document if it doesn't
+    include one explicitly; this is similar to what a real HTML parser does.
+
+    A `dom::Node` tree has a single root, so `parse` leaves out the doctype and any
+    comments before or after the root element. `parse_top_level` keeps them, as
+    siblings of the root element.
+
+    e.g.
+        parse_top_level("<!DOCTYPE html><!-- a --><p>b</p>")
+            => [<!DOCTYPE html>, <!-- a -->, <p>b</p>]
+ */
+
+/// Parse an HTML document and return the root element.
+pub fn parse(source: String) -> dom::Node {
+    parse_document(source).0
+}
+
+/// Parse an HTML document and return the root element, with the doctype and comments around it.
+pub fn parse_top_level(source: String) -> Vec<dom::Node> {
+    let mut parser = StreamingParser::new();
+    parser.tokenizer.input = source;
+    parser.run(true);
+    top_level_nodes(parser.builder.finish())
+}
+
+/*
+    Pages saved to disk are just bytes, in whatever encoding they were written in.
+    The encoding comes from a byte order mark or a `<meta charset>` if there is one,
+    and otherwise from `default_encoding`, which is usually windows-1252 for old
+    western pages. See the `encoding` module for the details.
+
+    e.g.
+        parse_bytes(b"<meta charset=shift_jis><p>\x82\xa0", Encoding::Windows1252) => <p>あ</p>
+ */
+
+/// Parse an HTML document from raw bytes, detecting its encoding.
+pub fn parse_bytes(bytes: &[u8], default_encoding: Encoding) -> dom::Node {
+    let (source, _) = encoding::decode_document(bytes, default_encoding);
+    parse(source)
+}
+
+/*
+    HTML parse errors are never fatal: the parser always recovers, so this never
+    returns `Err`. It has the same signature as `css::try_parse` so that callers can
+    treat both parsers the same way. Every error that was recovered from is
+    returned as a warning.
+ */
+
+/// Parse an HTML document and return the root element, with a warning for each parse error.
+pub fn try_parse(source: String) -> Result<Parsed<dom::Node>, ParseError> {
+    let (root, warnings) = parse_document(source);
+    Ok(Parsed { value: root, warnings })
+}
+
+/// Parse an HTML document, returning the root element and any parse errors.
+fn parse_document(source: String) -> (dom::Node, Vec<ParseError>) {
+    let mut parser = StreamingParser::new();
+    parser.tokenizer.input = source;
+    parser.finish_with_errors()
+}
+
+/// Is `node` a doctype or comment, which can be outside the root element?
+fn is_outside_root(node: &dom::Node) -> bool {
+    matches!(node.node_type, dom::NodeType::Doctype(_) | dom::NodeType::Comment(_))
+}
+
+/// Turn the top-level nodes of a document into a single root node, with the
+/// doctype and comments that come before and after it.
+fn top_level_nodes(mut nodes: Vec<dom::Node>) -> Vec<dom::Node> {
+    let first = nodes.iter().position(|node| !is_outside_root(node)).unwrap_or(nodes.len());
+    let last = nodes.iter().rposition(|node| !is_outside_root(node)).map_or(first, |index| index + 1);
+    let after = nodes.split_off(last);
+    let mut content = nodes.split_off(first);
+
+    // If the document contains a root element, just use it. Otherwise, create one.
+    let root = if content.len() == 1 {
+        content.remove(0)
+    } else {
+        dom::element("html".to_string(), HashMap::new(), content)
+    };
+    nodes.push(root);
+    nodes.extend(after);
+    nodes
+}
+
+/// Turn the top-level nodes of a document into a single root node.
+fn document_root(nodes: Vec<dom::Node>) -> dom::Node {
+    top_level_nodes(nodes).into_iter().find(|node| !is_outside_root(node)).unwrap()
+}
+
+
+/*
+    A fragment is parsed as the contents of a context element, the way `innerHTML`
+    is in a browser. The context decides how the tokenizer starts (the contents of
+    a `<textarea>` are text, not markup) and which parents are implied (rows in a
+    `<table>` get a `<tbody>`, but rows in a `<tbody>` don't). The context element
+    itself isn't part of the result. In an `<svg>` or `<math>` context, the fragment
+    is foreign content from the start.
+
+    e.g.
+        parse_fragment("table", "<tr><td>1")   => [<tbody><tr><td>1</td></tr></tbody>]
+        parse_fragment("ul", "<li>a<li>b")     => [<li>a</li>, <li>b</li>]
+        parse_fragment("svg", "<rect/>")       => [<rect></rect>] (in the SVG namespace)
+ */
+
+/// Parse `source` as the children of a `context` element.
+pub fn parse_fragment(context: &str, source: String) -> Vec<dom::Node> {
+    let context = context.to_ascii_lowercase();
+    let mut tokenizer = Tokenizer::new(source);
+    // The tokenizer has no last start tag yet, so in a <style> fragment, `</style>` is just text.
+    tokenizer.state = match context.as_str() {
+        "textarea" | "title" => State::RcData,
+        "iframe" | "noembed" | "noframes" | "script" | "style" | "xmp" => State::RawText,
+        "plaintext" => State::PlainText,
+        _ => State::Data,
+    };
+
+    let namespace = match context.as_str() {
+        "svg" => dom::Namespace::Svg,
+        "math" => dom::Namespace::MathMl,
+        _ => dom::Namespace::Html,
+    };
+    tokenizer.cdata_allowed = namespace != dom::Namespace::Html;
+
+    let mut builder = TreeBuilder::new();
+    builder.insert_element_ns(namespace, "html".to_string(), HashMap::new());
+    builder.fragment_context = Some(context);
+
+    let mut parser = StreamingParser { tokenizer, builder };
+    parser.run(true);
+    let mut nodes = parser.builder.finish();
+    nodes.remove(0).children
+}
+
+
+/*
+    A streaming parser takes the document in chunks, as they arrive from a file or the
+    network, and builds as much of the DOM as it can after each one. `snapshot` returns
+    a copy of the DOM built so far, with every element that is still open cut off at
+    the end of the input received so far, so it can be rendered straight away.
+
+    A token that is cut off by the end of a chunk might continue in the next one, like
+    `<di` or `&am`, so it is put back until more input arrives, or until `finish`
+    says there is no more. Text before it is passed on straight away, so a long run of
+    text arriving in small chunks is only read once.
+
+    e.g.
+        let mut parser = StreamingParser::new();
+        parser.feed("<ul><li>one<li>t");
+        parser.snapshot()                => <ul><li>one</li><li></li></ul>
+        parser.feed("wo</ul>");
+        parser.finish()                  => <ul><li>one</li><li>two</li></ul>
+ */
+
+/// An HTML parser that is given its input a chunk at a time.
+pub struct StreamingParser {
+    tokenizer: Tokenizer,
+    builder: TreeBuilder,
+}
+
+impl StreamingParser {
+    pub fn new() -> StreamingParser {
+        StreamingParser {
+            tokenizer: Tokenizer::new(String::new()),
+            builder: TreeBuilder::new(),
+        }
+    }
+
+    /// Add the next chunk of the document, and parse as much of it as possible.
+    pub fn feed(&mut self, chunk: &str) {
+        self.tokenizer.input.push_str(chunk);
+        self.run(false);
+    }
+
+    /// Return the DOM built from the input so far.
+    pub fn snapshot(&self) -> dom::Node {
+        document_root(self.builder.snapshot())
+    }
+
+    /// Parse whatever input is left, and return the finished DOM.
+    pub fn finish(self) -> dom::Node {
+        self.finish_with_errors().0
+    }
+
+    fn finish_with_errors(mut self) -> (dom::Node, Vec<ParseError>) {
+        self.run(true);
+        let mut errors = std::mem::take(&mut self.tokenizer.errors);
+        errors.append(&mut self.builder.errors);
+        let errors = error::locate(&self.tokenizer.input, errors);
+        (document_root(self.builder.finish()), errors)
+    }
+
+    /// Pass tokens to the tree builder until the input runs out.
+    /// Unless this is the end of the document, a token that was cut off by the end of the input is put back.
+    fn run(&mut self, at_end: bool) {
+        loop {
+            let start = self.tokenizer.position;
+            let state = self.tokenizer.state;
+            let last_start_tag = self.tokenizer.last_start_tag.clone();
+            let error_count = self.tokenizer.errors.len();
+
+            let token = self.tokenizer.next_token();
+            let end = self.tokenizer.position;
+            if !at_end && (self.tokenizer.ran_out || matches!(token, Token::Eof)) {
+                // Wait for the rest of the token.
+                self.tokenizer.position = start;
+                self.tokenizer.state = state;
+                self.tokenizer.last_start_tag = last_start_tag;
+                self.tokenizer.errors.truncate(error_count);
+                return;
+            }
+
+            let is_eof = matches!(token, Token::Eof);
+            self.builder.position = start;
+            self.builder.token_end = end;
+            self.builder.process_token(token);
+            if is_eof {
+                return;
+            }
+            let current = self.builder.current_node();
+            self.tokenizer.cdata_allowed = self.builder.namespace(current) != dom::Namespace::Html;
+            if let Some(state) = self.builder.tokenizer_state.take() {
+                self.tokenizer.state = state;
+            }
+        }
+    }
+}
+
+impl Default for StreamingParser {
+    fn default() -> StreamingParser {
+        StreamingParser::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `source` and serialize the result, which is the easiest way to compare trees.
+    fn html(source: &str) -> String {
+        parse(source.to_string()).outer_html()
+    }
+
+    /// The text in `source`, as the parser decoded it.
+    fn text(source: &str) -> String {
+        fn collect(node: &dom::Node, out: &mut String) {
+            if let dom::NodeType::Text(ref text) = node.node_type {
+                out.push_str(text);
+            }
+            for child in &node.children {
+                collect(child, out);
+            }
+        }
+        let mut out = String::new();
+        collect(&parse(source.to_string()), &mut out);
+        out
+    }
+
+    /// The messages of the parse errors in `source`.
+    fn errors(source: &str) -> Vec<String> {
+        try_parse(source.to_string()).unwrap().warnings.iter().map(|error| error.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_nested_elements_and_attributes() {
+        assert_eq!(html("<div id=\"main\" class=\"test\"><p>Hello <em>world</em>!</p></div>"),
+                   "<div class=\"test\" id=\"main\"><p>Hello <em>world</em>!</p></div>");
+        assert!(errors("<div><p>Hello</p></div>").is_empty());
+    }
+
+    #[test]
+    fn implies_end_tags() {
+        assert_eq!(html("<ul><li>a<li>b</ul>"), "<ul><li>a</li><li>b</li></ul>");
+        assert_eq!(html("<p>a<div>b</div>"), "<html><p>a</p><div>b</div></html>");
+        assert_eq!(html("<h1>a<h2>b"), "<html><h1>a</h1><h2>b</h2></html>");
+    }
+
+    #[test]
+    fn fixes_misnested_formatting_elements() {
+        assert_eq!(html("<b>1<p>2</b>3</p>"), "<html><b>1</b><p><b>2</b>3</p></html>");
+        assert_eq!(html("<b><i>a</b>b</i>"), "<html><b><i>a</i></b><i>b</i></html>");
+        assert_eq!(html("<a href=\"x\">1<a>2"), "<html><a href=\"x\">1</a><a>2</a></html>");
+    }
+
+    #[test]
+    fn recovers_from_unmatched_end_tags() {
+        assert_eq!(html("a</p>b"), "<html>a<p></p>b</html>");
+        assert_eq!(html("<div>a</span>b</div>"), "<div>ab</div>");
+        assert_eq!(errors("<div>a</span>b</div>").len(), 1);
+    }
+
+    #[test]
+    fn reports_recovered_errors_with_locations() {
+        assert_eq!(errors("a</p>b"), ["line 1, column 2: expected an open <p> element but found </p>"]);
+        assert_eq!(errors("<div>\n<span>"), [
+            "line 2, column 7: expected </div> but found end of input",
+            "line 2, column 7: expected </span> but found end of input",
+        ]);
+        // Columns count characters, not bytes.
+        assert_eq!(errors("<b>\u{e9}\u{e9}<span/></span></b>"), ["line 1, column 6: expected '>' but found <span/>"]);
+        assert!(errors("<br/>").is_empty());
+    }
+
+    #[test]
+    fn treats_a_lone_less_than_sign_as_text() {
+        assert_eq!(html("<p>1 < 2</p>"), "<p>1 &lt; 2</p>");
+    }
+
+    #[test]
+    fn parses_comments_doctypes_and_processing_instructions() {
+        assert_eq!(html("<p>a<!-- note -->b</p>"), "<p>a<!-- note -->b</p>");
+        assert_eq!(html("<p><?xml version=\"1.0\"?></p>"), "<p><!--?xml version=\"1.0\"?--></p>");
+        assert_eq!(html("<!DOCTYPE html><p>a</p>"), "<p>a</p>");
+    }
+
+    #[test]
+    fn keeps_the_doctype_and_comments_around_the_root() {
+        let nodes = parse_top_level("<!DOCTYPE html>\n<!-- a --><html><body>x</body></html><!-- b -->".to_string());
+        let serialized: Vec<String> = nodes.iter().map(dom::Node::outer_html).collect();
+        assert_eq!(serialized, ["<!DOCTYPE html>", "<!-- a -->", "<html><body>x<!-- b --></body></html>"]);
+
+        let nodes = parse_top_level("<!-- a -->text<p>b</p><!-- c -->".to_string());
+        let serialized: Vec<String> = nodes.iter().map(dom::Node::outer_html).collect();
+        assert_eq!(serialized, ["<!-- a -->", "<html>text<p>b</p></html>", "<!-- c -->"]);
+    }
+
+    #[test]
+    fn void_elements_never_have_children() {
+        let root = parse("<p><br/><img src=\"x\">a<input/>b</p>".to_string());
+        assert_eq!(root.outer_html(), "<p><br><img src=\"x\">a<input>b</p>");
+        assert_eq!(root.children.len(), 5);
+        assert!(root.children.iter().all(|child| child.children.is_empty()));
+
+        // The slash means nothing on other elements, so the <div> still contains the text.
+        assert_eq!(html("<div/>a</div>b"), "<html><div>a</div>b</html>");
+        assert_eq!(html("<br></br>"), "<html><br><br></html>");
+    }
+
+    #[test]
+    fn decodes_character_references() {
+        assert_eq!(text("<p>&lt;&amp;&gt; &quot;&copy;&quot;</p>"), "<&> \"\u{A9}\"");
+        assert_eq!(text("<p>&#65;&#x42;&#X43;</p>"), "ABC");
+        assert_eq!(text("<p>&bogus; & &#;</p>"), "&bogus; & &#;");
+    }
+
+    #[test]
+    fn decodes_legacy_references_without_a_semicolon() {
+        // The longest match wins, so "&notit;" is "&not" followed by "it;".
+        assert_eq!(text("<p>&notit;</p>"), "\u{AC}it;");
+        assert_eq!(text("<p>a &amp b</p>"), "a & b");
+        // In attributes, a legacy reference followed by "=" or a letter is left alone.
+        assert_eq!(html("<a href=\"?a=1&copy=2\">x</a>"), "<a href=\"?a=1&amp;copy=2\">x</a>");
+        assert_eq!(html("<a title=\"&copy 2024\">x</a>"), "<a title=\"\u{A9} 2024\">x</a>");
+    }
+
+    #[test]
+    fn replaces_bad_numeric_references() {
+        // 0x80 to 0x9F are read as windows-1252, like browsers do.
+        assert_eq!(text("<p>&#x80;&#150;</p>"), "\u{20AC}\u{2013}");
+        assert_eq!(text("<p>&#0;</p>"), "\u{FFFD}");
+        assert_eq!(text("<p>&#x110000;&#xD800;</p>"), "\u{FFFD}\u{FFFD}");
+        assert_eq!(text("<p>&#99999999999999;</p>"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn parses_unquoted_boolean_and_duplicate_attributes() {
+        assert_eq!(html("<div a b=1 a=2 c></div>"), "<div a=\"\" b=\"1\" c=\"\"></div>");
+        assert_eq!(html("<DIV ID=Main Class='x y'></DIV>"), "<div class=\"x y\" id=\"Main\"></div>");
+        assert_eq!(html("<a href=?a=1&amp;b=2>x</a>"), "<a href=\"?a=1&amp;b=2\">x</a>");
+    }
+
+    #[test]
+    fn reads_raw_text_elements_up_to_their_end_tag() {
+        let root = parse("<script>if (a<b) </div></script>".to_string());
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(root.outer_html(), "<script>if (a<b) </div></script>");
+        assert_eq!(html("<style>p > a { color: red }</STYLE>"), "<style>p > a { color: red }</style>");
+        assert_eq!(text("<title><b>x</b></title>"), "<b>x</b>");
+    }
+
+    #[test]
+    fn decodes_references_in_textarea_and_title_only() {
+        assert_eq!(text("<textarea>&lt;b&gt;</textarea>"), "<b>");
+        assert_eq!(text("<title>a &amp; b</title>"), "a & b");
+        assert_eq!(text("<script>a &amp;&amp; b</script>"), "a &amp;&amp; b");
+        assert_eq!(text("<style>a::after { content: \"&lt;\" }</style>"), "a::after { content: \"&lt;\" }");
+    }
+
+    #[test]
+    fn parses_bytes_in_the_encoding_they_name() {
+        let root = parse_bytes(b"<p><meta charset=shift_jis>\x82\xa0</p>", Encoding::Windows1252);
+        assert_eq!(root.outer_html(), "<p><meta charset=\"shift_jis\">\u{3042}</p>");
+        let root = parse_bytes(b"<p>caf\xe9</p>", Encoding::Windows1252);
+        assert_eq!(root.outer_html(), "<p>caf\u{E9}</p>");
+    }
+
+    /// Feed `source` to a streaming parser one character at a time.
+    fn parse_in_pieces(source: &str) -> dom::Node {
+        let mut parser = StreamingParser::new();
+        let mut buffer = [0; 4];
+        for c in source.chars() {
+            parser.feed(c.encode_utf8(&mut buffer));
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn streaming_byte_by_byte_matches_parse() {
+        let documents = [
+            "<!DOCTYPE html><html><head><title>A &amp; B</title></head><body><p class=x>Hi</p></body></html>",
+            "<p>1 &lt; 2 &amp 3 &#x27;4&#39; &copy; &notit; &#</p><p>a<!-- c -- d --></p><?pi?><!x>",
+            "<div id=\"a&amp;b\" hidden>x</div><b>1<p>2</b>3</p><table><tr><td>1</table>",
+            "<script>if (a </s && b) {}</script><style>p { color: red }</STYLE><textarea>\n&lt;x</textarea>",
+            "<svg><title>t</title><![CDATA[a<b]]><rect/></svg><math><mi>x</mi></math>",
+            "<ul><li>a<li>b</ul><pre>\nline</pre><p>caf\u{e9} \u{1F600}</p>",
+            "<p>unclosed <b>tag <i",
+        ];
+        for document in documents {
+            assert_eq!(parse_in_pieces(document).outer_html(), html(document), "{}", document);
+        }
+    }
+
+    #[test]
+    fn streaming_passes_on_finished_text() {
+        let mut parser = StreamingParser::new();
+        parser.feed("<p>hello");
+        assert_eq!(parser.snapshot().outer_html(), "<p>hello</p>");
+        assert_eq!(parser.tokenizer.position, "<p>hello".len());
+
+        // A reference that could still get longer is held back, but not the text before it.
+        parser.feed(" &am");
+        assert_eq!(parser.snapshot().outer_html(), "<p>hello </p>");
+        parser.feed("p; world");
+        assert_eq!(parser.snapshot().outer_html(), "<p>hello &amp; world</p>");
+    }
+
+    #[test]
+    fn streaming_passes_on_a_finished_end_tag() {
+        let mut parser = StreamingParser::new();
+        parser.feed("<div><p>a</p>");
+        assert_eq!(parser.tokenizer.position, "<div><p>a</p>".len());
+        let snapshot = parser.snapshot();
+        assert_eq!(snapshot.outer_html(), "<div><p>a</p></div>");
+        assert_eq!(snapshot.children[0].span, Some(dom::Span { start: 5, end: 13 }));
+        parser.feed("b</div>");
+        assert_eq!(parser.finish().outer_html(), "<div><p>a</p>b</div>");
+    }
+
+    #[test]
+    fn streaming_holds_back_an_unfinished_end_tag_in_raw_text() {
+        let mut parser = StreamingParser::new();
+        parser.feed("<style>a</sty");
+        assert_eq!(parser.snapshot().outer_html(), "<style>a</style>");
+        parser.feed("le>b");
+        assert_eq!(parser.finish().outer_html(), "<html><style>a</style>b</html>");
+    }
+
+    /// Parse a fragment and serialize each of the top-level nodes.
+    fn fragment(context: &str, source: &str) -> Vec<String> {
+        parse_fragment(context, source.to_string()).iter().map(|node| node.outer_html()).collect()
+    }
+
+    #[test]
+    fn fragments_imply_parents_from_their_context() {
+        assert_eq!(fragment("table", "<tr><td>1"), ["<tbody><tr><td>1</td></tr></tbody>"]);
+        assert_eq!(fragment("tbody", "<tr><td>1"), ["<tr><td>1</td></tr>"]);
+        assert_eq!(fragment("tr", "<td>1<td>2"), ["<td>1</td>", "<td>2</td>"]);
+        assert_eq!(fragment("ul", "<li>a<li>b"), ["<li>a</li>", "<li>b</li>"]);
+        assert_eq!(fragment("DIV", "a<b>c"), ["a", "<b>c</b>"]);
+    }
+
+    #[test]
+    fn fragments_in_raw_text_contexts_are_text() {
+        let nodes = parse_fragment("style", "a</style>b".to_string());
+        assert_eq!(nodes.len(), 1);
+        assert!(matches!(nodes[0].node_type, dom::NodeType::Text(ref text) if text == "a</style>b"));
+
+        let nodes = parse_fragment("textarea", "&lt;b&gt;</textarea>x".to_string());
+        assert!(matches!(nodes[0].node_type, dom::NodeType::Text(ref text) if text == "<b></textarea>x"));
+        assert_eq!(fragment("title", "<b>x</b>"), ["&lt;b&gt;x&lt;/b&gt;"]);
+    }
+
+    /// Describe each element in `node` in document order, with the namespaces of it and its attributes.
+    fn elements(node: &dom::Node) -> Vec<String> {
+        let mut result = Vec::new();
+        if let dom::NodeType::Element(ref element) = node.node_type {
+            let mut attributes: Vec<String> = element.attributes.keys()
+                .map(|name| format!(" {}={:?}", name, element.attribute_namespace(name)))
+                .collect();
+            attributes.sort();
+            result.push(format!("{} {:?}{}", element.tag_name, element.namespace, attributes.concat()));
+        }
+        for child in &node.children {
+            result.extend(elements(child));
+        }
+        result
+    }
+
+    #[test]
+    fn parses_svg_and_mathml_in_their_namespaces() {
+        let root = parse("<svg viewbox='0 0 1 1'><lineargradient/><foreignObject><p>x</p></foreignObject></svg>".to_string());
+        assert_eq!(elements(&root), [
+            "svg Svg viewBox=None",
+            "linearGradient Svg",
+            "foreignObject Svg",
+            "p Html",
+        ]);
+        let root = parse("<math><mi>x</mi><annotation-xml encoding='text/html'><div>y</div></annotation-xml></math>".to_string());
+        assert_eq!(elements(&root), [
+            "math MathMl",
+            "mi MathMl",
+            "annotation-xml MathMl encoding=None",
+            "div Html",
+        ]);
+        let root = parse("<svg><use xlink:href='#a' XML:lang=en /></svg>".to_string());
+        assert_eq!(elements(&root), ["svg Svg", "use Svg xlink:href=Some(XLink) xml:lang=Some(Xml)"]);
+    }
+
+    #[test]
+    fn foreign_content_follows_xml_like_rules() {
+        // Self-closing tags close, and CDATA sections are text.
+        assert_eq!(html("<svg><rect/><circle></circle><![CDATA[a<b]]></svg>"),
+                   "<svg><rect></rect><circle></circle>a&lt;b</svg>");
+        // Outside foreign content, CDATA is a bogus comment.
+        assert_eq!(html("<p><![CDATA[x]]></p>"), "<p><!--[CDATA[x]]--></p>");
+        // HTML elements like <p> break out of foreign content.
+        assert_eq!(html("<svg><p>break</p>"), "<html><svg></svg><p>break</p></html>");
+    }
+
+    #[test]
+    fn fragments_in_svg_and_math_are_foreign_content() {
+        let nodes = parse_fragment("svg", "<lineargradient/><![CDATA[a<b]]><foreignObject><p>x</p></foreignObject>".to_string());
+        let described: Vec<String> = nodes.iter().flat_map(elements).collect();
+        assert_eq!(described, ["linearGradient Svg", "foreignObject Svg", "p Html"]);
+        assert_eq!(fragment("svg", "<rect/>a<![CDATA[<b>]]>"), ["<rect></rect>", "a&lt;b&gt;"]);
+
+        let nodes = parse_fragment("MATH", "<mi>x</mi><mo>+</mo>".to_string());
+        let described: Vec<String> = nodes.iter().flat_map(elements).collect();
+        assert_eq!(described, ["mi MathMl", "mo MathMl"]);
+
+        // An end tag for the context element is ignored, and a breakout element
+        // is HTML, but stays inside the fragment.
+        let nodes = parse_fragment("svg", "<g></svg><circle/></g><p>x</p>".to_string());
+        let described: Vec<String> = nodes.iter().flat_map(elements).collect();
+        assert_eq!(described, ["g Svg", "circle Svg", "p Html"]);
+    }
+
+    /// The source text covered by each node in `node`, in document order.
+    fn spans<'a>(node: &dom::Node, source: &'a str) -> Vec<&'a str> {
+        let mut result: Vec<&str> = node.span.iter().map(|span| &source[span.start..span.end]).collect();
+        for child in &node.children {
+            result.extend(spans(child, source));
+        }
+        result
+    }
+
+    #[test]
+    fn records_source_spans() {
+        let source = "<p>hi <b>there</b></p>";
+        assert_eq!(spans(&parse(source.to_string()), source), ["<p>hi <b>there</b></p>", "hi ", "<b>there</b>", "there"]);
+    }
+
+    #[test]
+    fn spans_of_implicitly_closed_elements_reach_the_closing_tag() {
+        let source = "<ul><li>a<li>b</ul>";
+        assert_eq!(spans(&parse(source.to_string()), source), ["<ul><li>a<li>b</ul>", "<li>a<li>", "a", "<li>b</ul>", "b"]);
+        let source = "<div>x<span>y</div>z";
+        assert_eq!(spans(&parse(source.to_string()), source), ["<div>x<span>y</div>", "x", "<span>y</div>", "y", "z"]);
+    }
+
+    #[test]
+    fn keeps_whitespace_text_nodes() {
+        assert_eq!(html("<p><b>a</b> <i>b</i>\n</p>"), "<p><b>a</b> <i>b</i>\n</p>");
+        assert_eq!(html("\n  <p>a</p>\n"), "<p>a</p>");
+    }
+
+    #[test]
+    fn ignores_the_newline_after_pre_and_listing() {
+        assert_eq!(html("<pre>\nline\n</pre>"), "<pre>line\n</pre>");
+        assert_eq!(text("<listing>\n\nline</listing>"), "\nline");
+        assert_eq!(html("<div>\nline</div>"), "<div>\nline</div>");
     }
 }
@@ -0,0 +1,768 @@
+//! JSON import and export for the trees the engine builds.
+//!
+//! The DOM, style and layout trees can all be written out as JSON, so each stage
+//! can be inspected by tools that aren't written in Rust. The DOM can be read back
+//! too. The JSON reader and writer are hand-rolled, like the HTML and CSS parsers.
+
+use crate::error::{self, describe_next, ParseError, PendingError};
+use crate::{css, dom, layout, style};
+use std::fmt;
+
+
+/*
+    A JSON value. Objects keep their keys in the order they were added, so the same
+    tree is always written out the same way, which keeps golden files stable.
+
+    e.g.
+        {"type": "text", "text": "hi"} =>
+            Value::Object([
+                ("type", Value::String("text")),
+                ("text", Value::String("hi")),
+            ])
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Return the value of `key` if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    /// The JSON text for this value, with one member or item per line, indented by two spaces.
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        write_pretty(self, 0, &mut out);
+        out
+    }
+}
+
+/// Build an object from `(key, value)` pairs, keeping their order.
+fn object(members: Vec<(&str, Value)>) -> Value {
+    Value::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+/// A number for an `f32`, without the noise of widening it, e.g. 0.1 rather than 0.10000000149011612.
+fn number(n: f32) -> Value {
+    Value::Number(n.to_string().parse().unwrap_or(f64::NAN))
+}
+
+
+/*
+    Writing
+
+    `Display` writes a value on one line, and `to_pretty_string` spreads it over many.
+    Numbers that aren't finite have no JSON form, so they are written as null.
+ */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", quote(key), value)?;
+                }
+                write!(f, "}}")
+            }
+            scalar => write!(f, "{}", scalar_string(scalar)),
+        }
+    }
+}
+
+fn write_pretty(value: &Value, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth + 1);
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            out.push_str("[\n");
+            for (index, item) in items.iter().enumerate() {
+                out.push_str(&indent);
+                write_pretty(item, depth + 1, out);
+                out.push_str(if index + 1 < items.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}]", "  ".repeat(depth)));
+        }
+        Value::Object(members) if !members.is_empty() => {
+            out.push_str("{\n");
+            for (index, (key, value)) in members.iter().enumerate() {
+                out.push_str(&format!("{}{}: ", indent, quote(key)));
+                write_pretty(value, depth + 1, out);
+                out.push_str(if index + 1 < members.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}}}", "  ".repeat(depth)));
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+/// The JSON text for a value that isn't an array or object.
+fn scalar_string(value: &Value) -> String {
+    match value {
+        Value::Number(n) if n.is_finite() => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::String(s) => quote(s),
+        _ => "null".to_string(),
+    }
+}
+
+/// `s` as a JSON string, with quotes, backslashes and control characters escaped.
+fn quote(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c < ' ' => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+
+/*
+    Reading
+
+    The reader follows the JSON grammar exactly, and stops at the first thing that
+    doesn't fit it, since there is no sensible way to recover in JSON. It reads
+    nested arrays and objects with recursion, so it refuses to nest them deeper
+    than `MAX_DEPTH`, rather than running out of stack.
+
+    e.g.
+        "[[[ ... 600 times" => expected at most 512 nested arrays and objects but found '['
+ */
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+    /// How many arrays and objects the parser is inside.
+    depth: usize,
+}
+
+/// The deepest arrays and objects can be nested in a JSON document.
+pub const MAX_DEPTH: usize = 512;
+
+type ParseResult<T> = Result<T, PendingError>;
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.position..]
+    }
+
+    fn error(&self, expected: &str) -> PendingError {
+        PendingError::new(self.position, expected, describe_next(self.rest()))
+    }
+
+    fn next_char(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn consume_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.next_char() {
+            self.position += 1;
+        }
+    }
+
+    fn expect_char(&mut self, c: char) -> ParseResult<()> {
+        if self.next_char() == Some(c) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("{:?}", c)))
+        }
+    }
+
+    fn parse_value(&mut self) -> ParseResult<Value> {
+        self.consume_whitespace();
+        let value = match self.next_char() {
+            Some('{') => self.parse_nested(Parser::parse_object)?,
+            Some('[') => self.parse_nested(Parser::parse_array)?,
+            Some('"') => Value::String(self.parse_string()?),
+            Some('-' | '0'..='9') => self.parse_number()?,
+            _ => {
+                let literals = [("true", Value::Bool(true)), ("false", Value::Bool(false)), ("null", Value::Null)];
+                match literals.into_iter().find(|(word, _)| self.rest().starts_with(word)) {
+                    Some((word, value)) => {
+                        self.position += word.len();
+                        value
+                    }
+                    None => return Err(self.error("a JSON value")),
+                }
+            }
+        };
+        self.consume_whitespace();
+        Ok(value)
+    }
+
+    /// Parse an array or object with `parse`, one level deeper than the current one.
+    fn parse_nested(&mut self, parse: fn(&mut Self) -> ParseResult<Value>) -> ParseResult<Value> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!("at most {} nested arrays and objects", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_object(&mut self) -> ParseResult<Value> {
+        self.expect_char('{')?;
+        let mut members = Vec::new();
+        self.consume_whitespace();
+        if self.next_char() == Some('}') {
+            self.position += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.consume_whitespace();
+            if self.next_char() != Some('"') {
+                return Err(self.error("a string key"));
+            }
+            let key = self.parse_string()?;
+            self.consume_whitespace();
+            self.expect_char(':')?;
+            members.push((key, self.parse_value()?));
+            match self.next_char() {
+                Some(',') => self.position += 1,
+                Some('}') => {
+                    self.position += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> ParseResult<Value> {
+        self.expect_char('[')?;
+        let mut items = Vec::new();
+        self.consume_whitespace();
+        if self.next_char() == Some(']') {
+            self.position += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            match self.next_char() {
+                Some(',') => self.position += 1,
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> ParseResult<String> {
+        self.expect_char('"')?;
+        let mut result = String::new();
+        loop {
+            match self.next_char() {
+                Some('"') => {
+                    self.position += 1;
+                    return Ok(result);
+                }
+                Some('\\') => {
+                    self.position += 1;
+                    let escape = self.position;
+                    let c = match self.consume_char() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.parse_unicode_escape()?,
+                        _ => {
+                            self.position = escape;
+                            return Err(self.error("an escape character"));
+                        }
+                    };
+                    result.push(c);
+                }
+                Some(c) if c >= ' ' => {
+                    self.position += c.len_utf8();
+                    result.push(c);
+                }
+                _ => return Err(self.error("'\"'")),
+            }
+        }
+    }
+
+    /// Parse the hex digits after `\u`. A UTF-16 surrogate pair is written as two escapes,
+    /// and half of one on its own becomes U+FFFD.
+    fn parse_unicode_escape(&mut self) -> ParseResult<char> {
+        let first = self.parse_hex4()?;
+        if (0xD800..0xDC00).contains(&first) && self.rest().starts_with("\\u") {
+            let start = self.position;
+            self.position += 2;
+            let second = self.parse_hex4()?;
+            if (0xDC00..0xE000).contains(&second) {
+                let code = 0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00);
+                return Ok(char::from_u32(code).unwrap_or('\u{FFFD}'));
+            }
+            // Not the second half, so read it as an escape of its own.
+            self.position = start;
+        }
+        Ok(char::from_u32(first).unwrap_or('\u{FFFD}'))
+    }
+
+    fn parse_hex4(&mut self) -> ParseResult<u32> {
+        let code = self.rest().get(..4)
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|digits| u32::from_str_radix(digits, 16).unwrap());
+        match code {
+            Some(code) => {
+                self.position += 4;
+                Ok(code)
+            }
+            None => Err(self.error("four hex digits")),
+        }
+    }
+
+    fn parse_number(&mut self) -> ParseResult<Value> {
+        let start = self.position;
+        if self.next_char() == Some('-') {
+            self.position += 1;
+        }
+        match self.next_char() {
+            Some('0') => self.position += 1,
+            Some('1'..='9') => self.consume_digits(),
+            _ => return Err(self.error("a digit")),
+        }
+        if self.next_char() == Some('.') {
+            self.position += 1;
+            self.expect_digits()?;
+        }
+        if let Some('e' | 'E') = self.next_char() {
+            self.position += 1;
+            if let Some('+' | '-') = self.next_char() {
+                self.position += 1;
+            }
+            self.expect_digits()?;
+        }
+        Ok(Value::Number(self.input[start..self.position].parse().unwrap()))
+    }
+
+    fn consume_digits(&mut self) {
+        while let Some('0'..='9') = self.next_char() {
+            self.position += 1;
+        }
+    }
+
+    fn expect_digits(&mut self) -> ParseResult<()> {
+        if !matches!(self.next_char(), Some('0'..='9')) {
+            return Err(self.error("a digit"));
+        }
+        self.consume_digits();
+        Ok(())
+    }
+}
+
+/// Parse a JSON document.
+pub fn parse(source: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { input: source, position: 0, depth: 0 };
+    let result = parser.parse_value().and_then(|value| match parser.next_char() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("end of input")),
+    });
+    result.map_err(|pending| error::locate(source, vec![pending]).remove(0))
+}
+
+
+/*
+    The DOM as JSON
+
+    Each node is an object with a "type", and the fields for that type. Attributes
+    are written in order of name. The namespace is only written for elements that
+    aren't HTML, as its URL, and the span only for nodes that have one.
+
+    e.g.
+        <p class="note">Hi<!-- x --></p> =>
+            {
+              "type": "element",
+              "tag": "p",
+              "attributes": {"class": "note"},
+              "children": [
+                {"type": "text", "text": "Hi"},
+                {"type": "comment", "text": " x "}
+              ]
+            }
+ */
+impl dom::Node {
+    /// This node and its descendants as JSON.
+    pub fn to_json(&self) -> Value {
+        node_json(self, true)
+    }
+
+    /// Read a node written by `to_json`.
+    pub fn from_json(value: &Value) -> Result<dom::Node, FormatError> {
+        read_node(value, "")
+    }
+}
+
+/// The JSON for `node`, with or without its children.
+fn node_json(node: &dom::Node, with_children: bool) -> Value {
+    let mut members = match node.node_type {
+        dom::NodeType::Element(ref element) => {
+            let mut members = vec![
+                ("type", Value::String("element".to_string())),
+                ("tag", Value::String(element.tag_name.clone())),
+            ];
+            if element.namespace != dom::Namespace::Html {
+                members.push(("namespace", Value::String(element.namespace.url().to_string())));
+            }
+            let mut names: Vec<&String> = element.attributes.keys().collect();
+            names.sort();
+            let attributes = names.into_iter()
+                .map(|name| (name.clone(), Value::String(element.attributes[name].clone())))
+                .collect();
+            members.push(("attributes", Value::Object(attributes)));
+            members
+        }
+        dom::NodeType::Text(ref text) => {
+            vec![("type", Value::String("text".to_string())), ("text", Value::String(text.clone()))]
+        }
+        dom::NodeType::Comment(ref text) => {
+            vec![("type", Value::String("comment".to_string())), ("text", Value::String(text.clone()))]
+        }
+        dom::NodeType::Doctype(ref doctype) => vec![
+            ("type", Value::String("doctype".to_string())),
+            ("name", Value::String(doctype.name.clone())),
+            ("public_id", Value::String(doctype.public_id.clone())),
+            ("system_id", Value::String(doctype.system_id.clone())),
+        ],
+    };
+    if let Some(span) = node.span {
+        members.push(("span", object(vec![
+            ("start", Value::Number(span.start as f64)),
+            ("end", Value::Number(span.end as f64)),
+        ])));
+    }
+    if with_children && matches!(node.node_type, dom::NodeType::Element(_)) {
+        members.push(("children", Value::Array(node.children.iter().map(|child| node_json(child, true)).collect())));
+    }
+    object(members)
+}
+
+
+/// The reason JSON couldn't be read as a DOM node. `path` is where in the JSON the
+/// problem is, e.g. "/children/2/tag".
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatError {
+    pub path: String,
+    pub expected: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() { "/" } else { &self.path };
+        write!(f, "{}: expected {}", path, self.expected)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+fn format_error(path: &str, expected: &str) -> FormatError {
+    FormatError { path: path.to_string(), expected: expected.to_string() }
+}
+
+/// Read member `key` of `value` as a string. Missing members read as "" if `optional` is set.
+fn read_string(value: &Value, key: &str, path: &str, optional: bool) -> Result<String, FormatError> {
+    match value.get(key) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        None if optional => Ok(String::new()),
+        _ => Err(format_error(&format!("{}/{}", path, key), "a string")),
+    }
+}
+
+fn read_node(value: &Value, path: &str) -> Result<dom::Node, FormatError> {
+    if value.as_object().is_none() {
+        return Err(format_error(path, "an object"));
+    }
+    let mut node = match read_string(value, "type", path, false)?.as_str() {
+        "element" => {
+            let tag_name = read_string(value, "tag", path, false)?;
+            let namespace = match value.get("namespace") {
+                None => dom::Namespace::Html,
+                Some(url) => NAMESPACES.iter().copied().find(|namespace| Some(namespace.url()) == url.as_str())
+                    .ok_or_else(|| format_error(&format!("{}/namespace", path), "a namespace URL"))?,
+            };
+            let mut attributes = dom::AttributeMap::new();
+            if let Some(members) = value.get("attributes") {
+                let members = members.as_object()
+                    .ok_or_else(|| format_error(&format!("{}/attributes", path), "an object"))?;
+                for (name, value) in members {
+                    let value = value.as_str()
+                        .ok_or_else(|| format_error(&format!("{}/attributes/{}", path, name), "a string"))?;
+                    attributes.insert(name.clone(), value.to_string());
+                }
+            }
+            let mut children = Vec::new();
+            if let Some(items) = value.get("children") {
+                let items = items.as_array()
+                    .ok_or_else(|| format_error(&format!("{}/children", path), "an array"))?;
+                for (index, item) in items.iter().enumerate() {
+                    children.push(read_node(item, &format!("{}/children/{}", path, index))?);
+                }
+            }
+            dom::element_ns(namespace, tag_name, attributes, children)
+        }
+        "text" => dom::text(read_string(value, "text", path, false)?),
+        "comment" => dom::comment(read_string(value, "text", path, false)?),
+        "doctype" => dom::doctype(
+            read_string(value, "name", path, true)?,
+            read_string(value, "public_id", path, true)?,
+            read_string(value, "system_id", path, true)?,
+        ),
+        _ => return Err(format_error(&format!("{}/type", path), "\"element\", \"text\", \"comment\" or \"doctype\"")),
+    };
+    if let Some(span) = value.get("span") {
+        let offset = |key: &str| span.get(key).and_then(Value::as_f64).filter(|n| *n >= 0.0 && n.fract() == 0.0);
+        match (offset("start"), offset("end")) {
+            (Some(start), Some(end)) => node.span = Some(dom::Span { start: start as usize, end: end as usize }),
+            _ => return Err(format_error(&format!("{}/span", path), "an object with \"start\" and \"end\" offsets")),
+        }
+    }
+    Ok(node)
+}
+
+const NAMESPACES: [dom::Namespace; 6] = [
+    dom::Namespace::Html,
+    dom::Namespace::Svg,
+    dom::Namespace::MathMl,
+    dom::Namespace::XLink,
+    dom::Namespace::Xml,
+    dom::Namespace::Xmlns,
+];
+
+
+/*
+    The style and layout trees as JSON
+
+    These are for looking at, so they can't be read back. Each styled node or box
+    refers to its DOM node without the node's children, since those show up in the
+    tree anyway. CSS values are written the way they would be in a stylesheet.
+
+    e.g.
+        {
+          "node": {"type": "element", "tag": "div", "attributes": {}},
+          "specified_values": {"display": "block", "width": "100px"},
+          "children": []
+        }
+ */
+impl style::StyledNode<'_> {
+    /// This styled node and its descendants as JSON.
+    pub fn to_json(&self) -> Value {
+        let mut names: Vec<&String> = self.specified_values.keys().collect();
+        names.sort();
+        let values = names.into_iter()
+            .map(|name| (name.clone(), Value::String(css_value_string(&self.specified_values[name]))))
+            .collect();
+        object(vec![
+            ("node", node_json(self.node, false)),
+            ("specified_values", Value::Object(values)),
+            ("children", Value::Array(self.children.iter().map(|child| child.to_json()).collect())),
+        ])
+    }
+}
+
+/// A CSS value as it would be written in a stylesheet, e.g. `10px` or `#ff0000`.
+fn css_value_string(value: &css::Value) -> String {
+    match value {
+        css::Value::Keyword(keyword) => keyword.clone(),
+        css::Value::Length(length, css::Unit::Px) => format!("{}px", length),
+        css::Value::ColorValue(color) if color.a == 255 => format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b),
+        css::Value::ColorValue(color) => format!("#{:02x}{:02x}{:02x}{:02x}", color.r, color.g, color.b, color.a),
+    }
+}
+
+impl layout::LayoutBox<'_> {
+    /// This box and its descendants as JSON. Anonymous boxes have no node.
+    pub fn to_json(&self) -> Value {
+        let mut members = match self.box_type {
+            layout::BoxType::BlockNode(node) => {
+                vec![("box_type", Value::String("block".to_string())), ("node", node_json(node.node, false))]
+            }
+            layout::BoxType::InlineNode(node) => {
+                vec![("box_type", Value::String("inline".to_string())), ("node", node_json(node.node, false))]
+            }
+            layout::BoxType::AnonymousBlock => vec![("box_type", Value::String("anonymous".to_string()))],
+        };
+        let dimensions = &self.dimensions;
+        members.push(("dimensions", object(vec![
+            ("content", rect_json(&dimensions.content)),
+            ("padding", edges_json(&dimensions.padding)),
+            ("border", edges_json(&dimensions.border)),
+            ("margin", edges_json(&dimensions.margin)),
+        ])));
+        members.push(("children", Value::Array(self.children.iter().map(|child| child.to_json()).collect())));
+        object(members)
+    }
+}
+
+fn rect_json(rect: &layout::Rect) -> Value {
+    object(vec![
+        ("x", number(rect.x)),
+        ("y", number(rect.y)),
+        ("width", number(rect.width)),
+        ("height", number(rect.height)),
+    ])
+}
+
+fn edges_json(edges: &layout::EdgeSizes) -> Value {
+    object(vec![
+        ("left", number(edges.left)),
+        ("right", number(edges.right)),
+        ("top", number(edges.top)),
+        ("bottom", number(edges.bottom)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    /// The error from parsing `source`, which must be bad JSON.
+    fn error(source: &str) -> ParseError {
+        parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn reads_back_the_dom_it_writes() {
+        let node = html::parse("<div id=a class='b c'>Hi \"there\"\n<!-- x --><svg viewBox='0 0 1 1'><rect/></svg></div>".to_string());
+        let json = node.to_json();
+        assert_eq!(dom::Node::from_json(&json), Ok(node.clone()));
+        assert_eq!(parse(&json.to_string()), Ok(json.clone()));
+        assert_eq!(parse(&json.to_pretty_string()), Ok(json));
+    }
+
+    #[test]
+    fn reports_where_a_node_is_malformed() {
+        let json = parse(r#"{"type": "element", "tag": "p", "children": [{"type": "text"}]}"#).unwrap();
+        assert_eq!(dom::Node::from_json(&json), Err(format_error("/children/0/text", "a string")));
+        let json = parse(r#"{"type": "element", "tag": "p", "namespace": "x"}"#).unwrap();
+        assert_eq!(dom::Node::from_json(&json).unwrap_err().to_string(), "/namespace: expected a namespace URL");
+    }
+
+    #[test]
+    fn reads_escapes() {
+        assert_eq!(parse(r#""a\"\\\/\b\f\n\r\té""#), Ok(Value::String("a\"\\/\u{8}\u{c}\n\r\té".to_string())));
+        assert_eq!(quote("a\"\\\n\u{1}é"), r#""a\"\\\n\u0001é""#);
+    }
+
+    #[test]
+    fn refuses_bad_escapes() {
+        let bad = error(r#""a\q""#);
+        assert_eq!((bad.column, bad.expected.as_str(), bad.found.as_str()), (4, "an escape character", "'q'"));
+        // A character after the backslash that takes more than one byte.
+        let bad = error(r#""a\é""#);
+        assert_eq!((bad.column, bad.found.as_str()), (4, "'é'"));
+        let bad = error(r#""\u12x4""#);
+        assert_eq!((bad.column, bad.expected.as_str()), (4, "four hex digits"));
+        assert_eq!(error(r#""a\"#).found, "end of input");
+        assert_eq!(error("\"a\nb\"").expected, "'\"'");
+    }
+
+    #[test]
+    fn reads_surrogate_pairs() {
+        assert_eq!(parse(r#""\ud83d\ude00""#), Ok(Value::String("\u{1F600}".to_string())));
+        assert_eq!(parse(r#""\uD83D\uDE00""#), Ok(Value::String("\u{1F600}".to_string())));
+        // Half a pair on its own is a replacement character.
+        assert_eq!(parse(r#""\ud83dx""#), Ok(Value::String("\u{FFFD}x".to_string())));
+        assert_eq!(parse(r#""\ude00\ud83d""#), Ok(Value::String("\u{FFFD}\u{FFFD}".to_string())));
+        assert_eq!(parse(r#""\ud83dA""#), Ok(Value::String("\u{FFFD}A".to_string())));
+    }
+
+    #[test]
+    fn refuses_nesting_too_deep() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        let bad = error(&nested(MAX_DEPTH + 1));
+        assert_eq!((bad.column, bad.found.as_str()), (MAX_DEPTH + 1, "'['"));
+        assert_eq!(bad.expected, format!("at most {} nested arrays and objects", MAX_DEPTH));
+
+        let objects = format!("{}1{}", r#"{"a":"#.repeat(MAX_DEPTH + 1), "}".repeat(MAX_DEPTH + 1));
+        assert!(parse(&objects).is_err());
+        // Nesting that goes back up doesn't count.
+        let siblings = format!("[{}]", vec![nested(MAX_DEPTH - 1); 3].join(","));
+        assert!(parse(&siblings).is_ok());
+    }
+
+    #[test]
+    fn reads_numbers_and_literals() {
+        let value = parse(" [0, -1.5e2, 10E-1, true, false, null, {}] ").unwrap();
+        assert_eq!(value, Value::Array(vec![
+            Value::Number(0.0), Value::Number(-150.0), Value::Number(1.0),
+            Value::Bool(true), Value::Bool(false), Value::Null, Value::Object(Vec::new()),
+        ]));
+        assert_eq!(error("01").expected, "end of input");
+        assert_eq!(error("1.").expected, "a digit");
+        assert_eq!(error("[1 2]").expected, "',' or ']'");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "null");
+    }
+}
@@ -2,7 +2,7 @@
 
 use crate::{css, style};
 
-/**
+/*
  *  the layout module takes the style tree and translates it into a bunch of rectangles in
  *  a two-dimensional space.
  *
@@ -10,7 +10,7 @@ use crate::{css, style};
  *  "layout tree".
  */
 
-/**
+/*
  *  layout is all about "boxes". a box is a rectangular section of a web page. It has a
  *  width, a height, and a position on the page. this rectangle is called the "content area"
  *  because it's where the box's content is drawn. The content may be text, image, video,
@@ -28,7 +28,7 @@ use crate::{css, style};
 // css box model. all sizes are in px.
 
 /// position of the content area relative to the document origin:
-#[derive(Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct Rect {
     pub x: f32,
     pub y: f32,
@@ -37,7 +37,7 @@ pub struct Rect {
 }
 
 /// surrounding edges:
-#[derive(Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct EdgeSizes {
     pub left: f32,
     pub right: f32,
@@ -45,7 +45,7 @@ pub struct EdgeSizes {
     pub bottom: f32,
 }
 
-#[derive(Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct Dimensions {
     pub content: Rect,
     pub padding: EdgeSizes,
@@ -97,7 +97,7 @@ pub struct LayoutBox<'a> {
 }
 
 
-/**
+/*
  *  To build the layout tree, we need to look at the display property for each DOM node.
  *  I added some code to the style module to get the display value for a node. If there's
  *  no specified value it returns the initial value, "inline".
@@ -150,22 +150,31 @@ fn build_layout_tree<'a>(style_node: &'a style::StyledNode<'a>) -> LayoutBox<'a>
     root
 }
 
+/// Transform a style tree into a layout tree, laid out in `containing_block`.
+pub fn layout_tree<'a>(node: &'a style::StyledNode<'a>, mut containing_block: Dimensions) -> LayoutBox<'a> {
+    // The layout algorithm expects the container height to start at 0.
+    containing_block.content.height = 0.0;
+    let mut root_box = build_layout_tree(node);
+    root_box.layout(containing_block);
+    root_box
+}
+
 
-impl LayoutBox<'_> {
+impl<'a> LayoutBox<'a> {
     /*
         If a block node contains an inline child, create an anonymous block box to
         contain it. If there are several inline children in a row, put them all in
         the same anonymous container.
      */
     /// Where a new inline child should go.
-    fn get_inline_container(&mut self) -> &mut LayoutBox {
+    fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
         match self.box_type {
             BoxType::InlineNode(_) | BoxType::AnonymousBlock => self,
             BoxType::BlockNode(_) => {
                 // If we've just generated an anonymous block box, keep using it.
                 // Otherwise, create a new one.
                 match self.children.last() {
-                    Some(&LayoutBox { box_type: AnonymousBlock, .. }) => {}
+                    Some(&LayoutBox { box_type: BoxType::AnonymousBlock, .. }) => {}
                     _ => self.children.push(LayoutBox::new(BoxType::AnonymousBlock))
                 }
                 self.children.last_mut().unwrap()
@@ -183,7 +192,7 @@ impl LayoutBox<'_> {
     /// Lay out a box and its descendants.
     fn layout(&mut self, containing_block: Dimensions) {
         match self.box_type {
-            BoxType::BlockNode(_) => self.layout(containing_block),
+            BoxType::BlockNode(_) => self.layout_block(containing_block),
             BoxType::InlineNode(_) => {} // TODO
             BoxType::AnonymousBlock => {} // TODO
         }
@@ -235,7 +244,7 @@ impl LayoutBox<'_> {
         let zero: css::Value = css::Value::Length(0.0, css::Unit::Px);
 
 
-        /**
+        /*
          *  This uses a helper function called "style::StyledNode::lookup", which just
          *  tries a series of values in sequence. If the first property isn't set, it
          *  tries the second one. If that's not set either, it returns the given default
@@ -256,7 +265,7 @@ impl LayoutBox<'_> {
         let padding_right: css::Value = style.lookup("padding-right", "padding", &zero);
 
 
-        /**
+        /*
          *  Since a child can't change its parent's width, it needs to make sure its own
          *  width fits the parent's. The CSS spec expresses this as a set of [constraints](https://www.w3.org/TR/CSS2/visudet.html#blockwidth)
          *  and an algorithm for solving them. The following code implements that algorithm.
@@ -275,7 +284,7 @@ impl LayoutBox<'_> {
             &width
         ].iter().map(|v: &&css::Value| v.to_px()).sum();
 
-        /**
+        /*
          *  This is the minimum horizontal space needed for the box. If this isn't equal
          *  to the container width, we'll need to adjust something to make it equal.
          */
@@ -285,8 +294,8 @@ impl LayoutBox<'_> {
             the available space. Following the spec, we first check if the box is too big.
             If so, we set any expandable margins to zero.
          */
-        /// If width is not auto and the total is wider than the container,
-        /// treat auto margins as 0.
+        // If width is not auto and the total is wider than the container,
+        // treat auto margins as 0.
         if width != auto && total > containing_block.content.width {
             if margin_left == auto {
                 margin_left = css::Value::Length(0.0, css::Unit::Px)
@@ -351,11 +360,22 @@ impl LayoutBox<'_> {
             }
         }
 
-        /**
+        /*
          *  At this point, the constraints are met and any "auto" values have been
          *  converted to lengths. The results are the [used values](https://www.w3.org/TR/CSS2/cascade.html#used-value)
          *  for the horizontal box dimensions, which we will store in the layout tree.
          */
+        let d: &mut Dimensions = &mut self.dimensions;
+        d.content.width = width.to_px();
+
+        d.padding.left = padding_left.to_px();
+        d.padding.right = padding_right.to_px();
+
+        d.border.left = border_left.to_px();
+        d.border.right = border_right.to_px();
+
+        d.margin.left = margin_left.to_px();
+        d.margin.right = margin_right.to_px();
     }
 
 
@@ -414,8 +434,8 @@ impl LayoutBox<'_> {
      *  the "height" property is set to an explicit length, we'll use that instead:
      */
     fn calculate_block_height(&mut self) {
-        /// If the height is set to an explicit length, use that exact length.
-        /// Otherwise, just keep the value set by `layout_block_children`.
+        // If the height is set to an explicit length, use that exact length.
+        // Otherwise, just keep the value set by `layout_block_children`.
         if let Some(css::Value::Length(h, css::Unit::Px)) = self.get_style_node().value("height") {
             self.dimensions.content.height = h;
         }
@@ -423,7 +443,7 @@ impl LayoutBox<'_> {
 }
 
 
-/**
+/*
  *  The total vertical space taken up by each child is the height of its "margin box",
  *  which we calculate like so:
  */
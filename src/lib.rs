@@ -0,0 +1,22 @@
+//! A toy browser engine, built up one stage at a time: parsing HTML and CSS,
+//! styling, layout and painting. The `main` binary is only a placeholder for now,
+//! so the stages are a library, to be used by tests and other programs.
+
+pub mod dom;
+pub mod document;
+pub mod traversal;
+pub mod event;
+pub mod mutation;
+pub mod diff;
+pub mod json;
+pub mod html;
+pub mod encoding;
+pub mod entities;
+pub mod jis0208;
+pub mod css;
+pub mod error;
+pub mod loader;
+pub mod serialize;
+pub mod style;
+pub mod layout;
+pub mod painting;
@@ -0,0 +1,344 @@
+//! Loading the resources a document refers to, such as linked stylesheets.
+//!
+//! Resources are fetched through the `ResourceLoader` trait, so the engine doesn't
+//! care where they come from. Only the filesystem and memory are supported for now.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+
+/*
+    A resource loader takes an absolute URL and returns the bytes stored there.
+    Relative URLs in a document are resolved with `resolve_url` before they get here.
+
+    e.g.
+        loader.load("file:///site/style.css") => Ok(b"p { color: #ff0000; }")
+ */
+/// Something that can fetch the contents of a URL.
+pub trait ResourceLoader {
+    /// Return the bytes stored at `url`.
+    fn load(&self, url: &str) -> io::Result<Vec<u8>>;
+
+    /// Return the contents of `url` as text. Invalid UTF-8 is replaced with U+FFFD.
+    fn load_text(&self, url: &str) -> io::Result<String> {
+        let bytes = self.load(url)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+
+/// Loads `file:` URLs, and plain paths, from the filesystem.
+/// Percent-escapes in `file:` URLs are decoded, so "file:///a%20b.css" loads "/a b.css".
+pub struct FileLoader;
+
+impl ResourceLoader for FileLoader {
+    fn load(&self, url: &str) -> io::Result<Vec<u8>> {
+        let (path, is_url) = match url.strip_prefix("file://") {
+            Some(path) => (path, true),
+            None if has_scheme(url) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't load {}", url)));
+            }
+            None => (url, false),
+        };
+        // The query and fragment aren't part of the file name.
+        let path = match path.find(['?', '#']) {
+            Some(end) => &path[..end],
+            None => path,
+        };
+        if is_url {
+            fs::read(percent_decode(path))
+        } else {
+            fs::read(path)
+        }
+    }
+}
+
+
+/*
+    The memory loader serves resources that were added to it up front, which is
+    handy for tests and for documents built by a program.
+
+    e.g.
+        let mut loader = MemoryLoader::new();
+        loader.insert("file:///index.html", "<link rel=stylesheet href=style.css>");
+        loader.insert("file:///style.css", "p { color: #ff0000; }");
+ */
+/// Serves resources from a map of URLs to bytes.
+#[derive(Default)]
+pub struct MemoryLoader {
+    resources: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader { resources: HashMap::new() }
+    }
+
+    /// Store `bytes` at `url`, replacing anything that was there.
+    pub fn insert(&mut self, url: &str, bytes: impl Into<Vec<u8>>) {
+        self.resources.insert(url.to_string(), bytes.into());
+    }
+}
+
+impl ResourceLoader for MemoryLoader {
+    fn load(&self, url: &str) -> io::Result<Vec<u8>> {
+        self.resources.get(url).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no resource at {}", url))
+        })
+    }
+}
+
+
+/*
+    Resolving a URL works like a browser does for the common cases:
+        - A URL with a scheme is already absolute.
+        - "//host/path" keeps the scheme of the base.
+        - "/path" keeps the scheme and host of the base.
+        - Anything else is relative to the directory of the base.
+    Then "." and ".." segments are removed from the path.
+
+    e.g.
+        resolve_url("file:///site/blog/post.html", "../style.css") => "file:///site/style.css"
+        resolve_url("http://example.com/a/b.html", "/c.css")        => "http://example.com/c.css"
+ */
+/// Resolve `url` against the absolute URL `base`.
+pub fn resolve_url(base: &str, url: &str) -> String {
+    if has_scheme(url) {
+        return url.to_string();
+    }
+
+    let (scheme, rest) = match base.find(':') {
+        Some(colon) if has_scheme(base) => base.split_at(colon + 1),
+        _ => ("", base),
+    };
+    if url.starts_with("//") {
+        return format!("{}{}", scheme, url);
+    }
+
+    let (authority, path) = match rest.strip_prefix("//") {
+        Some(after_slashes) => {
+            let end = after_slashes.find('/').map_or(rest.len(), |slash| slash + 2);
+            rest.split_at(end)
+        }
+        None => ("", rest),
+    };
+    // An empty URL or a lone fragment keeps the query of the base, e.g. "page?q#s".
+    let path_and_query = match path.find('#') {
+        Some(end) => &path[..end],
+        None => path,
+    };
+    let path = match path.find(['?', '#']) {
+        Some(end) => &path[..end],
+        None => path,
+    };
+
+    let path = if url.is_empty() {
+        path_and_query.to_string()
+    } else if url.starts_with('/') {
+        url.to_string()
+    } else if url.starts_with('#') {
+        format!("{}{}", path_and_query, url)
+    } else if url.starts_with('?') {
+        format!("{}{}", path, url)
+    } else {
+        // Replace everything after the last '/' of the base path.
+        let directory = match path.rfind('/') {
+            Some(slash) => &path[..=slash],
+            // "http://example.com" has an empty path, which means "/".
+            None if !authority.is_empty() => "/",
+            None => "",
+        };
+        format!("{}{}", directory, url)
+    };
+    format!("{}{}{}", scheme, authority, remove_dot_segments(&path))
+}
+
+/// Decode "%XX" escapes in a URL path. A '%' that isn't followed by two hex digits is kept.
+fn percent_decode(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes.get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Does `url` start with a scheme, like "file:" or "http:"?
+fn has_scheme(url: &str) -> bool {
+    match url.find(':') {
+        // A single letter is more likely a Windows drive than a scheme.
+        Some(colon) if colon > 1 => {
+            let scheme = &url[..colon];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        _ => false,
+    }
+}
+
+/// Remove "." and ".." segments from a path, keeping any query and fragment.
+fn remove_dot_segments(path: &str) -> String {
+    let (path, suffix) = match path.find(['?', '#']) {
+        Some(end) => path.split_at(end),
+        None => (path, ""),
+    };
+    let parts: Vec<&str> = path.split('/').collect();
+    let mut segments: Vec<&str> = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        let is_last = index == parts.len() - 1;
+        match *part {
+            "." => {}
+            ".." => {
+                // Never go above the root.
+                if segments.len() > 1 || segments.first().is_some_and(|first| !first.is_empty()) {
+                    segments.pop();
+                }
+            }
+            segment => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // "a/b/.." refers to the directory "a/".
+        if is_last {
+            segments.push("");
+        }
+    }
+    segments.join("/") + suffix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_the_normal_examples_from_rfc_3986() {
+        // RFC 3986, section 5.4.1, except that "g:h" is "gh:h" here, because a
+        // one-letter scheme is taken to be a Windows drive.
+        let base = "http://a/b/c/d;p?q";
+        let examples = [
+            ("gh:h", "gh:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            ("g?y#s", "http://a/b/c/g?y#s"),
+            (";x", "http://a/b/c/;x"),
+            ("g;x", "http://a/b/c/g;x"),
+            ("g;x?y#s", "http://a/b/c/g;x?y#s"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../", "http://a/"),
+            ("../../g", "http://a/g"),
+        ];
+        for (url, expected) in examples {
+            assert_eq!(resolve_url(base, url), expected, "resolving {:?}", url);
+        }
+    }
+
+    #[test]
+    fn resolves_the_abnormal_examples_from_rfc_3986() {
+        // RFC 3986, section 5.4.2.
+        let base = "http://a/b/c/d;p?q";
+        let examples = [
+            ("../../../g", "http://a/g"),
+            ("../../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            (".g", "http://a/b/c/.g"),
+            ("g..", "http://a/b/c/g.."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("./g/.", "http://a/b/c/g/"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g;x=1/./y", "http://a/b/c/g;x=1/y"),
+            ("g;x=1/../y", "http://a/b/c/y"),
+            ("g?y/./x", "http://a/b/c/g?y/./x"),
+            ("g?y/../x", "http://a/b/c/g?y/../x"),
+            ("g#s/./x", "http://a/b/c/g#s/./x"),
+            ("g#s/../x", "http://a/b/c/g#s/../x"),
+            ("http:g", "http:g"),
+        ];
+        for (url, expected) in examples {
+            assert_eq!(resolve_url(base, url), expected, "resolving {:?}", url);
+        }
+    }
+
+    #[test]
+    fn resolves_file_urls_and_paths() {
+        assert_eq!(resolve_url("file:///site/blog/post.html", "../style.css"), "file:///site/style.css");
+        assert_eq!(resolve_url("http://example.com", "a.css"), "http://example.com/a.css");
+        assert_eq!(resolve_url("site/index.html", "a.css"), "site/a.css");
+        assert_eq!(resolve_url("C:/site/index.html", "a.css"), "C:/site/a.css");
+    }
+
+    #[test]
+    fn removes_dot_segments() {
+        // RFC 3986, section 5.2.4.
+        assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(remove_dot_segments("mid/content=5/../6"), "mid/6");
+        assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+        assert_eq!(remove_dot_segments("/../a"), "/a");
+        assert_eq!(remove_dot_segments("/a/../b?c/../d"), "/b?c/../d");
+    }
+
+    #[test]
+    fn memory_loader_serves_what_was_inserted() {
+        let mut loader = MemoryLoader::new();
+        loader.insert("file:///a.css", "p {}");
+        loader.insert("file:///b.css", vec![b'a', 0xff]);
+        assert_eq!(loader.load("file:///a.css").unwrap(), b"p {}");
+        assert_eq!(loader.load_text("file:///b.css").unwrap(), "a\u{FFFD}");
+        assert_eq!(loader.load("file:///c.css").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn file_loader_reads_files_by_url_or_path() {
+        let directory = std::env::temp_dir().join(format!("loader-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("a b.css");
+        fs::write(&path, "p {}").unwrap();
+        let path = path.to_str().unwrap().replace('\\', "/");
+        let url = format!("file://{}{}", if path.starts_with('/') { "" } else { "/" }, path.replace(' ', "%20"));
+
+        assert_eq!(FileLoader.load(&path).unwrap(), b"p {}");
+        assert_eq!(FileLoader.load(&url).unwrap(), b"p {}");
+        assert_eq!(FileLoader.load(&format!("{}?v=2#top", url)).unwrap(), b"p {}");
+        assert_eq!(FileLoader.load("http://example.com/a.css").unwrap_err().kind(), io::ErrorKind::Unsupported);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn percent_decodes_only_valid_escapes() {
+        assert_eq!(percent_decode("/a%20b%2Fc.css"), PathBuf::from("/a b/c.css"));
+        assert_eq!(percent_decode("/100%/%zz%4"), PathBuf::from("/100%/%zz%4"));
+        assert_eq!(percent_decode("/%C3%A9.css"), PathBuf::from("/\u{e9}.css"));
+    }
+}
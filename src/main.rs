@@ -1,10 +1,3 @@
-mod dom;
-mod html;
-mod css;
-mod style;
-mod layout;
-mod painting;
-
 fn main() {
     println!("Hello, world!");
 }
@@ -0,0 +1,351 @@
+//! Mutation observers, which keep a record of changes made to a `Document`.
+//!
+//! Like the DOM's `MutationObserver`, except that records aren't delivered to a
+//! callback. They pile up in the observer until the program takes them, e.g. once
+//! per frame, to restyle just the parts of the document that changed.
+
+use crate::document::{Document, NodeId};
+use crate::dom::DomError;
+
+
+/*
+    Every change made through the document's mutation methods is described by one
+    record, which says what kind of change it was and which node it happened to:
+        - ChildList: children were added to or removed from `target`.
+        - Attributes: attribute `attribute_name` of `target` was set or removed.
+        - CharacterData: the text of the text or comment node `target` changed.
+    `old_value` is only filled in if the observer asked for it.
+
+    e.g.
+        document.append_child(list, item) =>
+            MutationRecord {
+                kind: MutationKind::ChildList,
+                target: list,
+                added_nodes: [item],
+                previous_sibling: Some(the item that was last before),
+                ..
+            }
+ */
+/// What kind of change a `MutationRecord` describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MutationKind {
+    /// Children were added or removed.
+    ChildList,
+    /// An attribute was set or removed.
+    Attributes,
+    /// The text of a text or comment node changed.
+    CharacterData,
+}
+
+/// A single change to a document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MutationRecord {
+    pub kind: MutationKind,
+    /// The node that changed, or whose children changed.
+    pub target: NodeId,
+    /// For ChildList: the children that were added, and those that were removed.
+    pub added_nodes: Vec<NodeId>,
+    pub removed_nodes: Vec<NodeId>,
+    /// For ChildList: the siblings on either side of the added or removed nodes.
+    pub previous_sibling: Option<NodeId>,
+    pub next_sibling: Option<NodeId>,
+    /// For Attributes: the name of the attribute that changed.
+    pub attribute_name: Option<String>,
+    /// The attribute value or text from before the change.
+    pub old_value: Option<String>,
+}
+
+impl MutationRecord {
+    pub(crate) fn child_list(target: NodeId, added_nodes: Vec<NodeId>, removed_nodes: Vec<NodeId>,
+                             previous_sibling: Option<NodeId>, next_sibling: Option<NodeId>) -> MutationRecord {
+        MutationRecord {
+            kind: MutationKind::ChildList,
+            target,
+            added_nodes,
+            removed_nodes,
+            previous_sibling,
+            next_sibling,
+            attribute_name: None,
+            old_value: None,
+        }
+    }
+
+    pub(crate) fn attributes(target: NodeId, name: &str, old_value: Option<String>) -> MutationRecord {
+        MutationRecord {
+            kind: MutationKind::Attributes,
+            attribute_name: Some(name.to_string()),
+            old_value,
+            ..MutationRecord::child_list(target, Vec::new(), Vec::new(), None, None)
+        }
+    }
+
+    pub(crate) fn character_data(target: NodeId, old_value: String) -> MutationRecord {
+        MutationRecord {
+            kind: MutationKind::CharacterData,
+            old_value: Some(old_value),
+            ..MutationRecord::child_list(target, Vec::new(), Vec::new(), None, None)
+        }
+    }
+}
+
+
+/*
+    The options say which changes an observer cares about. Without `subtree`, only
+    changes to the observed node itself are recorded, e.g. its own children being
+    added or removed, not those of its children.
+
+    e.g.
+        ObserverOptions { child_list: true, subtree: true, ..Default::default() }
+            => every node added to or removed from anywhere under the observed node
+ */
+/// Which changes a mutation observer records.
+#[derive(Clone, Default)]
+pub struct ObserverOptions {
+    /// Record children being added or removed.
+    pub child_list: bool,
+    /// Record attributes being set or removed.
+    pub attributes: bool,
+    /// Record changes to the text of text and comment nodes.
+    pub character_data: bool,
+    /// Record changes to descendants of the observed node too.
+    pub subtree: bool,
+    /// Keep the value an attribute had before it changed. Implies `attributes`.
+    pub attribute_old_value: bool,
+    /// Keep the text from before it changed. Implies `character_data`.
+    pub character_data_old_value: bool,
+    /// Only record changes to these attributes. Implies `attributes`.
+    pub attribute_filter: Option<Vec<String>>,
+}
+
+/// Identifies a mutation observer made with `Document::create_mutation_observer`.
+/// Like a `NodeId`, it is only meaningful for the document it came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
+#[derive(Default)]
+struct Observer {
+    /// The nodes being observed, and the options they are observed with.
+    targets: Vec<(NodeId, ObserverOptions)>,
+    records: Vec<MutationRecord>,
+}
+
+/// The mutation observers of a document.
+#[derive(Default)]
+pub(crate) struct Observers {
+    observers: Vec<Observer>,
+}
+
+
+impl Document {
+    /// Make a new mutation observer, which doesn't observe anything yet.
+    pub fn create_mutation_observer(&mut self) -> ObserverId {
+        self.observers.observers.push(Observer::default());
+        ObserverId(self.observers.observers.len() - 1)
+    }
+
+    /// Start recording changes to `target`. If `observer` already observes `target`,
+    /// its options are replaced.
+    ///
+    /// Returns `DomError::Type` if `options` doesn't ask for any kind of change, and
+    /// `DomError::NotFound` if `observer` wasn't made by this document.
+    pub fn observe(&mut self, observer: ObserverId, target: NodeId, mut options: ObserverOptions) -> Result<(), DomError> {
+        // Asking for old values or filtering attributes implies observing them.
+        if options.attribute_old_value || options.attribute_filter.is_some() {
+            options.attributes = true;
+        }
+        if options.character_data_old_value {
+            options.character_data = true;
+        }
+        if !(options.child_list || options.attributes || options.character_data) {
+            return Err(DomError::Type);
+        }
+
+        let targets = &mut self.observer_mut(observer)?.targets;
+        match targets.iter_mut().find(|(node, _)| *node == target) {
+            Some(existing) => existing.1 = options,
+            None => targets.push((target, options)),
+        }
+        Ok(())
+    }
+
+    /// Stop recording changes, and throw away any records that haven't been taken.
+    pub fn disconnect(&mut self, observer: ObserverId) -> Result<(), DomError> {
+        let observer = self.observer_mut(observer)?;
+        observer.targets.clear();
+        observer.records.clear();
+        Ok(())
+    }
+
+    /// Return the changes recorded since the last call, oldest first.
+    pub fn take_records(&mut self, observer: ObserverId) -> Result<Vec<MutationRecord>, DomError> {
+        Ok(std::mem::take(&mut self.observer_mut(observer)?.records))
+    }
+
+    /// The observer with id `observer`, or `DomError::NotFound` if it came from another
+    /// document that has more observers than this one.
+    fn observer_mut(&mut self, observer: ObserverId) -> Result<&mut Observer, DomError> {
+        self.observers.observers.get_mut(observer.0).ok_or(DomError::NotFound)
+    }
+
+    /*
+        A record goes to each observer that observes its target, or observes an
+        ancestor of its target with `subtree`, and whose options ask for that kind of
+        change. An observer that observes more than one of those nodes still gets the
+        record only once.
+     */
+    /// Give `record` to every observer interested in it.
+    pub(crate) fn queue_mutation_record(&mut self, record: MutationRecord) {
+        if self.observers.observers.is_empty() {
+            return;
+        }
+        let target = record.target;
+        let path: Vec<NodeId> = std::iter::once(target).chain(self.ancestors(target)).collect();
+
+        for observer in &mut self.observers.observers {
+            let mut interested = false;
+            let mut wants_old_value = false;
+            for (node, options) in &observer.targets {
+                if !path.contains(node) || (*node != target && !options.subtree) {
+                    continue;
+                }
+                let wanted = match record.kind {
+                    MutationKind::ChildList => options.child_list,
+                    MutationKind::Attributes => options.attributes && options.attribute_filter.as_ref().is_none_or(|filter| {
+                        record.attribute_name.as_ref().is_some_and(|name| filter.contains(name))
+                    }),
+                    MutationKind::CharacterData => options.character_data,
+                };
+                if !wanted {
+                    continue;
+                }
+                interested = true;
+                wants_old_value |= match record.kind {
+                    MutationKind::Attributes => options.attribute_old_value,
+                    MutationKind::CharacterData => options.character_data_old_value,
+                    MutationKind::ChildList => false,
+                };
+            }
+            if interested {
+                let mut record = record.clone();
+                if !wants_old_value {
+                    record.old_value = None;
+                }
+                observer.records.push(record);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::tests::list;
+    use crate::dom;
+
+    #[test]
+    fn records_child_list_changes() {
+        let (mut document, list, first, second) = list();
+        let observer = document.create_mutation_observer();
+        document.observe(observer, list, ObserverOptions { child_list: true, ..Default::default() }).unwrap();
+
+        document.remove_child(list, first).unwrap();
+        document.append_child(list, first).unwrap();
+        // Without `subtree`, changes to the items' own children aren't recorded.
+        document.set_text_content(second, "c");
+        assert_eq!(document.take_records(observer).unwrap(), [
+            MutationRecord::child_list(list, Vec::new(), vec![first], None, Some(second)),
+            MutationRecord::child_list(list, vec![first], Vec::new(), Some(second), None),
+        ]);
+        assert!(document.take_records(observer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn records_changes_in_the_subtree() {
+        let (mut document, list, first, _) = list();
+        let text = document.first_child(first).unwrap();
+        let observer = document.create_mutation_observer();
+        let options = ObserverOptions { subtree: true, character_data_old_value: true, ..Default::default() };
+        document.observe(observer, list, options.clone()).unwrap();
+        // Observing a node inside the subtree as well doesn't make records twice.
+        document.observe(observer, first, options).unwrap();
+
+        document.set_text_content(text, "z");
+        document.set_attribute(first, "class", "x");
+        assert_eq!(document.take_records(observer).unwrap(), [MutationRecord::character_data(text, "a".to_string())]);
+    }
+
+    #[test]
+    fn records_attributes_with_filter_and_old_values() {
+        let (mut document, _, first, _) = list();
+        let with_old = document.create_mutation_observer();
+        let without_old = document.create_mutation_observer();
+        document.observe(with_old, first, ObserverOptions {
+            attribute_old_value: true,
+            attribute_filter: Some(vec!["class".to_string()]),
+            ..Default::default()
+        }).unwrap();
+        document.observe(without_old, first, ObserverOptions { attributes: true, ..Default::default() }).unwrap();
+
+        document.set_attribute(first, "class", "x");
+        document.set_attribute(first, "class", "y");
+        document.set_attribute(first, "title", "t");
+        assert_eq!(document.take_records(with_old).unwrap(), [
+            MutationRecord::attributes(first, "class", None),
+            MutationRecord::attributes(first, "class", Some("x".to_string())),
+        ]);
+        assert_eq!(document.take_records(without_old).unwrap(), [
+            MutationRecord::attributes(first, "class", None),
+            MutationRecord::attributes(first, "class", None),
+            MutationRecord::attributes(first, "title", None),
+        ]);
+
+        document.disconnect(without_old).unwrap();
+        document.remove_attribute(first, "title");
+        assert!(document.take_records(without_old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_options_that_ask_for_nothing() {
+        let (mut document, list, _, _) = list();
+        let observer = document.create_mutation_observer();
+        assert_eq!(document.observe(observer, list, ObserverOptions { subtree: true, ..Default::default() }),
+                   Err(DomError::Type));
+        document.set_attribute(list, "class", "x");
+        assert!(document.take_records(observer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_observers_from_another_document() {
+        let (mut other, ..) = list();
+        other.create_mutation_observer();
+        let (mut document, list, _, _) = list();
+        let foreign = other.create_mutation_observer();
+        let options = ObserverOptions { child_list: true, ..Default::default() };
+        assert_eq!(document.observe(foreign, list, options), Err(DomError::NotFound));
+        assert_eq!(document.take_records(foreign), Err(DomError::NotFound));
+        assert_eq!(document.disconnect(foreign), Err(DomError::NotFound));
+    }
+
+    #[test]
+    fn records_text_content_replacing_children() {
+        let (mut document, list, first, _) = list();
+        let text = document.first_child(first).unwrap();
+        let observer = document.create_mutation_observer();
+        let options = ObserverOptions { child_list: true, character_data_old_value: true, subtree: true, ..Default::default() };
+        document.observe(observer, list, options).unwrap();
+
+        // The item's text node is reused, so only its text changes.
+        document.set_text_content(first, "z");
+        assert_eq!(document.take_records(observer).unwrap(), [MutationRecord::character_data(text, "a".to_string())]);
+
+        let added = document.create_node(dom::text("y".to_string()));
+        document.append_child(first, added).unwrap();
+        document.take_records(observer).unwrap();
+        document.set_text_content(first, "x");
+        assert_eq!(document.take_records(observer).unwrap(), [
+            MutationRecord::child_list(first, Vec::new(), vec![added], Some(text), None),
+            MutationRecord::character_data(text, "z".to_string()),
+        ]);
+        assert_eq!(document.text_content(first), "x");
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{css, layout};
 
-/**
+/*
  *  Painting 101
  *
  *  This code takes the tree of boxes from the layout module and turns them into an array
@@ -45,7 +45,7 @@ enum DisplayCommand {
 }
 
 
-/**
+/*
  *  To build the display list, we walk through the layout tree and generate a series of
  *  commands for each box. First we draw the box's background, then we draw its borders
  *  and content on top of the background.
@@ -69,7 +69,7 @@ fn render_layout_box(list: &mut DisplayList, layout_box: &layout::LayoutBox) {
 }
 
 
-/**
+/*
  *  By default, HTML elements are stacked in the order they appear: If two elements overlap,
  *  the later one is drawn on top of the earlier one. This is reflected in our display list,
  *  which will draw the elements in the same order they appear in the DOM tree. If this code
@@ -82,12 +82,9 @@ fn render_layout_box(list: &mut DisplayList, layout_box: &layout::LayoutBox) {
  */
 
 fn render_background(list: &mut DisplayList, layout_box: &layout::LayoutBox) {
-    get_color(layout_box, "background")
-        .map(
-            |color: css::Color| list.push(
-                DisplayCommand::SolidColor(color, layout_box.dimensions.border_box())
-            )
-        );
+    if let Some(color) = get_color(layout_box, "background") {
+        list.push(DisplayCommand::SolidColor(color, layout_box.dimensions.border_box()));
+    }
 }
 
 /// Return the specified color for CSS property `name`, or None if no color was specified.
@@ -155,10 +152,10 @@ fn render_borders(list: &mut DisplayList, layout_box: &layout::LayoutBox) {
  *  Now that we've built the display list, we need to turn it into pixels by executing
  *  each DisplayCommand. We'll store the pixels in a Canvas:
  */
-struct Canvas {
-    pixels: Vec<css::Color>,
-    width: usize,
-    height: usize,
+pub struct Canvas {
+    pub pixels: Vec<css::Color>,
+    pub width: usize,
+    pub height: usize,
 }
 
 impl Canvas {
@@ -185,14 +182,14 @@ impl Canvas {
     fn paint_item(&mut self, item: &DisplayCommand) {
         match item {
             &DisplayCommand::SolidColor(color, rect) => {
-                /// Clip the rectangle to the canvas boundaries.
+                // Clip the rectangle to the canvas boundaries.
                 let x0: usize = rect.x.clamp(0.0, self.width as f32) as usize;
                 let y0: usize = rect.y.clamp(0.0, self.height as f32) as usize;
                 let x1: usize = (rect.x + rect.width).clamp(0.0, self.width as f32) as usize;
                 let y1: usize = (rect.y + rect.height).clamp(0.0, self.height as f32) as usize;
 
-                for y in (y0..y1) {
-                    for x in (x0..x1) {
+                for y in y0..y1 {
+                    for x in x0..x1 {
                         // TODO: alpha compositing with existing pixel
                         self.pixels[x + y * self.width] = color;
                     }
@@ -207,7 +204,7 @@ impl Canvas {
  *  list and then rasterizes it to a canvas:
  */
 /// Paint a tree of LayoutBoxes to an array of pixels.
-fn paint(layout_root: &layout::LayoutBox, bounds: layout::Rect) -> Canvas {
+pub fn paint(layout_root: &layout::LayoutBox, bounds: layout::Rect) -> Canvas {
     let display_list = build_display_list(layout_root);
     let mut canvas = Canvas::new(bounds.width as usize, bounds.height as usize);
     for item in display_list {
@@ -0,0 +1,357 @@
+//! Turning a DOM tree back into HTML markup.
+//!
+//! Follows the HTML fragment serialization algorithm, so that parsing the output
+//! gives back the same tree.
+
+use crate::dom;
+use crate::html;
+
+
+/*
+    Text and attribute values are escaped so that they read back as the same
+    characters. Non-breaking spaces are escaped too, because they look just like
+    ordinary spaces in the output.
+
+    e.g.
+        Text("a < b & c")          => a &lt; b &amp; c
+        "title": "say \"hi\""      => title="say &quot;hi&quot;"
+ */
+
+/// Elements whose text is written out without escaping, because the parser reads it as raw text.
+const RAW_TEXT_ELEMENTS: &[&str] = &[
+    "iframe", "noembed", "noframes", "plaintext", "script", "style", "xmp",
+];
+
+/// Elements that ignore a newline right after their start tag,
+/// and whose text is kept exactly as it is when pretty-printing.
+const PREFORMATTED_ELEMENTS: &[&str] = &["listing", "pre", "textarea"];
+
+fn escape_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '\u{A0}' => result.push_str("&nbsp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn escape_attribute(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '\u{A0}' => result.push_str("&nbsp;"),
+            '"' => result.push_str("&quot;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+
+/*
+    Attributes are stored in a hash map, which has no order. They are written out
+    sorted by name, so that serializing the same tree always gives the same output.
+ */
+/// The start tag of an element, with its attributes.
+fn start_tag(element: &dom::Element) -> String {
+    let mut names: Vec<&String> = element.attributes.keys().collect();
+    names.sort();
+    let mut tag = format!("<{}", element.tag_name);
+    for name in names {
+        tag.push_str(&format!(" {}=\"{}\"", name, escape_attribute(&element.attributes[name])));
+    }
+    tag.push('>');
+    tag
+}
+
+/// The markup for a doctype, e.g. `<!DOCTYPE html>`.
+fn doctype(doctype: &dom::Doctype) -> String {
+    let mut markup = format!("<!DOCTYPE {}", doctype.name);
+    if !doctype.public_id.is_empty() {
+        markup.push_str(&format!(" PUBLIC \"{}\"", doctype.public_id));
+        if !doctype.system_id.is_empty() {
+            markup.push_str(&format!(" \"{}\"", doctype.system_id));
+        }
+    } else if !doctype.system_id.is_empty() {
+        markup.push_str(&format!(" SYSTEM \"{}\"", doctype.system_id));
+    }
+    markup.push('>');
+    markup
+}
+
+
+/// Write the markup for `node` to `out`. `parent` is its parent element, if any.
+fn write_node(node: &dom::Node, parent: Option<&dom::Element>, out: &mut String) {
+    match node.node_type {
+        dom::NodeType::Text(ref text) => {
+            if parent.is_some_and(is_raw_text_element) {
+                out.push_str(text);
+            } else {
+                out.push_str(&escape_text(text));
+            }
+        }
+        dom::NodeType::Comment(ref data) => {
+            out.push_str(&format!("<!--{}-->", data));
+        }
+        dom::NodeType::Doctype(ref data) => out.push_str(&doctype(data)),
+        dom::NodeType::Element(ref element) => {
+            out.push_str(&start_tag(element));
+            if is_void_element(element) {
+                // Void elements have no end tag, and can't have children.
+                return;
+            }
+            write_children(node, element, out);
+            out.push_str(&format!("</{}>", element.tag_name));
+        }
+    }
+}
+
+/// Write the markup for the children of `node`, which is `element`.
+fn write_children(node: &dom::Node, element: &dom::Element, out: &mut String) {
+    if is_preformatted_element(element) && starts_with_newline(node) {
+        // The parser drops a newline right after <pre>, so write an extra one to keep it.
+        out.push('\n');
+    }
+    for child in &node.children {
+        write_node(child, Some(element), out);
+    }
+}
+
+/// SVG and MathML have elements called "style" and "script" too, but only HTML ones hold raw text.
+fn is_raw_text_element(element: &dom::Element) -> bool {
+    element.namespace == dom::Namespace::Html && RAW_TEXT_ELEMENTS.contains(&element.tag_name.as_str())
+}
+
+fn is_preformatted_element(element: &dom::Element) -> bool {
+    element.namespace == dom::Namespace::Html && PREFORMATTED_ELEMENTS.contains(&element.tag_name.as_str())
+}
+
+fn is_void_element(element: &dom::Element) -> bool {
+    element.namespace == dom::Namespace::Html && html::is_void_element(&element.tag_name)
+}
+
+fn starts_with_newline(node: &dom::Node) -> bool {
+    match node.children.first() {
+        Some(dom::Node { node_type: dom::NodeType::Text(ref text), .. }) => text.starts_with('\n'),
+        _ => false,
+    }
+}
+
+
+/*
+    Pretty-printing puts each element on its own line, indented by two spaces per
+    level. Text is trimmed, and whitespace-only text is left out, so the output can
+    look different from the original when rendered. An element that contains only
+    text is kept on one line, and the contents of `<pre>`, `<textarea>` and raw text
+    elements are written exactly as they are.
+
+    e.g.
+        <ul><li>one</li><li>two</li></ul> =>
+            <ul>
+              <li>one</li>
+              <li>two</li>
+            </ul>
+ */
+fn write_pretty(node: &dom::Node, parent: Option<&dom::Element>, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    match node.node_type {
+        dom::NodeType::Text(ref text) => {
+            let text = text.trim();
+            if !text.is_empty() {
+                out.push_str(&format!("{}{}\n", indent, escape_text(text)));
+            }
+        }
+        dom::NodeType::Comment(_) | dom::NodeType::Doctype(_) => {
+            out.push_str(&indent);
+            write_node(node, parent, out);
+            out.push('\n');
+        }
+        dom::NodeType::Element(ref element) => {
+            out.push_str(&indent);
+            let tag_name = element.tag_name.as_str();
+            let only_text = node.children.iter().all(|child| matches!(child.node_type, dom::NodeType::Text(_)));
+            if is_void_element(element)
+                || is_raw_text_element(element)
+                || is_preformatted_element(element)
+            {
+                write_node(node, parent, out);
+            } else if only_text {
+                out.push_str(&start_tag(element));
+                for child in &node.children {
+                    if let dom::NodeType::Text(ref text) = child.node_type {
+                        out.push_str(&escape_text(text.trim()));
+                    }
+                }
+                out.push_str(&format!("</{}>", tag_name));
+            } else {
+                out.push_str(&start_tag(element));
+                out.push('\n');
+                for child in &node.children {
+                    write_pretty(child, Some(element), depth + 1, out);
+                }
+                out.push_str(&format!("{}</{}>", indent, tag_name));
+            }
+            out.push('\n');
+        }
+    }
+}
+
+
+impl dom::Node {
+    /// The markup for this node, including its own tags.
+    pub fn outer_html(&self) -> String {
+        let mut out = String::new();
+        write_node(self, None, &mut out);
+        out
+    }
+
+    /// The markup for this node's children.
+    pub fn inner_html(&self) -> String {
+        let mut out = String::new();
+        match self.node_type {
+            dom::NodeType::Element(ref element) => write_children(self, element, &mut out),
+            _ => {
+                for child in &self.children {
+                    write_node(child, None, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// The markup for this node, with one element per line and nested elements indented.
+    pub fn pretty_html(&self) -> String {
+        let mut out = String::new();
+        write_pretty(self, None, 0, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(source: &str) -> dom::Node {
+        html::parse(source.to_string())
+    }
+
+    /// Serialize `source`, parse the result again, and check that nothing changed.
+    fn assert_round_trip(source: &str) {
+        let first = parse(source).outer_html();
+        assert_eq!(parse(&first).outer_html(), first, "round trip of {:?}", source);
+    }
+
+    #[test]
+    fn escapes_text_and_attribute_values() {
+        let attributes = HashMap::from([
+            ("title".to_string(), "say \"hi\" & <bye>".to_string()),
+            ("alt".to_string(), "a\u{A0}b".to_string()),
+        ]);
+        let node = dom::element("p".to_string(), attributes, vec![dom::text("a < b & c > d\u{A0}e \"f\"".to_string())]);
+        assert_eq!(node.outer_html(),
+                   "<p alt=\"a&nbsp;b\" title=\"say &quot;hi&quot; &amp; <bye>\">a &lt; b &amp; c &gt; d&nbsp;e \"f\"</p>");
+    }
+
+    #[test]
+    fn writes_void_elements_without_end_tags() {
+        assert_eq!(parse("<p>a<br>b<img src=x.png><input/></p>").outer_html(), "<p>a<br>b<img src=\"x.png\"><input></p>");
+        // Children of a void element can only come from building the tree by hand, and they are lost.
+        let br = dom::element("br".to_string(), HashMap::new(), vec![dom::text("x".to_string())]);
+        assert_eq!(br.outer_html(), "<br>");
+    }
+
+    #[test]
+    fn writes_raw_text_without_escaping() {
+        assert_eq!(parse("<script>if (a < b && c) {}</script>").outer_html(), "<script>if (a < b && c) {}</script>");
+        assert_eq!(parse("<style>p > a::after { content: \"&\" }</style>").outer_html(),
+                   "<style>p > a::after { content: \"&\" }</style>");
+        assert_eq!(parse("<textarea>a < b</textarea>").outer_html(), "<textarea>a &lt; b</textarea>");
+    }
+
+    #[test]
+    fn keeps_a_leading_newline_in_textarea() {
+        let textarea = dom::element("textarea".to_string(), HashMap::new(), vec![dom::text("\nx".to_string())]);
+        assert_eq!(textarea.outer_html(), "<textarea>\n\nx</textarea>");
+        assert_eq!(parse(&textarea.outer_html()).outer_html(), textarea.outer_html());
+    }
+
+    #[test]
+    fn writes_doctypes_and_comments() {
+        let nodes = html::parse_top_level("<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\" \"http://www.w3.org/TR/html4/strict.dtd\"><!--a--><p>b</p>".to_string());
+        let serialized: Vec<String> = nodes.iter().map(dom::Node::outer_html).collect();
+        assert_eq!(serialized, [
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\" \"http://www.w3.org/TR/html4/strict.dtd\">",
+            "<!--a-->",
+            "<p>b</p>",
+        ]);
+        let doctype = dom::doctype("html".to_string(), String::new(), "about:legacy-compat".to_string());
+        assert_eq!(doctype.outer_html(), "<!DOCTYPE html SYSTEM \"about:legacy-compat\">");
+    }
+
+    #[test]
+    fn writes_inner_html() {
+        let root = parse("<div id=a><p>x</p>y<!--z--></div>");
+        assert_eq!(root.inner_html(), "<p>x</p>y<!--z-->");
+        assert_eq!(root.children[0].inner_html(), "x");
+        assert_eq!(root.children[1].inner_html(), "");
+    }
+
+    #[test]
+    fn pretty_prints_one_element_per_line() {
+        let root = parse("<div>\n  <ul><li>one</li><li>two &amp; three</li></ul>\n  <p>a<b>b</b></p><br><pre>x\n  y</pre></div>");
+        assert_eq!(root.pretty_html(), concat!(
+            "<div>\n",
+            "  <ul>\n",
+            "    <li>one</li>\n",
+            "    <li>two &amp; three</li>\n",
+            "  </ul>\n",
+            "  <p>\n",
+            "    a\n",
+            "    <b>b</b>\n",
+            "  </p>\n",
+            "  <br>\n",
+            "  <pre>x\n  y</pre>\n",
+            "</div>\n",
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_the_parser() {
+        assert_round_trip("<div class=\"a&amp;b\" id=x><p>1 &lt; 2 &amp;&amp; 3 &gt; 2</p></div>");
+        assert_round_trip("<ul><li>a<li>b &nbsp;c</ul>");
+        assert_round_trip("<p>a<br>b<img src=\"x\" alt>c</p>");
+        assert_round_trip("<div><script>if (a < b) { document.write(\"</p>\") }</script><style>a > b {}</style></div>");
+        assert_round_trip("<div><textarea>\n\nkeep</textarea><pre>\n\nkeep</pre><title>&lt;t&gt;</title></div>");
+        assert_round_trip("<p>a<!-- b -->c</p>");
+    }
+
+    #[test]
+    fn pretty_prints_foreign_elements_by_their_own_rules() {
+        // Only HTML's <pre> keeps its whitespace, and only HTML's <style> holds raw text.
+        let text = |data: &str| dom::text(data.to_string());
+        let svg = dom::element_ns(dom::Namespace::Svg, "svg".to_string(), HashMap::new(), vec![
+            dom::element_ns(dom::Namespace::Svg, "pre".to_string(), HashMap::new(), vec![
+                text("  a "),
+                dom::element_ns(dom::Namespace::Svg, "g".to_string(), HashMap::new(), vec![]),
+            ]),
+            dom::element_ns(dom::Namespace::Svg, "style".to_string(), HashMap::new(), vec![text("a > b")]),
+        ]);
+        assert_eq!(svg.pretty_html(), concat!(
+            "<svg>\n",
+            "  <pre>\n",
+            "    a\n",
+            "    <g></g>\n",
+            "  </pre>\n",
+            "  <style>a &gt; b</style>\n",
+            "</svg>\n",
+        ));
+        assert_eq!(parse("<pre>  a <b>b</b></pre>").pretty_html(), "<pre>  a <b>b</b></pre>\n");
+    }
+}
@@ -4,6 +4,8 @@
 
 use crate::css;
 use crate::dom;
+use crate::error::ParseError;
+use crate::loader::{self, ResourceLoader};
 use std::collections::HashMap;
 
 
@@ -72,7 +74,11 @@ impl<'a> StyledNode<'a> {
     }
 
     /// The value of the `display` property (defaults to inline).
+    /// Comments and doctypes are never displayed.
     pub fn display(&self) -> Display {
+        if let dom::NodeType::Comment(_) | dom::NodeType::Doctype(_) = self.node.node_type {
+            return Display::None;
+        }
         match self.value("display") {
             Some(css::Value::Keyword(s)) => match &*s {
                 "block" => Display::Block,
@@ -82,6 +88,109 @@ impl<'a> StyledNode<'a> {
             _ => Display::Inline,
         }
     }
+
+    /// The text of this node as it would be rendered, with a line break between blocks.
+    pub fn inner_text(&self) -> String {
+        if let Display::None = self.display() {
+            return self.node.text_content();
+        }
+        let mut items = Vec::new();
+        collect_rendered_text(self, &mut items);
+        join_rendered_text(&items)
+    }
+}
+
+
+/*
+    The inner text of a node follows how it is displayed, roughly like `innerText`
+    in a browser:
+        - Nodes with `display: none` are left out, along with everything inside them.
+        - Blocks start on a new line, and paragraphs are separated by a blank line.
+        - `<br>` starts a new line.
+        - Runs of whitespace collapse to one space, and whitespace at the start and
+          end of a line is dropped.
+    This engine has no default stylesheet, so `<head>`, `<script>` and `<style>` are
+    only left out if a stylesheet hides them.
+
+    e.g.
+        <div>Hello,   <b>world</b>!<p>Second</p><p style="display: none">Hidden</p></div>
+            => "Hello, world!\n\nSecond"
+ */
+enum TextItem<'a> {
+    Text(&'a str),
+    /// A line break that is always written.
+    LineBreak,
+    /// At least this many line breaks, unless at the start or end of the text.
+    RequiredLineBreaks(usize),
+}
+
+fn collect_rendered_text<'a>(node: &StyledNode<'a>, items: &mut Vec<TextItem<'a>>) {
+    let required_line_breaks = match (&node.node.node_type, node.display()) {
+        (_, Display::None) => return,
+        (dom::NodeType::Text(ref data), _) => {
+            items.push(TextItem::Text(data));
+            return;
+        }
+        (dom::NodeType::Element(ref element), _) if element.tag_name == "br" => {
+            items.push(TextItem::LineBreak);
+            return;
+        }
+        (dom::NodeType::Element(ref element), Display::Block) if element.tag_name == "p" => 2,
+        (_, Display::Block) => 1,
+        (_, Display::Inline) => 0,
+    };
+
+    if required_line_breaks > 0 {
+        items.push(TextItem::RequiredLineBreaks(required_line_breaks));
+    }
+    for child in &node.children {
+        collect_rendered_text(child, items);
+    }
+    if required_line_breaks > 0 {
+        items.push(TextItem::RequiredLineBreaks(required_line_breaks));
+    }
+}
+
+fn join_rendered_text(items: &[TextItem]) -> String {
+    let mut text = String::new();
+    let mut pending_space = false;
+    let mut pending_line_breaks = 0;
+    for item in items {
+        match *item {
+            TextItem::Text(data) => {
+                for c in data.chars() {
+                    if c.is_ascii_whitespace() {
+                        pending_space = !text.is_empty() && !text.ends_with('\n');
+                        continue;
+                    }
+                    if pending_line_breaks > 0 {
+                        // Line breaks replace any whitespace around them.
+                        if !text.is_empty() {
+                            text.push_str(&"\n".repeat(pending_line_breaks));
+                        }
+                        pending_line_breaks = 0;
+                    } else if pending_space {
+                        text.push(' ');
+                    }
+                    pending_space = false;
+                    text.push(c);
+                }
+            }
+            TextItem::LineBreak => {
+                if !text.is_empty() {
+                    text.push_str(&"\n".repeat(pending_line_breaks));
+                }
+                text.push('\n');
+                pending_line_breaks = 0;
+                pending_space = false;
+            }
+            TextItem::RequiredLineBreaks(count) => {
+                pending_line_breaks = pending_line_breaks.max(count);
+                pending_space = false;
+            }
+        }
+    }
+    text
 }
 
 
@@ -179,26 +288,129 @@ fn matching_rules<'a>(element: &dom::Element, stylesheet: &'a css::Stylesheet) -
     their values in the HashMap.
  */
 /// Apply styles to a single element, returning the specified values.
+/// Declarations in the element's `style` attribute win over every rule.
 fn specified_values(element: &dom::Element, stylesheet: &css::Stylesheet) -> PropertyMap {
     let mut values = HashMap::new();
     let mut rules = matching_rules(element, stylesheet);
 
     // Go through the rules from lowest to highest specificity.
-    rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+    // The sort is stable, so later rules still win over earlier ones with the same specificity.
+    rules.sort_by_key(|&(specificity, _)| specificity);
     for (_, rule) in rules {
         for declaration in &rule.declarations {
             values.insert(declaration.name.clone(), declaration.value.clone());
         }
     }
 
+    if let Some(style) = element.attributes.get("style") {
+        for declaration in css::parse_inline_declarations(style.clone()) {
+            values.insert(declaration.name, declaration.value);
+        }
+    }
+
     values
 }
 
 
+/*
+    Documents can carry their own styles in `<style>` elements. These are the author
+    stylesheets, and they are combined into one stylesheet in document order, so that
+    a rule in a later `<style>` wins over an equally specific rule in an earlier one.
+
+    e.g.
+        <style>p { color: #000000; }</style>
+        <style>p { color: #ff0000; }</style>    => p is red
+ */
+/// Collect the rules from every `<style>` element in the document into one stylesheet.
+/// Linked stylesheets and `@import`s are ignored, since there is nothing to load them with.
+pub fn author_stylesheet(root: &dom::Node) -> css::Stylesheet {
+    let mut stylesheet = css::Stylesheet { rules: Vec::new() };
+    collect_stylesheets(root, None, &mut stylesheet);
+    stylesheet
+}
+
+
+/*
+    With a resource loader, `<link rel="stylesheet">` elements are loaded too, in
+    their place in document order, along with every `@import`. Relative URLs are
+    resolved against the document's base URL: the `href` of its first `<base>`
+    element if it has one, or else the URL the document was loaded from.
+
+    e.g.
+        <base href="/site/">
+        <link rel="stylesheet" href="style.css">    => loads "file:///site/style.css"
+ */
+/// Collect the rules from every `<style>` element and linked stylesheet of the document at `url`.
+pub fn load_author_stylesheet(root: &dom::Node, url: &str, loader: &dyn ResourceLoader) -> css::Stylesheet {
+    let base_url = match find_base_href(root) {
+        Some(href) => loader::resolve_url(url, href),
+        None => url.to_string(),
+    };
+    let mut stylesheet = css::Stylesheet { rules: Vec::new() };
+    collect_stylesheets(root, Some((&base_url, loader)), &mut stylesheet);
+    stylesheet
+}
+
+/// Find the `href` of the first `<base>` element that has one.
+fn find_base_href(node: &dom::Node) -> Option<&str> {
+    if let dom::NodeType::Element(ref element) = node.node_type {
+        if element.tag_name == "base" {
+            if let Some(href) = element.attributes.get("href") {
+                return Some(href);
+            }
+        }
+    }
+    node.children.iter().find_map(find_base_href)
+}
+
+/// Add the rules of the stylesheets in `node` and its descendants to `stylesheet`.
+/// Without `base`, the base URL and loader, only `<style>` elements are used.
+fn collect_stylesheets(node: &dom::Node, base: Option<(&str, &dyn ResourceLoader)>, stylesheet: &mut css::Stylesheet) {
+    if let dom::NodeType::Element(ref element) = node.node_type {
+        match &*element.tag_name {
+            "style" => {
+                let source: String = node.children.iter().filter_map(|child: &dom::Node| match child.node_type {
+                    dom::NodeType::Text(ref text) => Some(text.as_str()),
+                    _ => None,
+                }).collect();
+                let rules = match base {
+                    Some((base_url, loader)) => css::parse_with_imports(source, base_url, loader).rules,
+                    None => css::parse(source).rules,
+                };
+                stylesheet.rules.extend(rules);
+                return;
+            }
+            "link" if is_stylesheet_link(element) => {
+                if let (Some((base_url, loader)), Some(href)) = (base, element.attributes.get("href")) {
+                    let url = loader::resolve_url(base_url, href);
+                    // Like a browser, carry on without a stylesheet that can't be loaded.
+                    if let Ok(source) = loader.load_text(&url) {
+                        stylesheet.rules.extend(css::parse_with_imports(source, &url, loader).rules);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for child in &node.children {
+        collect_stylesheets(child, base, stylesheet);
+    }
+}
+
+/// Is this a `<link>` whose `rel` attribute includes "stylesheet"?
+/// Alternate stylesheets are only used when the user picks them, so they don't count.
+fn is_stylesheet_link(element: &dom::Element) -> bool {
+    let has_keyword = |keyword: &str| element.attributes.get("rel").is_some_and(|rel| {
+        rel.split_ascii_whitespace().any(|word| word.eq_ignore_ascii_case(keyword))
+    });
+    has_keyword("stylesheet") && !has_keyword("alternate")
+}
+
+
 /*
     Now we have everything we need to walk through the DOM tree and build the style tree.
     Note that selector matching works only on elements, so the specified values for
-    a text node are just and empty map.
+    a text, comment or doctype node are just and empty map.
  */
 /// Apply a stylesheet to an entire DOM tree, returning a `StyledNode` tree.
 pub fn style_tree<'a>(root: &'a dom::Node, stylesheet: &'a css::Stylesheet) -> StyledNode<'a> {
@@ -206,8 +418,181 @@ pub fn style_tree<'a>(root: &'a dom::Node, stylesheet: &'a css::Stylesheet) -> S
         node: root,
         specified_values: match root.node_type {
             dom::NodeType::Element(ref element) => specified_values(element, stylesheet),
-            dom::NodeType::Text(_) => HashMap::new(),
+            dom::NodeType::Text(_) | dom::NodeType::Comment(_) | dom::NodeType::Doctype(_) => HashMap::new(),
         },
         children: root.children.iter().map(|child: &dom::Node| style_tree(child, stylesheet)).collect(),
     }
 }
+
+
+/*
+    Querying finds elements with the same selector matching that styling uses.
+    Like the DOM methods of the same name, only the descendants of a node are
+    searched, not the node itself, and the results are in document order.
+
+    e.g.
+        <ul><li class="a">1</li><li>2</li><li class="a">3</li></ul>
+        query_selector_all(".a") => [<li class="a">1</li>, <li class="a">3</li>]
+ */
+impl dom::Node {
+    /// Return the first descendant element that matches `selectors`.
+    pub fn query_selector(&self, selectors: &str) -> Result<Option<&dom::Node>, ParseError> {
+        let selectors = css::parse_selector_list(selectors)?;
+        let mut found = Vec::new();
+        collect_matches(self, &selectors, true, &mut found);
+        Ok(found.into_iter().next())
+    }
+
+    /// Return every descendant element that matches `selectors`.
+    pub fn query_selector_all(&self, selectors: &str) -> Result<Vec<&dom::Node>, ParseError> {
+        let selectors = css::parse_selector_list(selectors)?;
+        let mut found = Vec::new();
+        collect_matches(self, &selectors, false, &mut found);
+        Ok(found)
+    }
+}
+
+/// Add the descendants of `node` that match any of `selectors` to `found`.
+/// Returns true if it stopped after the first match because `first_only` is set.
+fn collect_matches<'a>(node: &'a dom::Node, selectors: &[css::Selector], first_only: bool, found: &mut Vec<&'a dom::Node>) -> bool {
+    for child in &node.children {
+        if let dom::NodeType::Element(ref element) = child.node_type {
+            if selectors.iter().any(|selector| matches(element, selector)) {
+                found.push(child);
+                if first_only {
+                    return true;
+                }
+            }
+        }
+        if collect_matches(child, selectors, first_only, found) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+    use crate::loader::MemoryLoader;
+
+    /// The color that the first `<p>` in `source` ends up with, styled by its own `<style>` elements.
+    fn paragraph_color(source: &str) -> Option<css::Color> {
+        let root = html::parse(source.to_string());
+        let stylesheet = author_stylesheet(&root);
+        let styled = style_tree(&root, &stylesheet);
+        let paragraph = find_element(&styled, "p")?;
+        match paragraph.value("color") {
+            Some(css::Value::ColorValue(color)) => Some(color),
+            _ => None,
+        }
+    }
+
+    fn find_element<'a>(node: &'a StyledNode<'a>, tag_name: &str) -> Option<&'a StyledNode<'a>> {
+        match node.node.node_type {
+            dom::NodeType::Element(ref element) if element.tag_name == tag_name => Some(node),
+            _ => node.children.iter().find_map(|child| find_element(child, tag_name)),
+        }
+    }
+
+    fn rgb(r: u8, g: u8, b: u8) -> Option<css::Color> {
+        Some(css::Color { r, g, b, a: 255 })
+    }
+
+    #[test]
+    fn style_attribute_beats_style_elements() {
+        let source = "<div><style>#x { color: #ff0000 }</style><p id=\"x\" style=\"color: #0000ff\">a</p></div>";
+        assert!(paragraph_color(source) == rgb(0, 0, 255));
+    }
+
+    #[test]
+    fn later_style_elements_win() {
+        let source = "<div><style>p { color: #ff0000 }</style><p>a</p><style>p { color: #00ff00 }</style></div>";
+        assert!(paragraph_color(source) == rgb(0, 255, 0));
+
+        // Unless an earlier rule is more specific.
+        let source = "<div><style>p.a { color: #ff0000 }</style><p class=\"a\">a</p><style>p { color: #00ff00 }</style></div>";
+        assert!(paragraph_color(source) == rgb(255, 0, 0));
+    }
+
+    #[test]
+    fn loads_linked_stylesheets_in_document_order() {
+        let mut loader = MemoryLoader::new();
+        loader.insert("file:///site/css/a.css", "@import '../b.css'; p { color: #00ff00 }");
+        loader.insert("file:///site/b.css", "p { color: #ff0000 }");
+        let root = html::parse("<div><base href=\"/site/css/\"><style>p { color: #0000ff }</style>\
+                                <link rel=\"stylesheet\" href=\"a.css\"><link rel=stylesheet href=missing.css><p>a</p></div>".to_string());
+        let stylesheet = load_author_stylesheet(&root, "file:///index.html", &loader);
+        let styled = style_tree(&root, &stylesheet);
+        assert_eq!(stylesheet.rules.len(), 3);
+        assert!(find_element(&styled, "p").unwrap().value("color") == Some(css::Value::ColorValue(css::Color { r: 0, g: 255, b: 0, a: 255 })));
+
+        // Without a loader, only the <style> element is used.
+        assert_eq!(author_stylesheet(&root).rules.len(), 1);
+    }
+
+    #[test]
+    fn finds_the_first_base_with_an_href() {
+        let root = html::parse("<div><base target=\"_top\"><base href=\"a/\"><base href=\"b/\"></div>".to_string());
+        assert_eq!(find_base_href(&root), Some("a/"));
+        assert_eq!(find_base_href(&html::parse("<p>a</p>".to_string())), None);
+    }
+
+    #[test]
+    fn recognizes_stylesheet_links() {
+        let is_stylesheet = |source: &str| match html::parse(source.to_string()).node_type {
+            dom::NodeType::Element(ref element) => is_stylesheet_link(element),
+            _ => false,
+        };
+        assert!(is_stylesheet("<link rel=stylesheet href=a.css>"));
+        assert!(is_stylesheet("<link rel=\"preload  StyleSheet\" href=a.css>"));
+        assert!(!is_stylesheet("<link rel=\"alternate stylesheet\" href=a.css>"));
+        assert!(!is_stylesheet("<link rel=icon href=a.css>"));
+        assert!(!is_stylesheet("<link href=a.css>"));
+    }
+
+    #[test]
+    fn queries_descendants_in_document_order() {
+        let root = html::parse("<ul class=\"a\"><li class=\"a\">1</li><li id=\"x\">2</li><li class=\"a\">3</li></ul>".to_string());
+        let found: Vec<String> = root.query_selector_all(".a").unwrap().iter().map(|node| node.outer_html()).collect();
+        assert_eq!(found, ["<li class=\"a\">1</li>", "<li class=\"a\">3</li>"]);
+        assert_eq!(root.query_selector("#x, .a").unwrap().unwrap().outer_html(), "<li class=\"a\">1</li>");
+        assert_eq!(root.query_selector("#x").unwrap().unwrap().outer_html(), "<li id=\"x\">2</li>");
+        assert!(root.query_selector("p").unwrap().is_none());
+        assert!(root.query_selector("li li").is_err());
+    }
+
+    /// The inner text of the root of `source`, styled by its own `<style>` elements,
+    /// which are hidden along with anything marked `hidden`.
+    fn inner_text(source: &str) -> String {
+        let source = format!("<div><style>style, .hidden {{ display: none; }} div, p {{ display: block; }}</style>{}</div>", source);
+        let root = html::parse(source);
+        let stylesheet = author_stylesheet(&root);
+        style_tree(&root, &stylesheet).inner_text()
+    }
+
+    #[test]
+    fn inner_text_breaks_lines_between_blocks() {
+        assert_eq!(inner_text("Hello,   <b>world</b>!<p>Second</p><div>Third</div>  <div> Fourth </div>"),
+                   "Hello, world!\n\nSecond\n\nThird\nFourth");
+        assert_eq!(inner_text("<div>a</div><div></div><div>b</div>"), "a\nb");
+    }
+
+    #[test]
+    fn inner_text_leaves_out_hidden_nodes() {
+        assert_eq!(inner_text("a<p class=\"hidden\">Hidden</p><span class=\"hidden\"> x</span> b"), "a b");
+
+        // A hidden node on its own still has its text content.
+        let root = html::parse("<p style=\"display: none\">a <b>b</b></p>".to_string());
+        let stylesheet = author_stylesheet(&root);
+        assert_eq!(style_tree(&root, &stylesheet).inner_text(), "a b");
+    }
+
+    #[test]
+    fn inner_text_starts_a_line_at_each_br() {
+        assert_eq!(inner_text("a<br>b"), "a\nb");
+        assert_eq!(inner_text("a <br> <br> b"), "a\n\nb");
+        assert_eq!(inner_text("<p>a</p><br>b"), "a\n\n\nb");
+    }
+}
@@ -0,0 +1,628 @@
+//! Walking through the nodes of a `Document` or a `dom::Node` tree.
+//!
+//! The iterators for a `Document` follow its parent and sibling links, so they don't
+//! need recursion or a stack, and can start from any node. A `dom::Node` has no links
+//! to its parent or siblings, so it only gets the iterators that go downwards.
+
+use crate::document::{Document, NodeId};
+use crate::dom;
+
+
+/*
+    There are two depth-first orders. Pre-order visits a node before its children,
+    which is the order the nodes appear in the source (document order). Post-order
+    visits a node after its children, which is useful when a node depends on what
+    is inside it.
+
+    e.g.
+        <ul><li>a</li><li>b</li></ul>
+        pre-order:  ul, li, "a", li, "b"
+        post-order: "a", li, "b", li, ul
+ */
+
+/// Iterates over the children of a node.
+pub struct Children<'a> {
+    document: &'a Document,
+    next: Option<NodeId>,
+}
+
+impl Iterator for Children<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let node = self.next?;
+        self.next = self.document.next_sibling(node);
+        Some(node)
+    }
+}
+
+/// Iterates over the ancestors of a node, starting with its parent.
+pub struct Ancestors<'a> {
+    document: &'a Document,
+    next: Option<NodeId>,
+}
+
+impl Iterator for Ancestors<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let node = self.next?;
+        self.next = self.document.parent(node);
+        Some(node)
+    }
+}
+
+/// Iterates over a node and its descendants, each node before its children.
+pub struct PreOrder<'a> {
+    document: &'a Document,
+    root: NodeId,
+    next: Option<NodeId>,
+}
+
+impl Iterator for PreOrder<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let node = self.next?;
+        self.next = match self.document.first_child(node) {
+            Some(child) => Some(child),
+            None => following(self.document, self.root, node),
+        };
+        Some(node)
+    }
+}
+
+/// Iterates over a node and its descendants, each node after its children.
+pub struct PostOrder<'a> {
+    document: &'a Document,
+    root: NodeId,
+    next: Option<NodeId>,
+}
+
+impl Iterator for PostOrder<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let node = self.next?;
+        self.next = if node == self.root {
+            None
+        } else {
+            match self.document.next_sibling(node) {
+                Some(sibling) => Some(first_leaf(self.document, sibling)),
+                None => self.document.parent(node),
+            }
+        };
+        Some(node)
+    }
+}
+
+/// The next node after `node` in pre-order that isn't one of its descendants,
+/// without leaving the subtree of `root`.
+fn following(document: &Document, root: NodeId, node: NodeId) -> Option<NodeId> {
+    let mut current = node;
+    loop {
+        if current == root {
+            return None;
+        }
+        if let Some(sibling) = document.next_sibling(current) {
+            return Some(sibling);
+        }
+        current = document.parent(current)?;
+    }
+}
+
+/// The first node under `node` in post-order: follow first children down as far as they go.
+fn first_leaf(document: &Document, node: NodeId) -> NodeId {
+    let mut current = node;
+    while let Some(child) = document.first_child(current) {
+        current = child;
+    }
+    current
+}
+
+
+impl Document {
+    /// The children of node `id`, in order.
+    pub fn children(&self, id: NodeId) -> Children<'_> {
+        Children { document: self, next: self.first_child(id) }
+    }
+
+    /// The ancestors of node `id`, from its parent up to the root.
+    pub fn ancestors(&self, id: NodeId) -> Ancestors<'_> {
+        Ancestors { document: self, next: self.parent(id) }
+    }
+
+    /// Node `id` and its descendants, in document order.
+    pub fn pre_order(&self, id: NodeId) -> PreOrder<'_> {
+        PreOrder { document: self, root: id, next: Some(id) }
+    }
+
+    /// Node `id` and its descendants, with every node after its children.
+    pub fn post_order(&self, id: NodeId) -> PostOrder<'_> {
+        PostOrder { document: self, root: id, next: Some(first_leaf(self, id)) }
+    }
+
+    /// The descendants of node `id`, in document order, without `id` itself.
+    pub fn descendants(&self, id: NodeId) -> PreOrder<'_> {
+        let mut iterator = self.pre_order(id);
+        iterator.next();
+        iterator
+    }
+
+    /// The element descendants of node `id` with tag name `tag_name`, or all of them for "*".
+    pub fn get_elements_by_tag_name(&self, id: NodeId, tag_name: &str) -> Vec<NodeId> {
+        self.descendants(id).filter(|&node| self.element(node).is_some_and(|element| has_tag_name(element, tag_name))).collect()
+    }
+
+    /// The element descendants of node `id` that have all the space-separated classes in `class_names`.
+    pub fn get_elements_by_class_name(&self, id: NodeId, class_names: &str) -> Vec<NodeId> {
+        let wanted: Vec<&str> = class_names.split_ascii_whitespace().collect();
+        self.descendants(id).filter(|&node| self.element(node).is_some_and(|element| has_classes(element, &wanted))).collect()
+    }
+
+    /// The first element in the document whose id is `element_id`.
+    pub fn get_element_by_id(&self, element_id: &str) -> Option<NodeId> {
+        self.pre_order(self.root()).find(|&node| self.element(node).is_some_and(|element| has_id(element, element_id)))
+    }
+
+    /// A tree walker over the subtree of `root` that only stops at nodes accepted by `filter`.
+    pub fn tree_walker<F>(&self, root: NodeId, filter: F) -> TreeWalker<'_, F>
+    where
+        F: Fn(&dom::NodeType) -> Filter,
+    {
+        TreeWalker { document: self, root, current: root, filter }
+    }
+}
+
+
+/// Does `element` have tag name `tag_name`, or is `tag_name` "*"? HTML tag names are
+/// always lower case, so for HTML elements `tag_name` is matched ignoring case.
+fn has_tag_name(element: &dom::Element, tag_name: &str) -> bool {
+    tag_name == "*" || match element.namespace {
+        dom::Namespace::Html => element.tag_name.eq_ignore_ascii_case(tag_name),
+        _ => element.tag_name == tag_name,
+    }
+}
+
+/// Does `element` have all of the classes in `wanted`, which mustn't be empty?
+fn has_classes(element: &dom::Element, wanted: &[&str]) -> bool {
+    let classes = element.classes();
+    !wanted.is_empty() && wanted.iter().all(|class| classes.contains(class))
+}
+
+fn has_id(element: &dom::Element, element_id: &str) -> bool {
+    element.id().is_some_and(|id| id == element_id)
+}
+
+
+/*
+    The same lookups work on a `dom::Node` tree, for code that doesn't need a whole
+    `Document`, like styling and layout tools. They return references into the tree.
+    Without parent links there is no `ancestors` and no tree walker, which needs to
+    move up as well as down.
+
+    e.g.
+        let root = html::parse("<ul><li class=a>1</li><li>2</li></ul>".to_string());
+        root.get_elements_by_class_name("a") => [<li class="a">1</li>]
+ */
+
+/// Iterates over a `dom::Node` and its descendants, each node before its children.
+pub struct NodePreOrder<'a> {
+    stack: Vec<&'a dom::Node>,
+}
+
+impl<'a> Iterator for NodePreOrder<'a> {
+    type Item = &'a dom::Node;
+
+    fn next(&mut self) -> Option<&'a dom::Node> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children.iter().rev());
+        Some(node)
+    }
+}
+
+/// Iterates over a `dom::Node` and its descendants, each node after its children.
+pub struct NodePostOrder<'a> {
+    // Each node on the way down, with the number of its children visited so far.
+    stack: Vec<(&'a dom::Node, usize)>,
+}
+
+impl<'a> Iterator for NodePostOrder<'a> {
+    type Item = &'a dom::Node;
+
+    fn next(&mut self) -> Option<&'a dom::Node> {
+        loop {
+            let (node, visited) = self.stack.last_mut()?;
+            let node: &'a dom::Node = node;
+            match node.children.get(*visited) {
+                Some(child) => {
+                    *visited += 1;
+                    self.stack.push((child, 0));
+                }
+                None => {
+                    self.stack.pop();
+                    return Some(node);
+                }
+            }
+        }
+    }
+}
+
+impl dom::Node {
+    /// This node and its descendants, in document order.
+    pub fn pre_order(&self) -> NodePreOrder<'_> {
+        NodePreOrder { stack: vec![self] }
+    }
+
+    /// This node and its descendants, with every node after its children.
+    pub fn post_order(&self) -> NodePostOrder<'_> {
+        NodePostOrder { stack: vec![(self, 0)] }
+    }
+
+    /// The descendants of this node, in document order, without the node itself.
+    pub fn descendants(&self) -> NodePreOrder<'_> {
+        NodePreOrder { stack: self.children.iter().rev().collect() }
+    }
+
+    /// The element descendants with tag name `tag_name`, or all of them for "*".
+    pub fn get_elements_by_tag_name(&self, tag_name: &str) -> Vec<&dom::Node> {
+        self.descendants().filter(|node| element_of(node).is_some_and(|element| has_tag_name(element, tag_name))).collect()
+    }
+
+    /// The element descendants that have all the space-separated classes in `class_names`.
+    pub fn get_elements_by_class_name(&self, class_names: &str) -> Vec<&dom::Node> {
+        let wanted: Vec<&str> = class_names.split_ascii_whitespace().collect();
+        self.descendants().filter(|node| element_of(node).is_some_and(|element| has_classes(element, &wanted))).collect()
+    }
+
+    /// The first element, this one or a descendant, whose id is `element_id`.
+    pub fn get_element_by_id(&self, element_id: &str) -> Option<&dom::Node> {
+        self.pre_order().find(|node| element_of(node).is_some_and(|element| has_id(element, element_id)))
+    }
+}
+
+fn element_of(node: &dom::Node) -> Option<&dom::Element> {
+    match node.node_type {
+        dom::NodeType::Element(ref element) => Some(element),
+        _ => None,
+    }
+}
+
+
+/*
+    A tree walker works like the DOM's `TreeWalker`. It has a current node, which
+    starts at the root and is moved by its methods. Only the nodes its filter accepts
+    are visited:
+        - Accept: visit the node.
+        - Skip: don't visit the node, but do visit its children.
+        - Reject: don't visit the node or anything inside it.
+    Methods that find nothing to move to return `None` and leave the current node
+    where it was.
+
+    Iterating over a tree walker calls `next_node` until it runs out. For a flat list
+    of matching nodes, where Skip and Reject mean the same thing, `pre_order` and
+    `filter` do the job too.
+
+    e.g.
+        let mut walker = document.tree_walker(document.root(), |node| match node {
+            dom::NodeType::Element(_) => Filter::Accept,
+            _ => Filter::Skip,
+        });
+        walker.first_child() => the first child element of the root
+ */
+/// What a tree walker's filter decides about a node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Accept,
+    Skip,
+    Reject,
+}
+
+/// Moves around a subtree, visiting only the nodes its filter accepts.
+pub struct TreeWalker<'a, F> {
+    document: &'a Document,
+    root: NodeId,
+    current: NodeId,
+    filter: F,
+}
+
+impl<F: Fn(&dom::NodeType) -> Filter> TreeWalker<'_, F> {
+    /// The node the walker was made for. It never moves outside of it.
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    /// The node the walker is at.
+    pub fn current_node(&self) -> NodeId {
+        self.current
+    }
+
+    /// Move to `id`, which should be inside the walker's root.
+    pub fn set_current_node(&mut self, id: NodeId) {
+        self.current = id;
+    }
+
+    fn check(&self, id: NodeId) -> Filter {
+        (self.filter)(self.document.node_type(id))
+    }
+
+    /// Move to the closest accepted ancestor of the current node.
+    pub fn parent_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        while node != self.root {
+            node = self.document.parent(node)?;
+            if self.check(node) == Filter::Accept {
+                self.current = node;
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Move to the first accepted child of the current node.
+    pub fn first_child(&mut self) -> Option<NodeId> {
+        self.traverse_children(true)
+    }
+
+    /// Move to the last accepted child of the current node.
+    pub fn last_child(&mut self) -> Option<NodeId> {
+        self.traverse_children(false)
+    }
+
+    /// Move to the next accepted sibling of the current node.
+    pub fn next_sibling(&mut self) -> Option<NodeId> {
+        self.traverse_siblings(true)
+    }
+
+    /// Move to the previous accepted sibling of the current node.
+    pub fn previous_sibling(&mut self) -> Option<NodeId> {
+        self.traverse_siblings(false)
+    }
+
+    /// The first or last child of `id`.
+    fn child(&self, id: NodeId, first: bool) -> Option<NodeId> {
+        if first { self.document.first_child(id) } else { self.document.last_child(id) }
+    }
+
+    /// The next or previous sibling of `id`.
+    fn sibling(&self, id: NodeId, next: bool) -> Option<NodeId> {
+        if next { self.document.next_sibling(id) } else { self.document.previous_sibling(id) }
+    }
+
+    /// Find the first (or last) accepted node among the children of the current node,
+    /// looking inside skipped children too.
+    fn traverse_children(&mut self, first: bool) -> Option<NodeId> {
+        let mut node = self.child(self.current, first)?;
+        loop {
+            match self.check(node) {
+                Filter::Accept => {
+                    self.current = node;
+                    return Some(node);
+                }
+                Filter::Skip => {
+                    if let Some(child) = self.child(node, first) {
+                        node = child;
+                        continue;
+                    }
+                }
+                Filter::Reject => {}
+            }
+            // Nothing here, so try the next sibling, climbing out of skipped nodes as needed.
+            loop {
+                if let Some(sibling) = self.sibling(node, first) {
+                    node = sibling;
+                    break;
+                }
+                let parent = self.document.parent(node)?;
+                if parent == self.root || parent == self.current {
+                    return None;
+                }
+                node = parent;
+            }
+        }
+    }
+
+    /// Find the next (or previous) accepted sibling of the current node, looking inside
+    /// skipped siblings and out of skipped parents too.
+    fn traverse_siblings(&mut self, next: bool) -> Option<NodeId> {
+        let mut node = self.current;
+        if node == self.root {
+            return None;
+        }
+        loop {
+            let mut sibling = self.sibling(node, next);
+            while let Some(candidate) = sibling {
+                node = candidate;
+                let result = self.check(node);
+                if result == Filter::Accept {
+                    self.current = node;
+                    return Some(node);
+                }
+                sibling = self.child(node, next);
+                if result == Filter::Reject || sibling.is_none() {
+                    sibling = self.sibling(node, next);
+                }
+            }
+            node = self.document.parent(node)?;
+            if node == self.root || self.check(node) == Filter::Accept {
+                return None;
+            }
+        }
+    }
+
+    /// Move to the previous accepted node in document order.
+    pub fn previous_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        while node != self.root {
+            let mut sibling = self.document.previous_sibling(node);
+            while let Some(candidate) = sibling {
+                node = candidate;
+                let mut result = self.check(node);
+                // The node just before a sibling is the last node inside it.
+                while result != Filter::Reject {
+                    match self.document.last_child(node) {
+                        Some(child) => {
+                            node = child;
+                            result = self.check(node);
+                        }
+                        None => break,
+                    }
+                }
+                if result == Filter::Accept {
+                    self.current = node;
+                    return Some(node);
+                }
+                sibling = self.document.previous_sibling(node);
+            }
+            node = self.document.parent(node)?;
+            if self.check(node) == Filter::Accept {
+                self.current = node;
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Move to the next accepted node in document order.
+    pub fn next_node(&mut self) -> Option<NodeId> {
+        let mut node = self.current;
+        let mut result = Filter::Accept;
+        loop {
+            while result != Filter::Reject {
+                match self.document.first_child(node) {
+                    Some(child) => {
+                        node = child;
+                        result = self.check(node);
+                        if result == Filter::Accept {
+                            self.current = node;
+                            return Some(node);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            node = following(self.document, self.root, node)?;
+            result = self.check(node);
+            if result == Filter::Accept {
+                self.current = node;
+                return Some(node);
+            }
+        }
+    }
+}
+
+impl<F: Fn(&dom::NodeType) -> Filter> Iterator for TreeWalker<'_, F> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        self.next_node()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    const SOURCE: &str = "<div id=top><p class='a b'><b>x</b></p><ul><li class=a>1</li><LI>2</LI></ul></div>";
+
+    /// The tag names of `nodes`, with "#text" for text nodes.
+    fn names<'a>(nodes: impl Iterator<Item = &'a dom::NodeType>) -> Vec<String> {
+        nodes.map(|node| match node {
+            dom::NodeType::Element(element) => element.tag_name.clone(),
+            dom::NodeType::Text(text) => format!("#{}", text),
+            _ => "#other".to_string(),
+        }).collect()
+    }
+
+    /// The tag names of the nodes with ids `ids` in `document`.
+    fn names_of(document: &Document, ids: impl IntoIterator<Item = NodeId>) -> Vec<String> {
+        names(ids.into_iter().map(|id| document.node_type(id)))
+    }
+
+    #[test]
+    fn walks_a_document_in_order() {
+        let document = Document::new(html::parse(SOURCE.to_string()));
+        let root = document.root();
+        assert_eq!(names_of(&document, document.pre_order(root)),
+                   ["div", "p", "b", "#x", "ul", "li", "#1", "li", "#2"]);
+        assert_eq!(names_of(&document, document.post_order(root)),
+                   ["#x", "b", "p", "#1", "li", "#2", "li", "ul", "div"]);
+        assert_eq!(names_of(&document, document.descendants(root)).len(), 8);
+        assert_eq!(names_of(&document, document.children(root)), ["p", "ul"]);
+
+        let text = document.pre_order(root).last().unwrap();
+        assert_eq!(names_of(&document, document.ancestors(text)), ["li", "ul", "div"]);
+    }
+
+    #[test]
+    fn finds_elements_in_a_document() {
+        let document = Document::new(html::parse(SOURCE.to_string()));
+        let root = document.root();
+        assert_eq!(names_of(&document, document.get_elements_by_tag_name(root, "LI")), ["li", "li"]);
+        assert_eq!(document.get_elements_by_tag_name(root, "*").len(), 5);
+        assert_eq!(names_of(&document, document.get_elements_by_class_name(root, "a")), ["p", "li"]);
+        assert_eq!(names_of(&document, document.get_elements_by_class_name(root, " b  a ")), ["p"]);
+        assert!(document.get_elements_by_class_name(root, " ").is_empty());
+        assert_eq!(document.get_element_by_id("top"), Some(root));
+        assert_eq!(document.get_element_by_id("missing"), None);
+    }
+
+    #[test]
+    fn matches_foreign_tag_names_exactly() {
+        let node = html::parse("<div><span></span><svg><foreignObject/></svg></div>".to_string());
+        assert_eq!(node.get_elements_by_tag_name("foreignObject").len(), 1);
+        assert!(node.get_elements_by_tag_name("foreignobject").is_empty());
+        assert!(node.get_elements_by_tag_name("SVG").is_empty());
+        assert_eq!(node.get_elements_by_tag_name("SPAN").len(), 1);
+    }
+
+    #[test]
+    fn walks_and_searches_a_node_tree() {
+        let node = html::parse(SOURCE.to_string());
+        assert_eq!(names(node.pre_order().map(|node| &node.node_type)),
+                   ["div", "p", "b", "#x", "ul", "li", "#1", "li", "#2"]);
+        assert_eq!(names(node.post_order().map(|node| &node.node_type)),
+                   ["#x", "b", "p", "#1", "li", "#2", "li", "ul", "div"]);
+        assert_eq!(node.descendants().count(), 8);
+        assert_eq!(node.get_elements_by_tag_name("Li").len(), 2);
+        assert_eq!(names(node.get_elements_by_class_name("a").into_iter().map(|node| &node.node_type)), ["p", "li"]);
+        assert!(node.get_element_by_id("top").is_some_and(|found| std::ptr::eq(found, &node)));
+        assert!(node.children[0].get_element_by_id("top").is_none());
+    }
+
+    /// A filter that skips text, and does `for_p` with <p> elements.
+    fn elements_but_p(for_p: Filter) -> impl Fn(&dom::NodeType) -> Filter {
+        move |node| match node {
+            dom::NodeType::Element(element) if element.tag_name == "p" => for_p,
+            dom::NodeType::Element(_) => Filter::Accept,
+            _ => Filter::Skip,
+        }
+    }
+
+    #[test]
+    fn tree_walker_skips_nodes_or_whole_subtrees() {
+        let document = Document::new(html::parse(SOURCE.to_string()));
+        let root = document.root();
+
+        let walker = document.tree_walker(root, elements_but_p(Filter::Skip));
+        assert_eq!(names_of(&document, walker), ["b", "ul", "li", "li"]);
+        let walker = document.tree_walker(root, elements_but_p(Filter::Reject));
+        assert_eq!(names_of(&document, walker), ["ul", "li", "li"]);
+
+        // Moving around, a skipped <p> is looked into, but never stopped at.
+        let mut walker = document.tree_walker(root, elements_but_p(Filter::Skip));
+        assert_eq!(walker.root(), root);
+        let bold = walker.first_child().unwrap();
+        assert_eq!(names_of(&document, [bold]), ["b"]);
+        assert_eq!(names_of(&document, walker.next_sibling()), ["ul"]);
+        assert_eq!(names_of(&document, walker.last_child()), ["li"]);
+        assert_eq!(walker.next_sibling(), None);
+        assert_eq!(names_of(&document, walker.previous_node()), ["li"]);
+        assert_eq!(names_of(&document, walker.previous_node()), ["ul"]);
+        assert_eq!(walker.previous_node(), Some(bold));
+        assert_eq!(walker.parent_node(), Some(root));
+        assert_eq!(walker.parent_node(), None);
+        assert_eq!(walker.current_node(), root);
+    }
+}